@@ -13,7 +13,7 @@ async fn main() -> eyre::Result<()> {
         .listener(listener)
         .build()?;
 
-    let _ = client.start().await;
+    client.start().await?;
 
     Ok(())
 }
@@ -25,7 +25,7 @@ async fn main() -> eyre::Result<()> {
     }))
     .build()?;
 
-    let _ = client.start().await;
+    client.start().await?;
 
     Ok(())
 }
@@ -0,0 +1,222 @@
+// Acknowledges messages with `DeleteMessageBatch`, up to 10 messages per request instead of one
+// `DeleteMessage` call per message
+use rusoto_core::RusotoError;
+use rusoto_sqs::{
+    DeleteMessageBatchRequest, DeleteMessageBatchRequestEntry, DeleteMessageError, Message,
+};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::extended::S3Pointer;
+use super::producer::is_retryable;
+use super::telemetry::warn;
+use super::transport::Transport;
+use super::Error;
+
+/// Most messages SQS accepts in one `DeleteMessageBatch` call
+pub(crate) const MAX_BATCH_SIZE: usize = 10;
+
+/// How often a throttled or failed ack is retried before it is reported as failed
+const MAX_RETRIES: u32 = 3;
+
+/// How long to wait before the first retry, doubled for every further retry
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Acks waiting to be sent, with the pointer to the extended payload of each message
+#[derive(Default)]
+pub(crate) struct AckBuffer {
+    pending: Mutex<Vec<(Message, Option<S3Pointer>)>>,
+}
+
+impl AckBuffer {
+    // returns true once a full batch is buffered
+    pub(crate) fn push(&self, message: Message, pointer: Option<S3Pointer>) -> bool {
+        let mut pending = self.pending.lock().expect("ack buffer poisoned");
+        pending.push((message, pointer));
+
+        pending.len() >= MAX_BATCH_SIZE
+    }
+
+    pub(crate) fn take(&self) -> Vec<(Message, Option<S3Pointer>)> {
+        mem::take(&mut *self.pending.lock().expect("ack buffer poisoned"))
+    }
+}
+
+// deletes the messages of the receipt handles, in batches of 10. Returns the result of every
+// receipt handle, in order
+pub(crate) async fn delete_all(
+    client: &dyn Transport,
+    queue_url: &str,
+    receipt_handles: Vec<String>,
+) -> Vec<Result<(), Arc<Error>>> {
+    let mut results = Vec::with_capacity(receipt_handles.len());
+
+    for batch in receipt_handles.chunks(MAX_BATCH_SIZE) {
+        results.extend(delete_batch(client, queue_url, batch).await);
+    }
+
+    results
+}
+
+// the entry ids are the positions in the batch, to match results to receipt handles. Throttled
+// calls and entries that failed on the AWS side are retried, invalid receipt handles are not
+async fn delete_batch(
+    client: &dyn Transport,
+    queue_url: &str,
+    receipt_handles: &[String],
+) -> Vec<Result<(), Arc<Error>>> {
+    let mut results: Vec<Option<Result<(), Arc<Error>>>> = vec![None; receipt_handles.len()];
+
+    let mut entries: Vec<DeleteMessageBatchRequestEntry> = receipt_handles
+        .iter()
+        .enumerate()
+        .map(|(index, receipt_handle)| DeleteMessageBatchRequestEntry {
+            id: index.to_string(),
+            receipt_handle: receipt_handle.clone(),
+        })
+        .collect();
+
+    let mut retry_delay = RETRY_DELAY;
+
+    for attempt in 0..=MAX_RETRIES {
+        let last_attempt = attempt == MAX_RETRIES;
+
+        if attempt > 0 {
+            tokio::time::sleep(retry_delay).await;
+            retry_delay *= 2;
+        }
+
+        let result = client
+            .delete_message_batch(DeleteMessageBatchRequest {
+                entries: entries.clone(),
+                queue_url: queue_url.to_string(),
+            })
+            .await;
+
+        let result = match result {
+            Ok(result) => result,
+            Err(error) if is_retryable(&error) && !last_attempt => {
+                warn!("Error when acknowledging messages, retrying: {}", error);
+                continue;
+            }
+            Err(error) => {
+                let error = Arc::new(Error::from(error));
+
+                for entry in &entries {
+                    set_result(&mut results, &entry.id, Err(error.clone()));
+                }
+
+                break;
+            }
+        };
+
+        for deleted in result.successful {
+            set_result(&mut results, &deleted.id, Ok(()));
+        }
+
+        let mut retry_ids = Vec::new();
+
+        for failed in result.failed {
+            if !failed.sender_fault && !last_attempt {
+                retry_ids.push(failed.id);
+                continue;
+            }
+
+            let message = failed.message.unwrap_or_default();
+
+            // an invalid receipt handle is almost always an expired one, as for single acks
+            let error = if failed.code == "ReceiptHandleIsInvalid" {
+                Error::ReceiptHandleExpired(RusotoError::Service(
+                    DeleteMessageError::ReceiptHandleIsInvalid(message),
+                ))
+            } else {
+                Error::AckFailed {
+                    code: failed.code,
+                    message,
+                }
+            };
+
+            set_result(&mut results, &failed.id, Err(Arc::new(error)));
+        }
+
+        if retry_ids.is_empty() {
+            break;
+        }
+
+        warn!(
+            "{} messages were not acknowledged, retrying",
+            retry_ids.len()
+        );
+        entries.retain(|entry| retry_ids.contains(&entry.id));
+    }
+
+    results
+        .into_iter()
+        .map(|result| result.unwrap_or_else(|| Err(Arc::new(Error::UnknownAckResult))))
+        .collect()
+}
+
+fn set_result<T>(results: &mut [Option<T>], id: &str, result: T) {
+    if let Some(slot) = id
+        .parse::<usize>()
+        .ok()
+        .and_then(|index| results.get_mut(index))
+    {
+        *slot = Some(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{QueueOptions, SqsEmulator};
+    use rusoto_sqs::ReceiveMessageRequest;
+
+    const QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+
+    #[tokio::test]
+    async fn deletes_in_batches_and_reports_invalid_receipt_handles() {
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(QUEUE_URL, QueueOptions::default());
+
+        for _ in 0..12 {
+            emulator.push_message(QUEUE_URL, Message::default());
+        }
+
+        let mut receipt_handles = Vec::new();
+
+        for _ in 0..2 {
+            let messages = Transport::receive_message(
+                &emulator,
+                ReceiveMessageRequest {
+                    queue_url: QUEUE_URL.to_string(),
+                    max_number_of_messages: Some(10),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap()
+            .messages
+            .unwrap();
+
+            receipt_handles.extend(
+                messages
+                    .into_iter()
+                    .filter_map(|message| message.receipt_handle),
+            );
+        }
+
+        receipt_handles.push("expired".to_string());
+
+        let results = delete_all(&emulator, QUEUE_URL, receipt_handles).await;
+
+        assert_eq!(results.len(), 13);
+        assert!(results[..12].iter().all(Result::is_ok));
+        assert!(matches!(
+            results[12].as_ref().map_err(|error| &**error),
+            Err(Error::ReceiptHandleExpired(_))
+        ));
+        assert_eq!(emulator.deleted_messages(QUEUE_URL).len(), 12);
+    }
+}
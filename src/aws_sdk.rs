@@ -0,0 +1,379 @@
+//! Runs the listener and the [BufferedSender](crate::producer::BufferedSender) on the official
+//! [aws-sdk-sqs](https://docs.rs/aws-sdk-sqs) client instead of rusoto, enable with the `aws-sdk`
+//! feature
+//!
+//! Credentials, regions and endpoints are configured on the SDK client, so SSO, IMDSv2 and every
+//! other provider of `aws-config` works
+//!
+//! ```rust,ignore
+//! let config = aws_config::load_from_env().await;
+//!
+//! let client = SQSListenerClientBuilder::new_with_aws_sdk(aws_sdk_sqs::Client::new(&config))
+//!     .listener(listener)
+//!     .build()?;
+//! ```
+use async_trait::async_trait;
+use aws_sdk_sqs::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_sqs::primitives::Blob;
+use aws_sdk_sqs::types::{self as sdk, MessageSystemAttributeName, QueueAttributeName};
+use aws_sdk_sqs::Client;
+use bytes::Bytes;
+use http::{HeaderMap, StatusCode};
+use rusoto_core::request::{BufferedHttpResponse, HttpDispatchError};
+use rusoto_core::RusotoError;
+use rusoto_sqs::{
+    BatchResultErrorEntry, ChangeMessageVisibilityError, ChangeMessageVisibilityRequest,
+    DeleteMessageBatchError, DeleteMessageBatchRequest, DeleteMessageBatchResult,
+    DeleteMessageBatchResultEntry, DeleteMessageError, DeleteMessageRequest,
+    GetQueueAttributesError, GetQueueAttributesRequest, GetQueueAttributesResult, Message,
+    MessageAttributeValue, ReceiveMessageError, ReceiveMessageRequest, ReceiveMessageResult,
+    SendMessageBatchError, SendMessageBatchRequest, SendMessageBatchResult,
+    SendMessageBatchResultEntry, SendMessageError, SendMessageRequest, SendMessageResult,
+};
+use std::collections::HashMap;
+
+use super::transport::Transport;
+
+/// Adapts an [aws-sdk-sqs](https://docs.rs/aws-sdk-sqs) [Client] to the operations the listener
+/// uses, converting requests and responses to and from the rusoto types
+pub(crate) struct AwsSdkTransport {
+    client: Client,
+}
+
+impl AwsSdkTransport {
+    pub(crate) fn new(client: Client) -> Self {
+        Self { client }
+    }
+}
+
+// service errors become unknown responses with the error code in the body, like the ones rusoto
+// returns for errors it doesn't know, so checks for codes such as `ExpiredToken` keep working
+fn convert_error<E, R, T>(error: SdkError<E, R>) -> RusotoError<T>
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+    R: std::fmt::Debug,
+{
+    match &error {
+        SdkError::DispatchFailure(_) | SdkError::TimeoutError(_) => RusotoError::HttpDispatch(
+            HttpDispatchError::new(DisplayErrorContext(&error).to_string()),
+        ),
+        SdkError::ServiceError(_) => {
+            let body = format!(
+                "<ErrorResponse><Error><Code>{}</Code><Message>{}</Message></Error></ErrorResponse>",
+                error.code().unwrap_or_default(),
+                error.message().unwrap_or_default()
+            );
+
+            RusotoError::Unknown(BufferedHttpResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: Bytes::from(body),
+                headers: HeaderMap::new(),
+            })
+        }
+        _ => RusotoError::Validation(DisplayErrorContext(&error).to_string()),
+    }
+}
+
+fn to_sdk_attributes<T>(
+    attributes: Option<HashMap<String, MessageAttributeValue>>,
+) -> Result<Option<HashMap<String, sdk::MessageAttributeValue>>, RusotoError<T>> {
+    attributes
+        .map(|attributes| {
+            attributes
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = sdk::MessageAttributeValue::builder()
+                        .data_type(value.data_type)
+                        .set_string_value(value.string_value)
+                        .set_binary_value(value.binary_value.map(|bytes| Blob::new(bytes.to_vec())))
+                        .build()
+                        .map_err(|error| RusotoError::Validation(error.to_string()))?;
+
+                    Ok((name, value))
+                })
+                .collect()
+        })
+        .transpose()
+}
+
+fn from_sdk_message(message: sdk::Message) -> Message {
+    let attributes = message.attributes.map(|attributes| {
+        attributes
+            .into_iter()
+            .map(|(name, value)| (name.as_str().to_string(), value))
+            .collect()
+    });
+
+    let message_attributes = message.message_attributes.map(|attributes| {
+        attributes
+            .into_iter()
+            .map(|(name, value)| {
+                let value = MessageAttributeValue {
+                    data_type: value.data_type,
+                    string_value: value.string_value,
+                    binary_value: value
+                        .binary_value
+                        .map(|blob| Bytes::from(blob.into_inner())),
+                    ..Default::default()
+                };
+
+                (name, value)
+            })
+            .collect()
+    });
+
+    Message {
+        attributes,
+        body: message.body,
+        md5_of_body: message.md5_of_body,
+        md5_of_message_attributes: message.md5_of_message_attributes,
+        message_attributes,
+        message_id: message.message_id,
+        receipt_handle: message.receipt_handle,
+    }
+}
+
+#[async_trait]
+impl Transport for AwsSdkTransport {
+    async fn receive_message(
+        &self,
+        input: ReceiveMessageRequest,
+    ) -> Result<ReceiveMessageResult, RusotoError<ReceiveMessageError>> {
+        let attribute_names = input.attribute_names.map(|names| {
+            names
+                .iter()
+                .map(|name| MessageSystemAttributeName::from(name.as_str()))
+                .collect()
+        });
+
+        let output = self
+            .client
+            .receive_message()
+            .queue_url(input.queue_url)
+            .set_message_system_attribute_names(attribute_names)
+            .set_message_attribute_names(input.message_attribute_names)
+            .set_max_number_of_messages(input.max_number_of_messages.map(|max| max as i32))
+            .set_visibility_timeout(input.visibility_timeout.map(|seconds| seconds as i32))
+            .set_wait_time_seconds(input.wait_time_seconds.map(|seconds| seconds as i32))
+            .set_receive_request_attempt_id(input.receive_request_attempt_id)
+            .send()
+            .await
+            .map_err(convert_error)?;
+
+        Ok(ReceiveMessageResult {
+            // the listener treats a missing list as a failed receive
+            messages: Some(
+                output
+                    .messages
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(from_sdk_message)
+                    .collect(),
+            ),
+        })
+    }
+
+    async fn delete_message(
+        &self,
+        input: DeleteMessageRequest,
+    ) -> Result<(), RusotoError<DeleteMessageError>> {
+        let result = self
+            .client
+            .delete_message()
+            .queue_url(input.queue_url)
+            .receipt_handle(input.receipt_handle.clone())
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(error) if error.code() == Some("ReceiptHandleIsInvalid") => {
+                Err(RusotoError::Service(
+                    DeleteMessageError::ReceiptHandleIsInvalid(input.receipt_handle),
+                ))
+            }
+            Err(error) => Err(convert_error(error)),
+        }
+    }
+
+    async fn delete_message_batch(
+        &self,
+        input: DeleteMessageBatchRequest,
+    ) -> Result<DeleteMessageBatchResult, RusotoError<DeleteMessageBatchError>> {
+        let mut entries = Vec::with_capacity(input.entries.len());
+
+        for entry in input.entries {
+            let entry = sdk::DeleteMessageBatchRequestEntry::builder()
+                .id(entry.id)
+                .receipt_handle(entry.receipt_handle)
+                .build()
+                .map_err(|error| RusotoError::Validation(error.to_string()))?;
+
+            entries.push(entry);
+        }
+
+        let output = self
+            .client
+            .delete_message_batch()
+            .queue_url(input.queue_url)
+            .set_entries(Some(entries))
+            .send()
+            .await
+            .map_err(convert_error)?;
+
+        let successful = output
+            .successful
+            .into_iter()
+            .map(|entry| DeleteMessageBatchResultEntry { id: entry.id })
+            .collect();
+
+        let failed = output
+            .failed
+            .into_iter()
+            .map(|entry| BatchResultErrorEntry {
+                code: entry.code,
+                id: entry.id,
+                message: entry.message,
+                sender_fault: entry.sender_fault,
+            })
+            .collect();
+
+        Ok(DeleteMessageBatchResult { successful, failed })
+    }
+
+    async fn change_message_visibility(
+        &self,
+        input: ChangeMessageVisibilityRequest,
+    ) -> Result<(), RusotoError<ChangeMessageVisibilityError>> {
+        let result = self
+            .client
+            .change_message_visibility()
+            .queue_url(input.queue_url)
+            .receipt_handle(input.receipt_handle.clone())
+            .visibility_timeout(input.visibility_timeout as i32)
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(error) if error.code() == Some("ReceiptHandleIsInvalid") => {
+                Err(RusotoError::Service(
+                    ChangeMessageVisibilityError::ReceiptHandleIsInvalid(input.receipt_handle),
+                ))
+            }
+            Err(error) => Err(convert_error(error)),
+        }
+    }
+
+    async fn send_message(
+        &self,
+        input: SendMessageRequest,
+    ) -> Result<SendMessageResult, RusotoError<SendMessageError>> {
+        let output = self
+            .client
+            .send_message()
+            .queue_url(input.queue_url)
+            .message_body(input.message_body)
+            .set_delay_seconds(input.delay_seconds.map(|seconds| seconds as i32))
+            .set_message_attributes(to_sdk_attributes(input.message_attributes)?)
+            .set_message_deduplication_id(input.message_deduplication_id)
+            .set_message_group_id(input.message_group_id)
+            .send()
+            .await
+            .map_err(convert_error)?;
+
+        Ok(SendMessageResult {
+            md5_of_message_attributes: output.md5_of_message_attributes,
+            md5_of_message_body: output.md5_of_message_body,
+            message_id: output.message_id,
+            sequence_number: output.sequence_number,
+            ..Default::default()
+        })
+    }
+
+    async fn send_message_batch(
+        &self,
+        input: SendMessageBatchRequest,
+    ) -> Result<SendMessageBatchResult, RusotoError<SendMessageBatchError>> {
+        let mut entries = Vec::with_capacity(input.entries.len());
+
+        for entry in input.entries {
+            let entry = sdk::SendMessageBatchRequestEntry::builder()
+                .id(entry.id)
+                .message_body(entry.message_body)
+                .set_delay_seconds(entry.delay_seconds.map(|seconds| seconds as i32))
+                .set_message_attributes(to_sdk_attributes(entry.message_attributes)?)
+                .set_message_deduplication_id(entry.message_deduplication_id)
+                .set_message_group_id(entry.message_group_id)
+                .build()
+                .map_err(|error| RusotoError::Validation(error.to_string()))?;
+
+            entries.push(entry);
+        }
+
+        let output = self
+            .client
+            .send_message_batch()
+            .queue_url(input.queue_url)
+            .set_entries(Some(entries))
+            .send()
+            .await
+            .map_err(convert_error)?;
+
+        let successful = output
+            .successful
+            .into_iter()
+            .map(|entry| SendMessageBatchResultEntry {
+                id: entry.id,
+                md5_of_message_attributes: entry.md5_of_message_attributes,
+                md5_of_message_body: entry.md5_of_message_body,
+                message_id: entry.message_id,
+                sequence_number: entry.sequence_number,
+                ..Default::default()
+            })
+            .collect();
+
+        let failed = output
+            .failed
+            .into_iter()
+            .map(|entry| BatchResultErrorEntry {
+                code: entry.code,
+                id: entry.id,
+                message: entry.message,
+                sender_fault: entry.sender_fault,
+            })
+            .collect();
+
+        Ok(SendMessageBatchResult { successful, failed })
+    }
+
+    async fn get_queue_attributes(
+        &self,
+        input: GetQueueAttributesRequest,
+    ) -> Result<GetQueueAttributesResult, RusotoError<GetQueueAttributesError>> {
+        let attribute_names = input.attribute_names.map(|names| {
+            names
+                .iter()
+                .map(|name| QueueAttributeName::from(name.as_str()))
+                .collect()
+        });
+
+        let output = self
+            .client
+            .get_queue_attributes()
+            .queue_url(input.queue_url)
+            .set_attribute_names(attribute_names)
+            .send()
+            .await
+            .map_err(convert_error)?;
+
+        let attributes = output.attributes.map(|attributes| {
+            attributes
+                .into_iter()
+                .map(|(name, value)| (name.as_str().to_string(), value))
+                .collect()
+        });
+
+        Ok(GetQueueAttributesResult { attributes })
+    }
+}
@@ -0,0 +1,141 @@
+//! Backoff policies, deciding how long to wait before retrying a failed operation
+//!
+//! Use [ExponentialBackoff], implement [Backoff] for a custom policy, or enable the `backoff`
+//! feature to use the tested policies of the [backoff](https://docs.rs/backoff) crate through
+//! [BackoffCompat]
+//!
+//! ```rust,ignore
+//! let client = SQSListenerClientBuilder::new(Region::UsEast1)
+//!     .listener(listener)
+//!     .receive_backoff(BackoffCompat(backoff::ExponentialBackoff::default()))
+//!     .build()?;
+//! ```
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time::Duration;
+
+/// A backoff policy for retrying an operation
+pub trait Backoff: Send {
+    /// Resets the policy after the operation succeeded
+    fn reset(&mut self) {}
+
+    /// How long to wait before the next retry, `None` stops waiting and retries at the usual pace
+    fn next_backoff(&mut self) -> Option<Duration>;
+}
+
+impl<B: Backoff + ?Sized> Backoff for Box<B> {
+    fn reset(&mut self) {
+        (**self).reset()
+    }
+
+    fn next_backoff(&mut self) -> Option<Duration> {
+        (**self).next_backoff()
+    }
+}
+
+/// Waits `initial`, then twice as long after every further failure up to `max`, each wait
+/// shortened by a random part of up to `jitter` so clients that failed together don't retry
+/// together
+#[derive(Debug, Clone)]
+pub struct ExponentialBackoff {
+    initial: Duration,
+    max: Duration,
+    jitter: f64,
+    max_retries: Option<u32>,
+    retries: u32,
+}
+
+impl ExponentialBackoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            jitter: 0.2,
+            max_retries: None,
+            retries: 0,
+        }
+    }
+
+    /// Fraction of each wait that is random, from 0.0 to 1.0. Defaults to 0.2
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Give up after this many retries, defaults to retrying forever
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+}
+
+impl Backoff for ExponentialBackoff {
+    fn reset(&mut self) {
+        self.retries = 0;
+    }
+
+    fn next_backoff(&mut self) -> Option<Duration> {
+        if self.max_retries.map_or(false, |max| self.retries >= max) {
+            return None;
+        }
+
+        let factor = 2_u32.saturating_pow(self.retries.min(31));
+        let delay = self
+            .initial
+            .checked_mul(factor)
+            .unwrap_or(self.max)
+            .min(self.max);
+
+        self.retries += 1;
+
+        Some(delay.mul_f64(1.0 - self.jitter * random_fraction(self.retries)))
+    }
+}
+
+// RandomState is seeded randomly, so every policy gets different jitter
+fn random_fraction(seed: u32) -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    seed.hash(&mut hasher);
+    hasher.finish() as f64 / u64::MAX as f64
+}
+
+/// Adapter for the policies of the [backoff](https://docs.rs/backoff) crate, enable with the
+/// `backoff` feature
+#[cfg(feature = "backoff")]
+#[derive(Debug, Clone)]
+pub struct BackoffCompat<B>(pub B);
+
+#[cfg(feature = "backoff")]
+impl<B: ::backoff::backoff::Backoff + Send> Backoff for BackoffCompat<B> {
+    fn reset(&mut self) {
+        self.0.reset()
+    }
+
+    fn next_backoff(&mut self) -> Option<Duration> {
+        self.0.next_backoff()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_backoff_doubles_up_to_max() {
+        let mut backoff = ExponentialBackoff::new(Duration::from_secs(1), Duration::from_secs(5))
+            .jitter(0.0)
+            .max_retries(4);
+
+        let delays: Vec<_> = std::iter::from_fn(|| backoff.next_backoff()).collect();
+        assert_eq!(
+            delays,
+            vec![1, 2, 4, 5]
+                .into_iter()
+                .map(Duration::from_secs)
+                .collect::<Vec<_>>()
+        );
+
+        backoff.reset();
+        assert_eq!(backoff.next_backoff(), Some(Duration::from_secs(1)));
+    }
+}
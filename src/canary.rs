@@ -0,0 +1,78 @@
+//! Canary messages, used to check that a listener can send, receive and acknowledge messages
+use rusoto_sqs::{Message, MessageAttributeValue, SendMessageRequest};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Message attribute that marks a message as a canary, its value is the canary id
+pub(crate) const CANARY_ATTRIBUTE: &str = "sqs_listener_canary";
+
+/// Message attribute with the time a canary expires at, in milliseconds since the epoch. Until
+/// then a canary is left for the listener that sent it, afterwards any listener deletes it
+pub(crate) const EXPIRES_AT_ATTRIBUTE: &str = "sqs_listener_canary_expires_at";
+
+/// Request to send a canary with the given id to the queue, expiring after `expires_in`
+pub(crate) fn send_request(queue_url: &str, id: &str, expires_in: Duration) -> SendMessageRequest {
+    let expires_at = now_millis() + expires_in.as_millis();
+
+    let mut message_attributes = HashMap::new();
+    message_attributes.insert(
+        CANARY_ATTRIBUTE.to_string(),
+        MessageAttributeValue {
+            data_type: "String".to_string(),
+            string_value: Some(id.to_string()),
+            ..Default::default()
+        },
+    );
+    message_attributes.insert(
+        EXPIRES_AT_ATTRIBUTE.to_string(),
+        MessageAttributeValue {
+            data_type: "Number".to_string(),
+            string_value: Some(expires_at.to_string()),
+            ..Default::default()
+        },
+    );
+
+    // FIFO queues require a group id and a deduplication id
+    let (message_group_id, message_deduplication_id) = if queue_url.ends_with(".fifo") {
+        (Some(CANARY_ATTRIBUTE.to_string()), Some(id.to_string()))
+    } else {
+        (None, None)
+    };
+
+    SendMessageRequest {
+        queue_url: queue_url.to_string(),
+        message_body: format!("{} {}", CANARY_ATTRIBUTE, id),
+        message_attributes: Some(message_attributes),
+        message_group_id,
+        message_deduplication_id,
+        ..Default::default()
+    }
+}
+
+/// Returns the canary id if the message is a canary
+pub(crate) fn id(message: &Message) -> Option<&str> {
+    message
+        .message_attributes
+        .as_ref()?
+        .get(CANARY_ATTRIBUTE)?
+        .string_value
+        .as_deref()
+}
+
+/// Returns true if the canary expired, or was sent without an expiry by an older version
+pub(crate) fn is_expired(message: &Message) -> bool {
+    message
+        .message_attributes
+        .as_ref()
+        .and_then(|attributes| attributes.get(EXPIRES_AT_ATTRIBUTE))
+        .and_then(|attribute| attribute.string_value.as_deref())
+        .and_then(|expires_at| expires_at.parse::<u128>().ok())
+        .map_or(true, |expires_at| expires_at <= now_millis())
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or_default()
+}
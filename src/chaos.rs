@@ -0,0 +1,303 @@
+//! Fault injection for chaos testing, to check that retries and deduplication hold up before an
+//! AWS incident tests them
+//!
+//! Injects receive failures, ack failures, duplicated deliveries and handler delays with the
+//! configured probabilities. Enable with the `chaos` feature
+//!
+//! ```rust,ignore
+//! let faults = FaultInjectionBuilder::default()
+//!     .receive_failure_rate(0.1)
+//!     .duplicate_rate(0.05)
+//!     .build();
+//!
+//! let client = SQSListenerClientBuilder::new(Region::UsEast1)
+//!     .listener(listener)
+//!     .fault_injection(faults)
+//!     .build()?;
+//! ```
+use async_trait::async_trait;
+use derive_builder::Builder;
+use rusoto_core::request::HttpDispatchError;
+use rusoto_core::RusotoError;
+use rusoto_sqs::{
+    BatchResultErrorEntry, ChangeMessageVisibilityError, ChangeMessageVisibilityRequest,
+    DeleteMessageBatchError, DeleteMessageBatchRequest, DeleteMessageBatchResult,
+    DeleteMessageError, DeleteMessageRequest, GetQueueAttributesError, GetQueueAttributesRequest,
+    GetQueueAttributesResult, ReceiveMessageError, ReceiveMessageRequest, ReceiveMessageResult,
+    SendMessageBatchError, SendMessageBatchRequest, SendMessageBatchResult, SendMessageError,
+    SendMessageRequest, SendMessageResult, SqsClient,
+};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::transport::Transport;
+
+/// Faults to inject, every rate is a probability from 0.0 to 1.0 and defaults to 0.0
+#[derive(Clone, Builder, Debug)]
+#[builder(pattern = "owned")]
+#[builder(build_fn(name = "build_private", private))]
+pub struct FaultInjection {
+    #[builder(default = "0.0")]
+    /// Probability that a receive fails
+    receive_failure_rate: f64,
+
+    #[builder(default = "0.0")]
+    /// Probability that acknowledging a message fails, the message is redelivered after its
+    /// visibility timeout
+    ack_failure_rate: f64,
+
+    #[builder(default = "0.0")]
+    /// Probability that a received message is delivered twice in the same batch
+    duplicate_rate: f64,
+
+    #[builder(default = "0.0")]
+    /// Probability that the handler is delayed by `handler_delay`
+    handler_delay_rate: f64,
+
+    #[builder(default = "Duration::from_secs(1_u64)")]
+    /// How long a delayed handler waits before it is called, defaults to 1 second
+    handler_delay: Duration,
+
+    #[builder(default = "None", setter(strip_option))]
+    /// Seed for the fault decisions, to replay the same faults in a test. Random by default
+    seed: Option<u64>,
+}
+
+impl FaultInjectionBuilder {
+    pub fn build(self) -> FaultInjection {
+        self.build_private()
+            .expect("will always work because all fields have defaults")
+    }
+}
+
+/// Decides which faults to inject
+pub(crate) struct Injector {
+    faults: FaultInjection,
+    state: Mutex<u64>,
+}
+
+impl Injector {
+    pub(crate) fn new(faults: FaultInjection) -> Self {
+        let seed = faults.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_nanos() as u64)
+                .unwrap_or_default()
+        });
+
+        Self {
+            faults,
+            state: Mutex::new(seed),
+        }
+    }
+
+    /// How long to delay the handler, if a delay is injected
+    pub(crate) fn handler_delay(&self) -> Option<Duration> {
+        if self.inject(self.faults.handler_delay_rate) {
+            Some(self.faults.handler_delay)
+        } else {
+            None
+        }
+    }
+
+    fn inject(&self, rate: f64) -> bool {
+        rate > 0.0 && self.next_f64() < rate
+    }
+
+    // splitmix64, random enough to decide faults and needs no dependency
+    fn next_f64(&self) -> f64 {
+        let mut state = self.state.lock().expect("fault injection state poisoned");
+        *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut value = *state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        value ^= value >> 31;
+
+        (value >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
+
+/// Wraps the transport used by the listener, failing and duplicating requests
+pub(crate) struct ChaosTransport {
+    inner: Arc<dyn Transport>,
+    injector: Arc<Injector>,
+}
+
+impl ChaosTransport {
+    pub(crate) fn new(inner: Arc<dyn Transport>, injector: Arc<Injector>) -> Self {
+        Self { inner, injector }
+    }
+}
+
+fn injected_fault<E>(operation: &str) -> RusotoError<E> {
+    RusotoError::HttpDispatch(HttpDispatchError::new(format!(
+        "injected {} failure",
+        operation
+    )))
+}
+
+#[async_trait]
+impl Transport for ChaosTransport {
+    fn sqs_client(&self) -> Option<SqsClient> {
+        self.inner.sqs_client()
+    }
+
+    async fn receive_message(
+        &self,
+        input: ReceiveMessageRequest,
+    ) -> Result<ReceiveMessageResult, RusotoError<ReceiveMessageError>> {
+        if self
+            .injector
+            .inject(self.injector.faults.receive_failure_rate)
+        {
+            return Err(injected_fault("receive"));
+        }
+
+        let mut result = self.inner.receive_message(input).await?;
+
+        if let Some(messages) = result.messages.take() {
+            let mut delivered = Vec::with_capacity(messages.len());
+
+            for message in messages {
+                if self.injector.inject(self.injector.faults.duplicate_rate) {
+                    delivered.push(message.clone());
+                }
+
+                delivered.push(message);
+            }
+
+            result.messages = Some(delivered);
+        }
+
+        Ok(result)
+    }
+
+    async fn delete_message(
+        &self,
+        input: DeleteMessageRequest,
+    ) -> Result<(), RusotoError<DeleteMessageError>> {
+        if self.injector.inject(self.injector.faults.ack_failure_rate) {
+            return Err(injected_fault("ack"));
+        }
+
+        self.inner.delete_message(input).await
+    }
+
+    async fn delete_message_batch(
+        &self,
+        input: DeleteMessageBatchRequest,
+    ) -> Result<DeleteMessageBatchResult, RusotoError<DeleteMessageBatchError>> {
+        // injected failures are reported as failed on the AWS side, so they are retried
+        let (failed, entries) = input.entries.into_iter().partition::<Vec<_>, _>(|_| {
+            self.injector.inject(self.injector.faults.ack_failure_rate)
+        });
+
+        let mut result = if entries.is_empty() {
+            DeleteMessageBatchResult::default()
+        } else {
+            self.inner
+                .delete_message_batch(DeleteMessageBatchRequest { entries, ..input })
+                .await?
+        };
+
+        result
+            .failed
+            .extend(failed.into_iter().map(|entry| BatchResultErrorEntry {
+                code: "InternalError".to_string(),
+                id: entry.id,
+                message: Some("injected ack failure".to_string()),
+                sender_fault: false,
+            }));
+
+        Ok(result)
+    }
+
+    async fn change_message_visibility(
+        &self,
+        input: ChangeMessageVisibilityRequest,
+    ) -> Result<(), RusotoError<ChangeMessageVisibilityError>> {
+        self.inner.change_message_visibility(input).await
+    }
+
+    async fn send_message(
+        &self,
+        input: SendMessageRequest,
+    ) -> Result<SendMessageResult, RusotoError<SendMessageError>> {
+        self.inner.send_message(input).await
+    }
+
+    async fn send_message_batch(
+        &self,
+        input: SendMessageBatchRequest,
+    ) -> Result<SendMessageBatchResult, RusotoError<SendMessageBatchError>> {
+        self.inner.send_message_batch(input).await
+    }
+
+    async fn get_queue_attributes(
+        &self,
+        input: GetQueueAttributesRequest,
+    ) -> Result<GetQueueAttributesResult, RusotoError<GetQueueAttributesError>> {
+        self.inner.get_queue_attributes(input).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{QueueOptions, SqsEmulator};
+    use rusoto_sqs::Message;
+
+    const QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+
+    fn transport(faults: FaultInjection) -> (ChaosTransport, SqsEmulator) {
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(QUEUE_URL, QueueOptions::default());
+        emulator.push_message(QUEUE_URL, Message::default());
+
+        let injector = Arc::new(Injector::new(faults));
+        (
+            ChaosTransport::new(Arc::new(emulator.clone()), injector),
+            emulator,
+        )
+    }
+
+    fn receive_request() -> ReceiveMessageRequest {
+        ReceiveMessageRequest {
+            queue_url: QUEUE_URL.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn injects_receive_failures() {
+        let faults = FaultInjectionBuilder::default()
+            .receive_failure_rate(1.0)
+            .build();
+
+        let (transport, emulator) = transport(faults);
+
+        assert!(transport.receive_message(receive_request()).await.is_err());
+        assert_eq!(emulator.queued_messages(QUEUE_URL).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn duplicates_deliveries() {
+        let faults = FaultInjectionBuilder::default()
+            .duplicate_rate(1.0)
+            .seed(7)
+            .build();
+
+        let (transport, _emulator) = transport(faults);
+
+        let messages = transport
+            .receive_message(receive_request())
+            .await
+            .expect("receive is not failed")
+            .messages
+            .expect("messages are returned");
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], messages[1]);
+    }
+}
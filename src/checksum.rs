@@ -0,0 +1,133 @@
+// MD5 checksums of message bodies and attributes, computed the way SQS does
+use md5::{Digest, Md5};
+use rusoto_sqs::{Message, MessageAttributeValue};
+use std::collections::HashMap;
+
+use super::Error;
+
+// transport types of attribute values, from the SQS message attribute encoding
+const STRING: u8 = 1;
+const BINARY: u8 = 2;
+const STRING_LIST: u8 = 3;
+const BINARY_LIST: u8 = 4;
+
+/// Checks the checksums SQS returned against the message, a missing checksum is not checked
+pub(crate) fn verify(message: &Message) -> Result<(), Error> {
+    if let (Some(expected), Some(body)) = (&message.md5_of_body, &message.body) {
+        check("body", expected, body_md5(body))?;
+    }
+
+    if let (Some(expected), Some(attributes)) = (
+        &message.md5_of_message_attributes,
+        &message.message_attributes,
+    ) {
+        check("attributes", expected, attributes_md5(attributes))?;
+    }
+
+    Ok(())
+}
+
+fn check(part: &'static str, expected: &str, computed: String) -> Result<(), Error> {
+    if expected.eq_ignore_ascii_case(&computed) {
+        Ok(())
+    } else {
+        Err(Error::ChecksumMismatch {
+            part,
+            expected: expected.to_string(),
+            computed,
+        })
+    }
+}
+
+pub(crate) fn body_md5(body: &str) -> String {
+    format!("{:x}", Md5::digest(body.as_bytes()))
+}
+
+// every attribute sorted by name, each field prefixed with its length as a big endian u32
+pub(crate) fn attributes_md5(attributes: &HashMap<String, MessageAttributeValue>) -> String {
+    let mut names: Vec<&String> = attributes.keys().collect();
+    names.sort();
+
+    let mut bytes = Vec::new();
+
+    for name in names {
+        let value = &attributes[name];
+
+        push_field(&mut bytes, name.as_bytes());
+        push_field(&mut bytes, value.data_type.as_bytes());
+
+        if let Some(string_value) = &value.string_value {
+            bytes.push(STRING);
+            push_field(&mut bytes, string_value.as_bytes());
+        } else if let Some(binary_value) = &value.binary_value {
+            bytes.push(BINARY);
+            push_field(&mut bytes, binary_value);
+        } else if let Some(string_values) = &value.string_list_values {
+            bytes.push(STRING_LIST);
+            for string_value in string_values {
+                push_field(&mut bytes, string_value.as_bytes());
+            }
+        } else if let Some(binary_values) = &value.binary_list_values {
+            bytes.push(BINARY_LIST);
+            for binary_value in binary_values {
+                push_field(&mut bytes, binary_value);
+            }
+        }
+    }
+
+    format!("{:x}", Md5::digest(&bytes))
+}
+
+fn push_field(bytes: &mut Vec<u8>, field: &[u8]) {
+    bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(field);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attribute(value: &str) -> MessageAttributeValue {
+        MessageAttributeValue {
+            data_type: "String".to_string(),
+            string_value: Some(value.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn verifies_body_and_attributes() {
+        let mut attributes = HashMap::new();
+        attributes.insert("priority".to_string(), attribute("high"));
+        attributes.insert("source".to_string(), attribute("billing"));
+
+        let mut message = Message {
+            body: Some("hello".to_string()),
+            md5_of_body: Some("5d41402abc4b2a76b9719d911017c592".to_string()),
+            md5_of_message_attributes: Some(attributes_md5(&attributes)),
+            message_attributes: Some(attributes),
+            ..Default::default()
+        };
+
+        assert!(verify(&message).is_ok());
+
+        message.body = Some("hellp".to_string());
+        assert!(matches!(
+            verify(&message),
+            Err(Error::ChecksumMismatch { part: "body", .. })
+        ));
+
+        message.body = Some("hello".to_string());
+        if let Some(attributes) = &mut message.message_attributes {
+            attributes.insert("source".to_string(), attribute("shipping"));
+        }
+
+        assert!(matches!(
+            verify(&message),
+            Err(Error::ChecksumMismatch {
+                part: "attributes",
+                ..
+            })
+        ));
+    }
+}
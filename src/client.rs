@@ -1,138 +1,2566 @@
 #![doc(hidden)]
 /// Implementation details for SQSListenerClient, don't use directly.
 /// Instead use [SQSListenerClient](super::SQSListenerClient) and [SQSListenerClientBuilder](super::SQSListenerClientBuilder)
-use rusoto_sqs::{DeleteMessageRequest, Message, ReceiveMessageRequest, Sqs};
+use rusoto_sqs::{GetQueueAttributesRequest, Message, ReceiveMessageRequest, Sqs};
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::future::Future;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use tokio::time::Instant;
 
 use async_trait::async_trait;
 use derive_builder::Builder;
-use log::{debug, error, info};
+use rusoto_core::Region;
 use rusoto_sqs::SqsClient;
 
-use act_zero::runtimes::tokio::Timer;
-use act_zero::timer::Tick;
+use act_zero::runtimes::tokio::spawn_actor;
 use act_zero::*;
 
-use super::{Config, ConfigBuilder, Error, SQSListener};
+use super::acker::AckBuffer;
+use super::backoff::{Backoff, ExponentialBackoff};
+use super::chaos::{ChaosTransport, Injector};
+use super::endpoint;
+use super::extended::{self, PayloadStore};
+use super::metrics::{Metrics, QueueDepth};
+use super::redrive;
+use super::shutdown::ShutdownState;
+use super::sink::ResultSink;
+use super::telemetry::{self, debug, error, info, warn};
+use super::transport::Transport;
+use super::visibility::VisibilityPolicy;
+use super::worker::{message_group_id, Handling, Worker};
+use super::{
+    canary, unique_id, ClientRebuild, Config, ConfigBuilder, DispatchLag, Error, Handler, Health,
+    SQSListener, SlowMessage,
+};
+
+/// How long SQS keeps a `ReceiveRequestAttemptId`
+const RECEIVE_ATTEMPT_ID_VALIDITY: Duration = Duration::from_secs(5 * 60);
+
+/// Longest visibility timeout SQS accepts, 12 hours in seconds
+pub(crate) const MAX_VISIBILITY_TIMEOUT: i64 = 12 * 60 * 60;
+
+/// Minimum time between rebuilding the client after credentials expired
+const CLIENT_REBUILD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// When the poller polls next, decided by the outcome of a poll
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum NextPoll {
+    /// Messages were received, there may be more waiting
+    Now,
+
+    /// The workers had no room for more messages, poll once one of them is done
+    WorkerDone,
+
+    /// The receive was empty, failed or was skipped while backing off
+    CheckInterval,
+}
+
+/// A receive on a FIFO queue, retried with the same attempt id and batch size until it succeeds
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ReceiveAttempt {
+    pub(crate) id: String,
+    pub(crate) started_at: Instant,
+    pub(crate) batch_size: i64,
+}
+
+/// Dispatch lag, shared between the actor and every [SQSListenerClient](super::SQSListenerClient)
+#[derive(Debug, Default)]
+pub(crate) struct DispatchGauge {
+    poll_lag_micros: AtomicU64,
+    pub(crate) pending_acks: AtomicUsize,
+    pub(crate) in_flight: AtomicUsize,
+
+    // notified by the workers when they are done with a receive, so a poller waiting for room
+    // polls again right away
+    pub(crate) worker_done: Notify,
+}
+
+impl DispatchGauge {
+    pub(crate) fn snapshot(&self) -> DispatchLag {
+        DispatchLag {
+            poll_lag: Duration::from_micros(self.poll_lag_micros.load(Ordering::Relaxed)),
+            pending_acks: self.pending_acks.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Health of a poller, shared between the actor and every [SQSListenerClient](super::SQSListenerClient)
+#[derive(Debug, Default)]
+pub(crate) struct HealthGauge {
+    alive: AtomicBool,
+    // milliseconds since the unix epoch, 0 before the first successful receive
+    last_success_millis: AtomicU64,
+    consecutive_errors: AtomicU32,
+}
+
+impl HealthGauge {
+    fn receive_succeeded(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        self.last_success_millis
+            .store(now.as_millis() as u64, Ordering::Relaxed);
+        self.consecutive_errors.store(0, Ordering::Relaxed);
+    }
+
+    fn receive_failed(&self) {
+        self.consecutive_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Health {
+        let last_success_millis = self.last_success_millis.load(Ordering::Relaxed);
+
+        Health {
+            alive: self.alive.load(Ordering::Relaxed),
+            last_successful_poll: Some(last_success_millis)
+                .filter(|millis| *millis > 0)
+                .map(|millis| UNIX_EPOCH + Duration::from_millis(millis)),
+            consecutive_errors: self.consecutive_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// marks the poller alive while the actor holds it, dropped when the actor stops or panics
+pub(crate) struct AliveGuard(Arc<HealthGauge>);
+
+impl AliveGuard {
+    fn new(health_gauge: Arc<HealthGauge>) -> Self {
+        health_gauge.alive.store(true, Ordering::Relaxed);
+        Self(health_gauge)
+    }
+}
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.0.alive.store(false, Ordering::Relaxed);
+    }
+}
 
 #[derive(Builder)]
 #[builder(pattern = "owned")]
 #[doc(hidden)]
 #[builder(build_fn(private, name = "build_private"))]
-pub struct SQSListenerClient<F: Fn(&Message) + Send + Sync + 'static> {
+pub struct SQSListenerClient<F: Handler> {
     #[builder(default = "Addr::detached()", setter(skip))]
     pub(crate) pid: Addr<SQSListenerClient<F>>,
 
-    pub(crate) client: SqsClient,
+    #[builder(setter(custom))]
+    pub(crate) client: Arc<dyn Transport>,
 
     #[builder(default = "ConfigBuilder::default().build()")]
     pub(crate) config: Config,
 
-    #[builder(default = "Timer::default()", setter(skip))]
-    pub(crate) timer: Timer,
-
     /// Add a listener to the [SQSListenerClient]
     pub(crate) listener: SQSListener<F>,
+
+    // added with `listeners()`, each gets its own poller when the client is built
+    #[builder(default = "Vec::new()", setter(custom))]
+    pub(crate) extra_listeners: Vec<SQSListener<F>>,
+
+    #[builder(default = "None", setter(custom))]
+    pub(crate) on_canary_missed: Option<Arc<dyn Fn(Duration) + Send + Sync>>,
+
+    #[builder(default = "None", setter(custom))]
+    pub(crate) result_sink: Option<Arc<dyn ResultSink>>,
+
+    #[builder(default = "None", setter(custom))]
+    pub(crate) on_slow_message: Option<Arc<dyn Fn(&SlowMessage) + Send + Sync>>,
+
+    // only set with the `chaos` feature
+    #[builder(default = "None", setter(custom))]
+    pub(crate) fault_injector: Option<Arc<Injector>>,
+
+    // creates the `receive_backoff` of every listener
+    #[builder(default = "Some(default_receive_backoff())", setter(custom))]
+    pub(crate) new_receive_backoff: Option<Arc<dyn Fn() -> Box<dyn Backoff> + Send + Sync>>,
+
+    // set by `priv_build()`, a listener backs off on its own failures only
+    #[builder(default = "None", setter(skip))]
+    pub(crate) receive_backoff: Option<Mutex<Box<dyn Backoff>>>,
+
+    // no receives until then, set by the `receive_backoff` after a failed receive
+    #[builder(default = "None", setter(skip))]
+    pub(crate) receive_retry_at: Option<Instant>,
+
+    // id of the canary that was sent and not yet received
+    #[builder(default = "None", setter(skip))]
+    pub(crate) pending_canary: Option<String>,
+
+    #[builder(default = "None", setter(skip))]
+    pub(crate) canary_sent_at: Option<Instant>,
+
+    #[builder(default = "ReceiveMessageRequest::default()", setter(skip))]
+    pub(crate) receive_request_template: ReceiveMessageRequest,
+
+    #[builder(default = "Arc::new(DispatchGauge::default())", setter(skip))]
+    pub(crate) dispatch_gauge: Arc<DispatchGauge>,
+
+    #[builder(default = "Arc::new(HealthGauge::default())", setter(skip))]
+    pub(crate) health_gauge: Arc<HealthGauge>,
+
+    // set while the actor runs
+    #[builder(default = "None", setter(skip))]
+    pub(crate) alive_guard: Option<AliveGuard>,
+
+    // shared by every listener of the client
+    #[builder(default = "Arc::new(Metrics::default())", setter(custom))]
+    pub(crate) metrics: Arc<Metrics>,
+
+    // when the next poll is expected to start
+    #[builder(default = "None", setter(skip))]
+    pub(crate) next_poll_at: Option<Instant>,
+
+    // the last failed receive on a FIFO queue
+    #[builder(default = "None", setter(skip))]
+    pub(crate) receive_attempt: Option<ReceiveAttempt>,
+
+    // the current check interval in adaptive mode, `None` until the first receive
+    #[builder(default = "None", setter(skip))]
+    pub(crate) adaptive_interval: Option<Duration>,
+
+    // from the redrive policy of the queue, read by `tune_from_queue()`
+    #[builder(default = "None", setter(skip))]
+    pub(crate) max_receive_count: Option<u64>,
+
+    // used to validate the queue url and for the endpoint of a listener, the client has its own copy
+    #[builder(default = "None", setter(custom))]
+    pub(crate) region: Option<Region>,
+
+    #[builder(default = "None", setter(custom))]
+    pub(crate) client_factory: Option<Arc<dyn Fn() -> Arc<dyn Transport> + Send + Sync>>,
+
+    // set when a receive or ack failed because the credentials expired
+    #[builder(default = "Arc::new(AtomicBool::new(false))", setter(skip))]
+    pub(crate) credentials_expired: Arc<AtomicBool>,
+
+    #[builder(default = "None", setter(skip))]
+    pub(crate) client_rebuilt_at: Option<Instant>,
+
+    #[builder(default = "None", setter(custom))]
+    pub(crate) on_client_rebuilt: Option<Arc<dyn Fn(&ClientRebuild) + Send + Sync>>,
+
+    // first of the receives that are still failing with connection errors
+    #[builder(default = "None", setter(skip))]
+    pub(crate) connection_failing_since: Option<Instant>,
+
+    // start of the `warm_up` period
+    #[builder(default = "None", setter(skip))]
+    pub(crate) first_poll_at: Option<Instant>,
+
+    #[builder(default = "None", setter(custom))]
+    pub(crate) on_receipt_handle_expired: Option<Arc<dyn Fn(&Message) + Send + Sync>>,
+
+    #[builder(default = "None", setter(custom))]
+    pub(crate) on_started: Option<Arc<dyn Fn(&str) + Send + Sync>>,
+
+    #[builder(default = "None", setter(custom))]
+    pub(crate) on_poll_empty: Option<Arc<dyn Fn(&str) + Send + Sync>>,
+
+    #[builder(default = "None", setter(custom))]
+    pub(crate) on_message_acked: Option<Arc<dyn Fn(&Message) + Send + Sync>>,
+
+    #[builder(default = "None", setter(custom))]
+    pub(crate) on_stopped: Option<Arc<dyn Fn(&str) + Send + Sync>>,
+
+    #[builder(default = "None", setter(custom))]
+    pub(crate) on_error: Option<Arc<dyn Fn(&Error) + Send + Sync>>,
+
+    #[builder(default = "None", setter(custom))]
+    pub(crate) on_queue_depth: Option<Arc<dyn Fn(&QueueDepth) + Send + Sync>>,
+
+    #[builder(default = "None", setter(skip))]
+    pub(crate) queue_depth_read_at: Option<Instant>,
+
+    #[builder(default = "None", setter(custom))]
+    pub(crate) extended_payloads: Option<Arc<dyn PayloadStore>>,
+
+    #[builder(default = "None", setter(custom))]
+    pub(crate) handler_retry: Option<Arc<dyn Fn() -> Box<dyn Backoff> + Send + Sync>>,
+
+    #[builder(default = "None", setter(custom))]
+    pub(crate) visibility_policy: Option<Arc<dyn VisibilityPolicy>>,
+
+    #[builder(default = "false")]
+    /// Shut down gracefully on SIGTERM or SIGINT, like a [ShutdownHandle](crate::ShutdownHandle)
+    /// would, defaults to false. Once installed the signal handlers stay in place, so exit your
+    /// application after `start()` returns
+    pub(crate) shutdown_on_signals: bool,
+
+    // set on start, shared with the shutdown handles
+    #[builder(default = "None", setter(skip))]
+    pub(crate) shutdown: Option<Arc<ShutdownState>>,
+
+    // set by `priv_build()`, shared with the workers
+    #[builder(default = "None", setter(skip))]
+    pub(crate) handling: Option<Arc<Handling<F>>>,
+
+    // spawned when the actor starts
+    #[builder(default = "Vec::new()", setter(skip))]
+    pub(crate) workers: Vec<Addr<Worker<F>>>,
+
+    // worker to pass the next message without a group to
+    #[builder(default = "0", setter(skip))]
+    pub(crate) next_worker: usize,
 }
 
-impl<F: Fn(&Message) + Send + Sync> SQSListenerClientBuilder<F> {
+impl<F: Handler> SQSListenerClientBuilder<F> {
     // implementation detail
     pub(crate) fn priv_build(self) -> Result<SQSListenerClient<F>, SQSListenerClientBuilderError> {
-        self.build_private()
+        let mut client = self.build_private()?;
+        client.validate_partition()?;
+
+        client.receive_request_template = client.new_receive_request_template();
+        client.client = client.wrap_transport(client.client.clone());
+        client.use_listener_endpoint()?;
+        client.receive_backoff = client.new_receive_backoff();
+        client.handling = Some(Arc::new(client.new_handling()));
+
+        Ok(client)
+    }
+
+    /// Listen to several queues with one client. Each listener gets its own poller, workers and
+    /// `receive_backoff`, and shares the SQS client, config and hooks with the others. Replaces a
+    /// listener set with [`listener()`](Self::listener)
+    pub fn listeners(mut self, listeners: Vec<SQSListener<F>>) -> Self {
+        let mut listeners = listeners.into_iter();
+
+        self.listener = listeners.next();
+        self.extra_listeners = Some(listeners.collect());
+        self
     }
 
     // implementation, needs to be in this module because we are using Default with private fields
     pub(crate) fn priv_new_with_client(client: SqsClient) -> Self {
-        Self {
-            client: Some(client),
-            ..Default::default()
-        }
+        Self::default().client(client)
+    }
+
+    /// Called after the SQS client was rebuilt, because credentials expired or connections kept
+    /// failing for `rebuild_client_after`
+    pub fn on_client_rebuilt(
+        mut self,
+        hook: impl Fn(&ClientRebuild) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_client_rebuilt = Some(Some(Arc::new(hook)));
+        self
+    }
+
+    /// The region of the client, used to check that the queue is in the same partition (`aws`,
+    /// `aws-us-gov` or `aws-cn`). Set by the constructors that take a region
+    pub fn region(mut self, region: Region) -> Self {
+        self.region = Some(Some(region));
+        self
+    }
+
+    /// Creates a new client when credentials have expired (`ExpiredToken`, `InvalidClientTokenId`)
+    /// or connections keep failing, instead of retrying a dead session or connection pool until a
+    /// restart.
+    /// Set automatically by the constructors that create their own client
+    pub fn client_factory<S: Sqs + 'static>(
+        mut self,
+        factory: impl Fn() -> S + Send + Sync + 'static,
+    ) -> Self {
+        let factory = move || Arc::new(factory()) as Arc<dyn Transport>;
+        self.client_factory = Some(Some(Arc::new(factory)));
+        self
+    }
+
+    /// Use this client to talk to SQS, usually a [SqsClient] but any [Sqs] implementation works,
+    /// for example a mock in tests
+    pub fn client(self, client: impl Sqs + 'static) -> Self {
+        self.transport(Arc::new(client))
+    }
+
+    pub(crate) fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.client = Some(transport);
+        self
+    }
+
+    /// Called when a canary message, enabled with the `canary_interval` [Config](super::ConfigBuilder)
+    /// option, was not consumed within `canary_timeout`. Receives the time since the canary was sent
+    pub fn on_canary_missed(mut self, hook: impl Fn(Duration) + Send + Sync + 'static) -> Self {
+        self.on_canary_missed = Some(Some(Arc::new(hook)));
+        self
+    }
+
+    /// Add a [ResultSink](super::sink::ResultSink) which receives the outcome of every message
+    pub fn result_sink(mut self, result_sink: impl ResultSink + 'static) -> Self {
+        self.result_sink = Some(Some(Arc::new(result_sink)));
+        self
+    }
+
+    /// Called when a handler takes longer than the `slow_message_threshold`
+    /// [Config](super::ConfigBuilder) option
+    pub fn on_slow_message(mut self, hook: impl Fn(&SlowMessage) + Send + Sync + 'static) -> Self {
+        self.on_slow_message = Some(Some(Arc::new(hook)));
+        self
+    }
+
+    /// Called when a message could not be acknowledged because its receipt handle expired, usually
+    /// because the handler took longer than the visibility timeout. The message will be
+    /// redelivered, so it is handled again
+    pub fn on_receipt_handle_expired(
+        mut self,
+        hook: impl Fn(&Message) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_receipt_handle_expired = Some(Some(Arc::new(hook)));
+        self
+    }
+
+    /// Called with the queue url when the listener of a queue has started, before its first poll
+    pub fn on_started(mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.on_started = Some(Some(Arc::new(hook)));
+        self
+    }
+
+    /// Called with the queue url after a receive that returned no messages
+    pub fn on_poll_empty(mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.on_poll_empty = Some(Some(Arc::new(hook)));
+        self
+    }
+
+    /// Called for every message deleted from the queue, automatically or manually
+    pub fn on_message_acked(mut self, hook: impl Fn(&Message) + Send + Sync + 'static) -> Self {
+        self.on_message_acked = Some(Some(Arc::new(hook)));
+        self
+    }
+
+    /// Called with the queue url once the listener of a queue has stopped, after a shutdown
+    pub fn on_stopped(mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.on_stopped = Some(Some(Arc::new(hook)));
+        self
+    }
+
+    /// Called when a receive fails or a message could not be acknowledged, in addition to the
+    /// error being logged. Use it to raise alarms or trip a circuit breaker on persistent failures
+    pub fn on_error(mut self, hook: impl Fn(&Error) + Send + Sync + 'static) -> Self {
+        self.on_error = Some(Some(Arc::new(hook)));
+        self
+    }
+
+    /// Called with the number of messages on the queue every `queue_depth_interval`
+    /// [Config](super::ConfigBuilder) option
+    pub fn on_queue_depth(mut self, hook: impl Fn(&QueueDepth) + Send + Sync + 'static) -> Self {
+        self.on_queue_depth = Some(Some(Arc::new(hook)));
+        self
+    }
+
+    /// Download the payloads of messages sent with the SQS Extended Client before handling them,
+    /// see [extended](super::extended)
+    pub fn extended_payloads(mut self, store: impl PayloadStore + 'static) -> Self {
+        self.extended_payloads = Some(Some(Arc::new(store)));
+        self
+    }
+
+    /// Inject faults into receives, acks and handlers, see [chaos](super::chaos)
+    #[cfg(feature = "chaos")]
+    pub fn fault_injection(mut self, faults: super::chaos::FaultInjection) -> Self {
+        self.fault_injector = Some(Some(Arc::new(Injector::new(faults))));
+        self
+    }
+
+    /// Retry a failed handler in process, waiting as long as the backoff says between attempts,
+    /// before the message is left on the queue to be redelivered. The backoff is cloned for every
+    /// message, for example `ExponentialBackoff::new(initial, max).max_retries(3)`. Retries count
+    /// against the visibility timeout, see [`visibility_policy()`](Self::visibility_policy)
+    pub fn handler_retry(mut self, backoff: impl Backoff + Clone + Sync + 'static) -> Self {
+        self.handler_retry = Some(Some(Arc::new(move || {
+            Box::new(backoff.clone()) as Box<dyn Backoff>
+        })));
+        self
+    }
+
+    /// Extend the visibility timeout of messages while their handler is still running, see
+    /// [visibility](super::visibility)
+    pub fn visibility_policy(mut self, policy: impl VisibilityPolicy + 'static) -> Self {
+        self.visibility_policy = Some(Some(Arc::new(policy)));
+        self
+    }
+
+    /// Register the metrics of the listener with a prometheus registry, fails if metrics with the
+    /// same names are registered already. See [metrics](super::metrics)
+    #[cfg(feature = "prometheus")]
+    pub fn prometheus_registry(mut self, registry: &prometheus::Registry) -> Result<Self, Error> {
+        self.metrics = Some(Arc::new(Metrics::with_registry(registry)?));
+        Ok(self)
+    }
+
+    /// Wait before receiving again after a failed receive, instead of retrying at every
+    /// `check_interval`. Defaults to an [ExponentialBackoff] from 1 second up to 1 minute, reset
+    /// by the next successful receive. The backoff is cloned for every listener, so failures on
+    /// one queue don't hold back the others. See [backoff](super::backoff)
+    pub fn receive_backoff(mut self, backoff: impl Backoff + Clone + Sync + 'static) -> Self {
+        self.new_receive_backoff = Some(Some(Arc::new(move || {
+            Box::new(backoff.clone()) as Box<dyn Backoff>
+        })));
+        self
     }
 }
 
-impl<F: Fn(&Message) + Send + Sync> SQSListenerClient<F> {
-    pub(crate) async fn ack_message(&self, message: Message) -> ActorResult<Result<(), Error>> {
-        if message.receipt_handle.is_none() {
-            return Produces::ok(Err(Error::NoMessageHandle));
-        }
+impl<F: Handler> SQSListenerClient<F> {
+    // a client for each listener added with `listeners()`, sharing the settings of this one
+    pub(crate) fn split_listeners(
+        &mut self,
+    ) -> Result<Vec<SQSListenerClient<F>>, SQSListenerClientBuilderError> {
+        std::mem::take(&mut self.extra_listeners)
+            .into_iter()
+            .map(|listener| {
+                let mut client = self.with_listener(listener);
+                client.validate_partition()?;
+                client.use_listener_endpoint()?;
+                Ok(client)
+            })
+            .collect()
+    }
+
+    fn with_listener(&self, listener: SQSListener<F>) -> SQSListenerClient<F> {
+        let mut client = SQSListenerClient {
+            pid: Addr::detached(),
+            client: self.client.clone(),
+            config: self.config.clone(),
+            listener,
+            extra_listeners: Vec::new(),
+            on_canary_missed: self.on_canary_missed.clone(),
+            result_sink: self.result_sink.clone(),
+            on_slow_message: self.on_slow_message.clone(),
+            fault_injector: self.fault_injector.clone(),
+            new_receive_backoff: self.new_receive_backoff.clone(),
+            receive_backoff: None,
+            receive_retry_at: None,
+            pending_canary: None,
+            canary_sent_at: None,
+            receive_request_template: ReceiveMessageRequest::default(),
+            dispatch_gauge: Arc::new(DispatchGauge::default()),
+            health_gauge: Arc::new(HealthGauge::default()),
+            alive_guard: None,
+            metrics: self.metrics.clone(),
+            next_poll_at: None,
+            receive_attempt: None,
+            adaptive_interval: None,
+            max_receive_count: None,
+            region: self.region.clone(),
+            client_factory: self.client_factory.clone(),
+            credentials_expired: Arc::new(AtomicBool::new(false)),
+            client_rebuilt_at: None,
+            on_client_rebuilt: self.on_client_rebuilt.clone(),
+            connection_failing_since: None,
+            first_poll_at: None,
+            on_receipt_handle_expired: self.on_receipt_handle_expired.clone(),
+            on_started: self.on_started.clone(),
+            on_poll_empty: self.on_poll_empty.clone(),
+            on_message_acked: self.on_message_acked.clone(),
+            on_stopped: self.on_stopped.clone(),
+            on_error: self.on_error.clone(),
+            on_queue_depth: self.on_queue_depth.clone(),
+            queue_depth_read_at: None,
+            extended_payloads: self.extended_payloads.clone(),
+            handler_retry: self.handler_retry.clone(),
+            visibility_policy: self.visibility_policy.clone(),
+            shutdown_on_signals: self.shutdown_on_signals,
+            shutdown: None,
+            handling: None,
+            workers: Vec::new(),
+            next_worker: 0,
+        };
+
+        client.receive_request_template = client.new_receive_request_template();
+        client.receive_backoff = client.new_receive_backoff();
+        client.handling = Some(Arc::new(client.new_handling()));
+
+        client
+    }
+
+    // checks the queue exists and is accessible, connecting on the way, returns how long it took
+    pub(crate) async fn validate_queue(&self) -> Result<Duration, Error> {
+        let started_at = Instant::now();
+
+        self.client
+            .get_queue_attributes(GetQueueAttributesRequest {
+                queue_url: self.listener.queue_url.clone(),
+                attribute_names: Some(vec!["QueueArn".to_string()]),
+            })
+            .await?;
+
+        Ok(started_at.elapsed())
+    }
 
-        let ignore = self
+    // reads the visibility timeout, long poll wait time and redrive policy of the queue, deriving
+    // the slow message threshold from the visibility timeout and warning about settings that don't
+    // fit the queue
+    pub(crate) async fn tune_from_queue(&mut self) -> Result<(), Error> {
+        let attributes = self
             .client
-            .delete_message(DeleteMessageRequest {
+            .get_queue_attributes(GetQueueAttributesRequest {
                 queue_url: self.listener.queue_url.clone(),
-                receipt_handle: message.receipt_handle.clone().unwrap(),
+                attribute_names: Some(vec![
+                    "VisibilityTimeout".to_string(),
+                    "ReceiveMessageWaitTimeSeconds".to_string(),
+                    "RedrivePolicy".to_string(),
+                ]),
             })
-            .await;
+            .await?
+            .attributes
+            .unwrap_or_default();
+
+        let seconds = |name: &str| {
+            attributes
+                .get(name)
+                .and_then(|seconds| seconds.parse().ok())
+                .map(Duration::from_secs)
+        };
+
+        // an explicit `visibility_timeout` overrides the one of the queue
+        let visibility_timeout = self
+            .config
+            .visibility_timeout
+            .or_else(|| seconds("VisibilityTimeout"));
+
+        if let Some(visibility_timeout) = visibility_timeout {
+            match self.config.slow_message_threshold {
+                None => {
+                    let threshold = visibility_timeout / 2;
+                    info!(
+                        "Warning about messages taking longer than {:?}, half the visibility timeout",
+                        threshold
+                    );
 
-        match ignore {
-            Ok(_) => Produces::ok(Ok(())),
-            Err(error) => Produces::ok(Err(Error::AckMessage(error))),
+                    self.config.slow_message_threshold = Some(threshold);
+                    self.receive_request_template = self.new_receive_request_template();
+                    self.handling = Some(Arc::new(self.new_handling()));
+                }
+                Some(threshold) if threshold >= visibility_timeout => warn!(
+                    "slow_message_threshold of {:?} is not below the visibility timeout of {:?}, \
+                     messages will be redelivered while they are still being handled",
+                    threshold, visibility_timeout
+                ),
+                Some(_) => {}
+            }
+        }
+
+        // an explicit `wait_time_seconds` overrides the wait time of the queue
+        if let (None, Some(wait_time)) = (
+            self.config.wait_time_seconds,
+            seconds("ReceiveMessageWaitTimeSeconds"),
+        ) {
+            if wait_time > Duration::from_secs(0) {
+                warn!(
+                    "Queue long polls for up to {:?}, every receive blocks the listener and delays \
+                     acknowledgments for that long, set wait_time_seconds to choose explicitly",
+                    wait_time
+                );
+            }
         }
+
+        // failures on the last receive the redrive policy allows are recorded as dead-lettered
+        let max_receive_count = attributes
+            .get("RedrivePolicy")
+            .and_then(|redrive_policy| redrive::max_receive_count(redrive_policy));
+
+        if max_receive_count.is_some() {
+            self.max_receive_count = max_receive_count;
+            self.receive_request_template = self.new_receive_request_template();
+            self.handling = Some(Arc::new(self.new_handling()));
+        }
+
+        Ok(())
     }
-}
 
-#[async_trait]
-impl<F: Fn(&Message) + Send + Sync> Actor for SQSListenerClient<F> {
-    async fn started(&mut self, pid: Addr<Self>) -> ActorResult<()> {
-        info!("SQSListenerClient started...");
+    // sends a canary message and waits until it has been received and acked, messages received
+    // in the meantime are handled as usual, returns the round trip time of the canary
+    pub(crate) async fn self_test(&self, timeout: Duration) -> Result<Duration, Error> {
+        let canary_id = unique_id();
+        let started_at = Instant::now();
 
-        // Start the timer
-        self.timer
-            .set_timeout_for_strong(pid.clone(), self.config.check_interval);
+        self.client
+            .send_message(canary::send_request(
+                &self.listener.queue_url,
+                &canary_id,
+                timeout,
+            ))
+            .await?;
 
-        self.pid = pid;
+        while started_at.elapsed() < timeout {
+            let messages = self
+                .client
+                .receive_message(ReceiveMessageRequest {
+                    wait_time_seconds: Some(1),
+                    ..self.receive_request()
+                })
+                .await?
+                .messages
+                .unwrap_or_default();
 
-        Produces::ok(())
+            for message in messages {
+                if canary::id(&message) == Some(canary_id.as_str()) {
+                    self.handling()
+                        .delete_message(message.receipt_handle)
+                        .await?;
+                    return Ok(started_at.elapsed());
+                }
+
+                // canaries of other consumers are left for them
+                self.handling().handle_message(message).await;
+            }
+        }
+
+        Err(Error::SelfTestTimeout(timeout))
     }
 
-    async fn error(&mut self, error: ActorError) -> bool {
-        error!("SQSListenerClient Error: {:?}", error);
+    pub(crate) async fn ack_message(&self, mut message: Message) -> ActorResult<Result<(), Error>> {
+        Produces::ok(self.handling().ack(&mut message).await)
+    }
 
-        // do not stop on actor error
-        false
+    pub(crate) async fn ack_messages(
+        &self,
+        mut messages: Vec<Message>,
+    ) -> ActorResult<Vec<Result<(), Arc<Error>>>> {
+        Produces::ok(self.handling().ack_batch(&mut messages).await)
     }
-}
 
-#[async_trait]
-impl<F: Fn(&Message) + Send + Sync> Tick for SQSListenerClient<F> {
-    async fn tick(&mut self) -> ActorResult<()> {
-        if self.timer.tick() {
-            self.timer
-                .set_timeout_for_strong(self.pid.clone(), self.config.check_interval);
+    pub(crate) async fn nack_message(
+        &self,
+        message: Message,
+        delay: Duration,
+    ) -> ActorResult<Result<(), Error>> {
+        Produces::ok(self.handling().nack(message, delay).await)
+    }
 
-            match self.get_and_handle_messages().await {
-                Ok(()) => {}
-                Err(error) => error!("Error when handling message: {:?}", error),
-            }
+    // applies a new config from the next poll on, settings only read on start keep their value
+    pub(crate) async fn update_config(&mut self, config: Config) -> ActorResult<()> {
+        info!("Updating config of listener {}", self.listener.queue_url);
+
+        self.config = config;
+        self.adaptive_interval = None;
+        self.receive_request_template = self.new_receive_request_template();
+
+        // the new handling shares the ack buffer of the previous one
+        let handling = Arc::new(self.new_handling());
+
+        for worker in &self.workers {
+            let handling = handling.clone();
+            send!(worker.set_handling(handling));
+        }
+
+        if self.config.batch_acks {
+            flush_acks_periodically(Arc::downgrade(&handling), self.config.ack_flush_interval);
         }
+
+        self.handling = Some(handling);
+
         Produces::ok(())
     }
-}
 
-impl<F: Fn(&Message) + Send + Sync> SQSListenerClient<F> {
-    async fn get_and_handle_messages(&self) -> Result<(), Error> {
-        debug!("get and handle messages called");
-        let handler = &self.listener.handler;
+    // stops the actor, queued behind the poll in progress so it finishes first, then waits for
+    // the workers to handle the messages passed on to them
+    pub(crate) async fn shutdown(&mut self) -> ActorResult<()> {
+        for worker in &self.workers {
+            // a worker that stopped has nothing left to drain
+            let _ = call!(worker.drain()).await;
+        }
 
-        let messages = self
-            .client
-            .receive_message(ReceiveMessageRequest {
-                queue_url: self.listener.queue_url.clone(),
-                ..Default::default()
-            })
-            .await?
-            .messages
-            .ok_or(Error::UnknownReceiveMessages)?;
+        self.handling().flush_acks().await;
 
-        for message in messages {
-            // ignore result
-            handler(&message);
+        Err(Box::new(Error::ListenerStopped))
+    }
+
+    // runs inside the actor, so the operation never overlaps with polling
+    pub(crate) async fn raw<R, Fut>(
+        &self,
+        operation: impl FnOnce(SqsClient) -> Fut + Send + 'static,
+    ) -> ActorResult<Result<R, Error>>
+    where
+        Fut: Future<Output = R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let result = match self.client.sqs_client() {
+            Some(sqs_client) => Ok(operation(sqs_client).await),
+            None => Err(Error::NoSqsClient),
+        };
+
+        Produces::ok(result)
+    }
 
-            // if auto ack is set ack message
-            if self.config.auto_ack {
-                send!(self.pid.ack_message(message.clone()))
+    /// How long to wait before the first poll, `startup_delay` plus a random part of
+    /// `startup_jitter`
+    pub(crate) fn startup_delay(&self) -> Duration {
+        let jitter = self.config.startup_jitter;
+
+        if jitter == Duration::from_secs(0) {
+            return self.config.startup_delay;
+        }
+
+        // RandomState is seeded randomly, so every listener gets a different jitter
+        let mut hasher = RandomState::new().build_hasher();
+        unique_id().hash(&mut hasher);
+        let fraction = hasher.finish() as f64 / u64::MAX as f64;
+
+        self.config.startup_delay + jitter.mul_f64(fraction)
+    }
+
+    fn receive_request(&self) -> ReceiveMessageRequest {
+        self.receive_request_template.clone()
+    }
+
+    // a queue in another partition fails with an opaque signature error on the first poll
+    fn validate_partition(&self) -> Result<(), SQSListenerClientBuilderError> {
+        let region = match &self.region {
+            Some(region) => region,
+            None => return Ok(()),
+        };
+
+        let region_partition = endpoint::partition(region.name());
+
+        match endpoint::queue_url_partition(&self.listener.queue_url) {
+            Some(queue_partition) if queue_partition != region_partition => {
+                Err(SQSListenerClientBuilderError::ValidationError(format!(
+                    "queue {} is in the {} partition, but region {} is in the {} partition",
+                    self.listener.queue_url,
+                    queue_partition,
+                    region.name(),
+                    region_partition
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // a listener with its own endpoint gets its own SQS client, which is rebuilt on the same endpoint
+    fn use_listener_endpoint(&mut self) -> Result<(), SQSListenerClientBuilderError> {
+        let endpoint = match &self.listener.endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => return Ok(()),
+        };
+
+        let region = match &self.region {
+            Some(region) => Region::Custom {
+                name: region.name().to_string(),
+                endpoint,
+            },
+            None => {
+                return Err(SQSListenerClientBuilderError::ValidationError(format!(
+                    "listener for {} has an endpoint, but the client has no region",
+                    self.listener.queue_url
+                )))
             }
+        };
+
+        let factory_region = region.clone();
+        self.client_factory = Some(Arc::new(move || {
+            Arc::new(SqsClient::new(factory_region.clone())) as Arc<dyn Transport>
+        }));
+
+        self.client = self.wrap_transport(Arc::new(SqsClient::new(region)));
+
+        if let Some(handling) = &self.handling {
+            handling.set_client(self.client.clone());
         }
 
         Ok(())
     }
+
+    fn wrap_transport(&self, transport: Arc<dyn Transport>) -> Arc<dyn Transport> {
+        match &self.fault_injector {
+            Some(injector) => Arc::new(ChaosTransport::new(transport, injector.clone())),
+            None => transport,
+        }
+    }
+
+    // a new client loads fresh credentials, at most once every `CLIENT_REBUILD_INTERVAL` so a
+    // permanently broken session does not rebuild the client on every poll
+    fn rebuild_client(&mut self, reason: ClientRebuild) {
+        if self.client_rebuilt_at.map_or(false, |rebuilt_at| {
+            rebuilt_at.elapsed() < CLIENT_REBUILD_INTERVAL
+        }) {
+            return;
+        }
+
+        let client = match &self.client_factory {
+            Some(client_factory) => client_factory(),
+            None => {
+                error!(
+                    "{}, set a client_factory to recover without a restart",
+                    reason
+                );
+                return;
+            }
+        };
+
+        warn!("{}, rebuilding the SQS client", reason);
+
+        self.client = self.wrap_transport(client);
+        self.handling().set_client(self.client.clone());
+        self.client_rebuilt_at = Some(Instant::now());
+        self.credentials_expired.store(false, Ordering::Relaxed);
+        self.connection_failing_since = None;
+        self.reset_receive_backoff();
+
+        if let Some(on_client_rebuilt) = &self.on_client_rebuilt {
+            on_client_rebuilt(&reason)
+        }
+    }
+
+    // a new client gets a new connection pool and resolves the endpoint again, recovering from
+    // stale DNS or connections broken by a NAT change
+    fn track_connection_failures<T>(&mut self, result: &Result<T, Error>) {
+        let connection_failure = match result {
+            Err(error) => error.is_connection_failure(),
+            Ok(_) => false,
+        };
+
+        if !connection_failure {
+            self.connection_failing_since = None;
+            return;
+        }
+
+        let failing_for = self
+            .connection_failing_since
+            .get_or_insert_with(Instant::now)
+            .elapsed();
+
+        if failing_for >= self.config.rebuild_client_after {
+            self.rebuild_client(ClientRebuild::ConnectionFailures(failing_for));
+        }
+    }
+
+    // built when the client is built or tuned from the queue, and cloned for every receive
+    fn new_receive_request_template(&self) -> ReceiveMessageRequest {
+        let mut attribute_names = self.config.attribute_names.clone();
+
+        // the receive count is reported for slow messages, recorded in handler spans and tells the
+        // last receive before a message is dead-lettered
+        if (self.config.slow_message_threshold.is_some()
+            || self.max_receive_count.is_some()
+            || cfg!(feature = "tracing"))
+            && !attribute_names
+                .iter()
+                .any(|name| name == "All" || name == telemetry::RECEIVE_COUNT_ATTRIBUTE)
+        {
+            attribute_names.push(telemetry::RECEIVE_COUNT_ATTRIBUTE.to_string());
+        }
+
+        // messages are grouped by it in FIFO mode
+        if self.is_fifo()
+            && !attribute_names
+                .iter()
+                .any(|name| name == "All" || name == "MessageGroupId")
+        {
+            attribute_names.push("MessageGroupId".to_string());
+        }
+
+        let attribute_names = if attribute_names.is_empty() {
+            None
+        } else {
+            Some(attribute_names)
+        };
+
+        let mut message_attribute_names = vec![
+            canary::CANARY_ATTRIBUTE.to_string(),
+            canary::EXPIRES_AT_ATTRIBUTE.to_string(),
+        ];
+
+        let requested = self
+            .config
+            .message_attribute_names
+            .iter()
+            .chain(&self.config.priority_attribute)
+            .cloned();
+
+        // tell pointers to extended payloads apart from bodies that happen to look like one
+        let payload_sizes = extended::PAYLOAD_SIZE_ATTRIBUTES
+            .iter()
+            .filter(|_| self.extended_payloads.is_some())
+            .map(|name| name.to_string());
+
+        for name in requested.chain(payload_sizes) {
+            if !message_attribute_names.contains(&name) {
+                message_attribute_names.push(name);
+            }
+        }
+
+        ReceiveMessageRequest {
+            queue_url: self.listener.queue_url.clone(),
+            attribute_names,
+            message_attribute_names: Some(message_attribute_names),
+            max_number_of_messages: Some(self.config.max_messages.clamp(1, 10)),
+            wait_time_seconds: self
+                .config
+                .wait_time_seconds
+                .map(|seconds| seconds.clamp(0, 20)),
+            visibility_timeout: self
+                .config
+                .visibility_timeout
+                .map(|timeout| (timeout.as_secs() as i64).clamp(0, MAX_VISIBILITY_TIMEOUT)),
+            ..Default::default()
+        }
+    }
+
+    fn is_fifo(&self) -> bool {
+        self.config.fifo || self.listener.queue_url.ends_with(".fifo")
+    }
+
+    // stable sort, so messages of the same priority keep the order they were received in. Never
+    // sorted in FIFO mode, the order of a group comes first
+    fn sort_by_priority(&self, messages: &mut [Message]) {
+        let priority_attribute = match &self.config.priority_attribute {
+            Some(priority_attribute) if !self.is_fifo() => priority_attribute,
+            _ => return,
+        };
+
+        let priority_values = &self.config.priority_values;
+
+        messages.sort_by_key(|message| {
+            message
+                .message_attributes
+                .as_ref()
+                .and_then(|attributes| attributes.get(priority_attribute))
+                .and_then(|value| value.string_value.as_ref())
+                .and_then(|value| {
+                    priority_values
+                        .iter()
+                        .position(|priority| priority == value)
+                })
+                .unwrap_or(priority_values.len())
+        });
+    }
+}
+
+#[async_trait]
+impl<F: Handler> Actor for SQSListenerClient<F> {
+    async fn started(&mut self, pid: Addr<Self>) -> ActorResult<()> {
+        info!("SQSListenerClient started...");
+
+        self.alive_guard = Some(AliveGuard::new(self.health_gauge.clone()));
+
+        for _ in 0..self.config.workers {
+            let worker = Worker::new(self.handling().clone(), self.dispatch_gauge.clone());
+            self.workers.push(spawn_actor(worker));
+        }
+
+        if self.config.batch_acks {
+            flush_acks_periodically(
+                Arc::downgrade(self.handling()),
+                self.config.ack_flush_interval,
+            );
+        }
+
+        if let Some(on_started) = &self.on_started {
+            on_started(&self.listener.queue_url)
+        }
+
+        // Start polling
+        self.next_poll_at = Some(Instant::now());
+        send!(pid.receive_loop());
+
+        self.pid = pid;
+
+        Produces::ok(())
+    }
+
+    async fn error(&mut self, error: ActorError) -> bool {
+        if let Some(Error::ListenerStopped) = error.downcast_ref::<Error>() {
+            info!("SQSListenerClient stopped");
+
+            if let Some(on_stopped) = &self.on_stopped {
+                on_stopped(&self.listener.queue_url)
+            }
+
+            return true;
+        }
+
+        error!("SQSListenerClient Error: {:?}", error);
+
+        // do not stop on actor error
+        false
+    }
+}
+
+impl<F: Handler> SQSListenerClient<F> {
+    // polls again right away while receives return messages, as soon as a worker is done while
+    // the workers are full, and waits `check_interval` after an empty or failed receive. Every
+    // poll is a message to the actor, so acks and shutdowns queued in the meantime are handled
+    // between polls
+    pub(crate) async fn receive_loop(&mut self) -> ActorResult<()> {
+        // a poll queued while the last one ran must not start another one after a shutdown
+        let shutting_down = self
+            .shutdown
+            .as_ref()
+            .map_or(false, |shutdown| shutdown.is_requested());
+
+        if shutting_down {
+            return Produces::ok(());
+        }
+
+        if let Some(next_poll_at) = self.next_poll_at {
+            let poll_lag = Instant::now().saturating_duration_since(next_poll_at);
+
+            self.dispatch_gauge
+                .poll_lag_micros
+                .store(poll_lag.as_micros() as u64, Ordering::Relaxed);
+        }
+
+        let pid = self.pid.clone();
+        let next_poll = self.poll().await;
+
+        if next_poll == NextPoll::Now {
+            self.next_poll_at = Some(Instant::now());
+            send!(pid.receive_loop());
+            return Produces::ok(());
+        }
+
+        let check_interval = self.check_interval();
+        self.next_poll_at = Some(Instant::now() + check_interval);
+
+        let dispatch_gauge = self.dispatch_gauge.clone();
+
+        tokio::spawn(async move {
+            if next_poll == NextPoll::WorkerDone {
+                // still polls after the check interval, for canaries and queue depth reads
+                let worker_done = dispatch_gauge.worker_done.notified();
+                let _ = tokio::time::timeout(check_interval, worker_done).await;
+            } else {
+                tokio::time::sleep(check_interval).await;
+            }
+
+            send!(pid.receive_loop());
+        });
+
+        Produces::ok(())
+    }
+
+    // a single poll cycle in its own span, returns when to poll next
+    pub(crate) async fn poll(&mut self) -> NextPoll {
+        let span = telemetry::poll_span(&self.listener.queue_url);
+        telemetry::instrument(self.poll_cycle(), span).await
+    }
+
+    async fn poll_cycle(&mut self) -> NextPoll {
+        self.metrics.poll_cycle();
+
+        let backing_off = self
+            .receive_retry_at
+            .map_or(false, |retry_at| Instant::now() < retry_at);
+
+        let mut next_poll = NextPoll::CheckInterval;
+
+        if !backing_off {
+            let result = self.get_and_handle_messages().await;
+
+            match &result {
+                Ok(received) => {
+                    self.reset_receive_backoff();
+
+                    match *received {
+                        Some(received) => {
+                            self.health_gauge.receive_succeeded();
+                            self.adapt_check_interval(received);
+
+                            if received > 0 {
+                                next_poll = NextPoll::Now;
+                            }
+                        }
+                        None => next_poll = NextPoll::WorkerDone,
+                    }
+
+                    if let (Some(0), Some(on_poll_empty)) = (*received, &self.on_poll_empty) {
+                        on_poll_empty(&self.listener.queue_url)
+                    }
+                }
+                Err(error) => {
+                    error!("Error when receiving messages: {:?}", error);
+                    self.metrics.receive_failed();
+                    self.health_gauge.receive_failed();
+
+                    if let Some(on_error) = &self.on_error {
+                        on_error(error)
+                    }
+
+                    if error.is_expired_credentials() {
+                        self.credentials_expired.store(true, Ordering::Relaxed);
+                    }
+
+                    self.back_off_receive();
+                }
+            }
+
+            // after backing off, a rebuilt client is tried right away
+            self.track_connection_failures(&result);
+
+            if let Err(error) = result {
+                self.listener.handler.receive_failed(error);
+            }
+        }
+
+        if self.credentials_expired.load(Ordering::Relaxed) {
+            self.rebuild_client(ClientRebuild::ExpiredCredentials);
+        }
+
+        if let Some(canary_interval) = self.config.canary_interval {
+            self.check_canary(canary_interval).await;
+        }
+
+        if let Some(queue_depth_interval) = self.config.queue_depth_interval {
+            self.read_queue_depth(queue_depth_interval).await;
+        }
+
+        next_poll
+    }
+
+    fn new_receive_backoff(&self) -> Option<Mutex<Box<dyn Backoff>>> {
+        self.new_receive_backoff
+            .as_ref()
+            .map(|new_backoff| Mutex::new(new_backoff()))
+    }
+
+    fn back_off_receive(&mut self) {
+        let backoff = match &self.receive_backoff {
+            Some(backoff) => backoff,
+            None => return,
+        };
+
+        let delay = backoff
+            .lock()
+            .expect("receive backoff poisoned")
+            .next_backoff();
+
+        if let Some(delay) = delay {
+            debug!("Retrying receive in {:?}", delay);
+        }
+
+        self.receive_retry_at = delay.map(|delay| Instant::now() + delay);
+    }
+
+    fn reset_receive_backoff(&mut self) {
+        if self.receive_retry_at.take().is_none() {
+            return;
+        }
+
+        if let Some(backoff) = &self.receive_backoff {
+            backoff.lock().expect("receive backoff poisoned").reset();
+        }
+    }
+
+    // alerts if the pending canary has not been consumed in time, and sends a new canary once
+    // the previous one is resolved and `canary_interval` has passed
+    async fn check_canary(&mut self, canary_interval: Duration) {
+        let elapsed = self.canary_sent_at.map(|sent_at| sent_at.elapsed());
+
+        if let (Some(canary_id), Some(elapsed)) = (&self.pending_canary, elapsed) {
+            if elapsed < self.config.canary_timeout {
+                return;
+            }
+
+            error!("Canary {} was not consumed within {:?}", canary_id, elapsed);
+
+            if let Some(on_canary_missed) = &self.on_canary_missed {
+                on_canary_missed(elapsed)
+            }
+
+            // it expired, whichever consumer receives it now deletes it
+            self.handling()
+                .own_canaries
+                .lock()
+                .expect("own canaries poisoned")
+                .remove(canary_id);
+
+            self.pending_canary = None;
+        }
+
+        if elapsed.map_or(false, |elapsed| elapsed < canary_interval) {
+            return;
+        }
+
+        let canary_id = unique_id();
+        let request = canary::send_request(
+            &self.listener.queue_url,
+            &canary_id,
+            self.config.canary_timeout,
+        );
+
+        // owned before it's sent, so it is deleted if received before the send returns
+        self.handling()
+            .own_canaries
+            .lock()
+            .expect("own canaries poisoned")
+            .insert(canary_id.clone());
+
+        match self.client.send_message(request).await {
+            Ok(_) => {
+                debug!("Canary {} sent", canary_id);
+                self.pending_canary = Some(canary_id);
+            }
+            Err(error) => {
+                error!("Error when sending canary: {:?}", Error::from(error));
+                self.handling()
+                    .own_canaries
+                    .lock()
+                    .expect("own canaries poisoned")
+                    .remove(&canary_id);
+            }
+        }
+
+        self.canary_sent_at = Some(Instant::now());
+    }
+
+    // reads the number of messages on the queue once `queue_depth_interval` has passed
+    async fn read_queue_depth(&mut self, queue_depth_interval: Duration) {
+        if self
+            .queue_depth_read_at
+            .map_or(false, |read_at| read_at.elapsed() < queue_depth_interval)
+        {
+            return;
+        }
+
+        self.queue_depth_read_at = Some(Instant::now());
+
+        let request = GetQueueAttributesRequest {
+            queue_url: self.listener.queue_url.clone(),
+            attribute_names: Some(
+                QueueDepth::ATTRIBUTES
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect(),
+            ),
+        };
+
+        let attributes = match self.client.get_queue_attributes(request).await {
+            Ok(result) => result.attributes.unwrap_or_default(),
+            Err(error) => {
+                warn!("Error when reading queue depth: {:?}", Error::from(error));
+                return;
+            }
+        };
+
+        let queue_depth = QueueDepth::from_attributes(&self.listener.queue_url, &attributes);
+        debug!("Queue depth: {:?}", queue_depth);
+
+        self.metrics.queue_depth(&queue_depth);
+
+        if let Some(on_queue_depth) = &self.on_queue_depth {
+            on_queue_depth(&queue_depth)
+        }
+    }
+
+    // on FIFO queues a failed receive is retried with the same attempt id and batch size, so SQS
+    // returns the same batch instead of leaving it invisible until the visibility timeout expires
+    fn receive_attempt(&mut self, batch_size: i64) -> Option<&ReceiveAttempt> {
+        if !self.is_fifo() {
+            return None;
+        }
+
+        match &self.receive_attempt {
+            Some(attempt) if attempt.started_at.elapsed() < RECEIVE_ATTEMPT_ID_VALIDITY => {
+                debug!("Retrying receive with attempt id {}", attempt.id);
+            }
+            _ => {
+                self.receive_attempt = Some(ReceiveAttempt {
+                    id: unique_id(),
+                    started_at: Instant::now(),
+                    batch_size,
+                })
+            }
+        }
+
+        self.receive_attempt.as_ref()
+    }
+
+    // ramps up from a single message to `max_messages` over the `warm_up` period, so a restart
+    // into a deep backlog does not hit cold caches and connection pools with full batches
+    fn batch_size(&mut self) -> i64 {
+        let max_messages = self.config.max_messages.clamp(1, 10);
+
+        let warm_up = match self.config.warm_up {
+            Some(warm_up) => warm_up,
+            None => return max_messages,
+        };
+
+        let elapsed = self
+            .first_poll_at
+            .get_or_insert_with(Instant::now)
+            .elapsed();
+
+        if elapsed >= warm_up {
+            return max_messages;
+        }
+
+        let progress = elapsed.as_secs_f64() / warm_up.as_secs_f64();
+        1 + ((max_messages - 1) as f64 * progress) as i64
+    }
+
+    // returns the number of messages received, `None` if the workers were too busy to receive
+    async fn get_and_handle_messages(&mut self) -> Result<Option<usize>, Error> {
+        debug!("get and handle messages called");
+
+        let batch_size = match self.worker_capacity() {
+            Some(0) => {
+                debug!("Workers are busy, not receiving");
+                return Ok(None);
+            }
+            Some(capacity) => self.batch_size().min(capacity as i64),
+            None => self.batch_size(),
+        };
+
+        // a retried attempt has to ask for the same number of messages
+        let (attempt_id, batch_size) = match self.receive_attempt(batch_size) {
+            Some(attempt) => (Some(attempt.id.clone()), attempt.batch_size),
+            None => (None, batch_size),
+        };
+
+        let request = ReceiveMessageRequest {
+            receive_request_attempt_id: attempt_id,
+            max_number_of_messages: Some(batch_size),
+            ..self.receive_request()
+        };
+
+        let started_at = Instant::now();
+        let result = self.client.receive_message(request).await;
+        self.metrics.receive_latency(started_at.elapsed());
+
+        let mut messages = result?.messages.ok_or(Error::UnknownReceiveMessages)?;
+
+        // the receive succeeded, the next one needs a new attempt id
+        self.receive_attempt = None;
+
+        self.sort_by_priority(&mut messages);
+        let received = messages.len();
+        self.metrics.received(received);
+
+        for message in &messages {
+            if let Some(canary_id) = canary::id(message) {
+                if self.pending_canary.as_deref() == Some(canary_id) {
+                    debug!("Canary {} consumed", canary_id);
+                    self.pending_canary = None;
+                }
+            }
+        }
+
+        if self.workers.is_empty() {
+            self.handling().handle_messages(messages).await;
+        } else {
+            self.dispatch(messages);
+        }
+
+        Ok(Some(received))
+    }
+
+    fn check_interval(&self) -> Duration {
+        self.adaptive_interval.unwrap_or(self.config.check_interval)
+    }
+
+    // halves the check interval after a receive that returned messages and doubles it after an
+    // empty one, within `min_check_interval` and `max_check_interval`
+    fn adapt_check_interval(&mut self, received: usize) {
+        let (min, max) = match (
+            self.config.min_check_interval,
+            self.config.max_check_interval,
+        ) {
+            (None, None) => return,
+            (min, max) => (
+                min.unwrap_or(self.config.check_interval),
+                max.unwrap_or(self.config.check_interval),
+            ),
+        };
+
+        let interval = if received > 0 {
+            self.check_interval() / 2
+        } else {
+            self.check_interval().checked_mul(2).unwrap_or(max)
+        };
+
+        self.adaptive_interval = Some(interval.max(min).min(max));
+    }
+
+    // how many more messages the workers can take, `None` without workers
+    fn worker_capacity(&self) -> Option<usize> {
+        if self.workers.is_empty() {
+            return None;
+        }
+
+        let max_in_flight = self
+            .config
+            .max_in_flight
+            .unwrap_or(self.config.max_messages.clamp(1, 10) as usize * self.workers.len());
+
+        let in_flight = self.dispatch_gauge.in_flight.load(Ordering::SeqCst);
+
+        Some(max_in_flight.saturating_sub(in_flight))
+    }
+
+    // passes the messages on to the workers, a batch handler gets the whole receive. Messages of
+    // the same group always go to the same worker, so they are handled in order
+    fn dispatch(&mut self, messages: Vec<Message>) {
+        if messages.is_empty() {
+            return;
+        }
+
+        self.dispatch_gauge
+            .in_flight
+            .fetch_add(messages.len(), Ordering::SeqCst);
+
+        if self.listener.handler.handles_batches() {
+            let index = self.round_robin();
+            let worker = &self.workers[index];
+            send!(worker.handle(messages));
+            return;
+        }
+
+        let mut assigned: Vec<Vec<Message>> = vec![Vec::new(); self.workers.len()];
+
+        for message in messages {
+            let worker = match message_group_id(&message) {
+                Some(group_id) => {
+                    let mut hasher = DefaultHasher::new();
+                    group_id.hash(&mut hasher);
+                    hasher.finish() as usize % self.workers.len()
+                }
+                None => self.round_robin(),
+            };
+
+            assigned[worker].push(message);
+        }
+
+        for (worker, messages) in self.workers.iter().zip(assigned) {
+            if !messages.is_empty() {
+                send!(worker.handle(messages));
+            }
+        }
+    }
+
+    fn round_robin(&mut self) -> usize {
+        let worker = self.next_worker % self.workers.len();
+        self.next_worker = self.next_worker.wrapping_add(1);
+        worker
+    }
+
+    pub(crate) fn handling(&self) -> &Arc<Handling<F>> {
+        self.handling
+            .as_ref()
+            .expect("set when the client is built")
+    }
+
+    fn new_handling(&self) -> Handling<F> {
+        Handling {
+            handler: self.listener.handler.clone(),
+            queue_url: self.listener.queue_url.clone(),
+            config: self.config.clone(),
+            client: Mutex::new(self.client.clone()),
+            credentials_expired: self.credentials_expired.clone(),
+            result_sink: self.result_sink.clone(),
+            on_slow_message: self.on_slow_message.clone(),
+            on_receipt_handle_expired: self.on_receipt_handle_expired.clone(),
+            on_message_acked: self.on_message_acked.clone(),
+            on_error: self.on_error.clone(),
+            fault_injector: self.fault_injector.clone(),
+            extended_payloads: self.extended_payloads.clone(),
+            handler_retry: self.handler_retry.clone(),
+            visibility_policy: self.visibility_policy.clone(),
+            max_receive_count: self.max_receive_count,
+            metrics: self.metrics.clone(),
+            ack_buffer: self
+                .handling
+                .as_ref()
+                .map_or_else(Default::default, |handling| handling.ack_buffer.clone()),
+            own_canaries: self
+                .handling
+                .as_ref()
+                .map_or_else(Default::default, |handling| handling.own_canaries.clone()),
+        }
+    }
+}
+
+// a throttled or unreachable SQS is retried less and less often instead of after every
+// `check_interval`
+fn default_receive_backoff() -> Arc<dyn Fn() -> Box<dyn Backoff> + Send + Sync> {
+    Arc::new(|| {
+        Box::new(ExponentialBackoff::new(
+            Duration::from_secs(1),
+            Duration::from_secs(60),
+        )) as Box<dyn Backoff>
+    })
+}
+
+// flushes buffered acks every interval until the client is dropped, so none waits longer
+fn flush_acks_periodically<F: Handler>(handling: Weak<Handling<F>>, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            match handling.upgrade() {
+                Some(handling) => handling.flush_acks().await,
+                None => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chaos::FaultInjectionBuilder;
+    use crate::emulator::{QueueOptions, RedrivePolicy, SqsEmulator};
+    use crate::metrics::Stats;
+    use crate::sink::{ChannelSink, Outcome};
+    use crate::{testing, Region, SQSListenerClientBuilder};
+    use rusoto_sqs::MessageAttributeValue;
+    use std::collections::HashMap;
+
+    fn client(queue_url: &str) -> SQSListenerClient<impl Handler> {
+        SQSListenerClientBuilder::new(Region::UsEast1)
+            .listener(SQSListener::new(queue_url.to_string(), |_message| {}))
+            .priv_build()
+            .expect("all required fields are set")
+    }
+
+    fn message_with_priority(id: &str, priority: Option<&str>) -> Message {
+        let message_attributes = priority.map(|priority| {
+            let mut message_attributes = HashMap::new();
+            message_attributes.insert(
+                "priority".to_string(),
+                MessageAttributeValue {
+                    data_type: "String".to_string(),
+                    string_value: Some(priority.to_string()),
+                    ..Default::default()
+                },
+            );
+            message_attributes
+        });
+
+        Message {
+            message_id: Some(id.to_string()),
+            message_attributes,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reuses_receive_attempt_until_it_expires() {
+        let mut client = client("https://sqs.us-east-1.amazonaws.com/123456789012/queue.fifo");
+
+        let attempt = client.receive_attempt(1).cloned().unwrap();
+
+        // warm up may have raised the batch size since the failed attempt
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(client.receive_attempt(10), Some(&attempt));
+
+        tokio::time::advance(RECEIVE_ATTEMPT_ID_VALIDITY).await;
+        let renewed = client.receive_attempt(10).unwrap();
+        assert_ne!(renewed.id, attempt.id);
+        assert_eq!(renewed.batch_size, 10);
+    }
+
+    #[test]
+    fn no_receive_attempt_for_standard_queues() {
+        let mut client = client("https://sqs.us-east-1.amazonaws.com/123456789012/queue");
+
+        assert_eq!(client.receive_attempt(10), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ramps_up_batch_size_during_warm_up() {
+        let config = ConfigBuilder::default()
+            .max_messages(10)
+            .warm_up(Duration::from_secs(10))
+            .build();
+
+        let mut client = SQSListenerClientBuilder::new(Region::UsEast1)
+            .listener(SQSListener::new("queue".to_string(), |_message| {}))
+            .config(config)
+            .priv_build()
+            .expect("all required fields are set");
+
+        assert_eq!(client.batch_size(), 1);
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(client.batch_size(), 5);
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(client.batch_size(), 10);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backs_off_after_failed_receive() {
+        #[derive(Clone)]
+        struct Constant(Duration);
+
+        impl Backoff for Constant {
+            fn next_backoff(&mut self) -> Option<Duration> {
+                Some(self.0)
+            }
+        }
+
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = SqsEmulator::new();
+
+        let mut client = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::new(queue_url.to_string(), |_message| {}))
+                .receive_backoff(Constant(Duration::from_secs(10))),
+        );
+
+        // the queue does not exist yet, so the receive fails
+        client.poll().await;
+
+        emulator.create_queue(queue_url, QueueOptions::default());
+        emulator.push_message(queue_url, Message::default());
+
+        client.poll().await;
+        assert_eq!(emulator.queued_messages(queue_url).len(), 1);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        client.poll().await;
+        assert_eq!(emulator.deleted_messages(queue_url).len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backs_off_exponentially_by_default() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = SqsEmulator::new();
+
+        let mut client = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::new(queue_url.to_string(), |_message| {})),
+        );
+
+        // the queue does not exist yet, so both receives fail
+        client.poll().await;
+        tokio::time::advance(Duration::from_secs(1)).await;
+        client.poll().await;
+
+        emulator.create_queue(queue_url, QueueOptions::default());
+        emulator.push_message(queue_url, Message::default());
+
+        // the second failure waits between 1.6 and 2 seconds
+        tokio::time::advance(Duration::from_secs(1)).await;
+        client.poll().await;
+        assert_eq!(emulator.queued_messages(queue_url).len(), 1);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        client.poll().await;
+        assert_eq!(emulator.deleted_messages(queue_url).len(), 1);
+        assert!(client.receive_retry_at.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backs_off_each_listener_on_its_own() {
+        fn ignore(_message: &Message) {}
+
+        let orders = "https://sqs.us-east-1.amazonaws.com/000000000000/orders";
+        let refunds = "https://sqs.us-east-1.amazonaws.com/000000000000/refunds";
+
+        // neither queue exists, so every receive fails
+        let mut client = testing::emulated_client(
+            &SqsEmulator::new(),
+            SQSListenerClientBuilder::default()
+                .listeners(vec![
+                    SQSListener::new(orders.to_string(), ignore),
+                    SQSListener::new(refunds.to_string(), ignore),
+                ])
+                .receive_backoff(
+                    ExponentialBackoff::new(Duration::from_secs(10), Duration::from_secs(60))
+                        .jitter(0.0),
+                ),
+        );
+        let mut refunds_client = client.split_listeners().unwrap().remove(0);
+
+        client.poll().await;
+        refunds_client.poll().await;
+
+        let retry_in = Duration::from_secs(10);
+        assert_eq!(client.receive_retry_at, Some(Instant::now() + retry_in));
+        assert_eq!(
+            refunds_client.receive_retry_at,
+            Some(Instant::now() + retry_in)
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_a_listener_its_own_endpoint() {
+        fn ignore(_message: &Message) {}
+
+        let orders = "https://sqs.us-east-1.amazonaws.com/000000000000/orders";
+        let refunds = "https://sqs.us-east-1.amazonaws.com/000000000000/refunds";
+        let endpoint = "https://vpce-0123456789abcdef0.sqs.us-east-1.vpce.amazonaws.com";
+
+        let listeners = || {
+            vec![
+                SQSListener::new(orders.to_string(), ignore),
+                SQSListener::new(refunds.to_string(), ignore).endpoint(endpoint.to_string()),
+            ]
+        };
+
+        let mut client = testing::emulated_client(
+            &SqsEmulator::new(),
+            SQSListenerClientBuilder::default()
+                .listeners(listeners())
+                .region(Region::UsEast1),
+        );
+        let refunds_client = client.split_listeners().unwrap().remove(0);
+
+        assert!(client.client.sqs_client().is_none());
+        assert!(refunds_client.client.sqs_client().is_some());
+
+        // without a region there is nothing to build the client of the endpoint with
+        let mut client = testing::emulated_client(
+            &SqsEmulator::new(),
+            SQSListenerClientBuilder::default().listeners(listeners()),
+        );
+
+        assert!(matches!(
+            client.split_listeners(),
+            Err(SQSListenerClientBuilderError::ValidationError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn adapts_check_interval_to_traffic() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(queue_url, QueueOptions::default());
+
+        let config = ConfigBuilder::default()
+            .check_interval(Duration::from_secs(5))
+            .min_check_interval(Duration::from_secs(1))
+            .max_check_interval(Duration::from_secs(20))
+            .build();
+
+        let mut client = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::new(queue_url.to_string(), |_message| {}))
+                .config(config),
+        );
+
+        for expected in &[10_000, 20_000, 20_000] {
+            client.poll().await;
+            assert_eq!(client.check_interval(), Duration::from_millis(*expected));
+        }
+
+        for expected in &[10_000, 5_000, 2_500] {
+            emulator.push_message(queue_url, Message::default());
+            client.poll().await;
+            assert_eq!(client.check_interval(), Duration::from_millis(*expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_handled_and_failed_messages() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(queue_url, QueueOptions::default());
+
+        let listener = SQSListener::new_fallible(queue_url.to_string(), |message| async move {
+            match message.body.as_deref() {
+                Some("fail") => Err("handler failed"),
+                _ => Ok(()),
+            }
+        });
+
+        let config = ConfigBuilder::default().max_messages(3).build();
+
+        let mut client = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default()
+                .listener(listener)
+                .config(config),
+        );
+
+        for body in &["ok", "fail", "ok"] {
+            emulator.push_message(
+                queue_url,
+                Message {
+                    body: Some(body.to_string()),
+                    ..Default::default()
+                },
+            );
+        }
+
+        client.poll().await;
+
+        assert_eq!(
+            client.metrics.snapshot(),
+            Stats {
+                received: 3,
+                handled: 2,
+                acked: 2,
+                handler_failures: 1,
+                poll_cycles: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_queue_in_other_partition() {
+        let client = SQSListenerClientBuilder::new(Region::UsGovWest1)
+            .listener(SQSListener::new(
+                "https://sqs.us-east-1.amazonaws.com/123456789012/queue".to_string(),
+                |_message| {},
+            ))
+            .priv_build();
+
+        assert!(matches!(
+            client,
+            Err(SQSListenerClientBuilderError::ValidationError(_))
+        ));
+
+        let client = SQSListenerClientBuilder::new(Region::UsGovWest1)
+            .listener(SQSListener::new(
+                "arn:aws-us-gov:sqs:us-gov-west-1:123456789012:queue".to_string(),
+                |_message| {},
+            ))
+            .priv_build();
+
+        assert!(client.is_ok());
+    }
+
+    #[test]
+    fn exposes_only_sqs_clients_for_raw_operations() {
+        let client = client("https://sqs.us-east-1.amazonaws.com/123456789012/queue");
+        assert!(client.client.sqs_client().is_some());
+
+        let client = testing::emulated_client(
+            &SqsEmulator::new(),
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::new("queue".to_string(), |_message| {})),
+        );
+
+        assert!(client.client.sqs_client().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rebuilds_client_after_persistent_connection_failures() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(queue_url, QueueOptions::default());
+
+        let rebuilds = Arc::new(Mutex::new(Vec::new()));
+        let recorded_rebuilds = rebuilds.clone();
+
+        let config = ConfigBuilder::default()
+            .rebuild_client_after(Duration::from_secs(60))
+            .build();
+
+        let mut client = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::new(queue_url.to_string(), |_message| {}))
+                .config(config)
+                .on_client_rebuilt(move |reason| {
+                    recorded_rebuilds.lock().unwrap().push(reason.clone())
+                }),
+        );
+
+        // every receive fails with a connection error until the client is rebuilt
+        let faults = FaultInjectionBuilder::default()
+            .receive_failure_rate(1.0)
+            .build();
+        let injector = Arc::new(Injector::new(faults));
+        client.client = Arc::new(ChaosTransport::new(Arc::new(emulator.clone()), injector));
+
+        let factory_emulator = emulator.clone();
+        client.client_factory = Some(Arc::new(move || {
+            Arc::new(factory_emulator.clone()) as Arc<dyn Transport>
+        }));
+
+        client.poll().await;
+        assert!(rebuilds.lock().unwrap().is_empty());
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        client.poll().await;
+
+        assert_eq!(
+            *rebuilds.lock().unwrap(),
+            vec![ClientRebuild::ConnectionFailures(Duration::from_secs(60))]
+        );
+
+        emulator.push_message(queue_url, Message::default());
+        client.poll().await;
+        assert_eq!(emulator.deleted_messages(queue_url).len(), 1);
+    }
+
+    #[test]
+    fn jitters_startup_delay() {
+        let config = ConfigBuilder::default()
+            .startup_delay(Duration::from_secs(10))
+            .startup_jitter(Duration::from_secs(5))
+            .build();
+
+        let client = SQSListenerClientBuilder::new(Region::UsEast1)
+            .listener(SQSListener::new("queue".to_string(), |_message| {}))
+            .config(config)
+            .priv_build()
+            .expect("all required fields are set");
+
+        for _ in 0..10 {
+            let delay = client.startup_delay();
+            assert!(delay >= Duration::from_secs(10));
+            assert!(delay <= Duration::from_secs(15));
+        }
+    }
+
+    #[test]
+    fn sorts_messages_by_priority() {
+        let config = ConfigBuilder::default()
+            .priority_attribute("priority".to_string())
+            .priority_values(vec!["high".to_string(), "low".to_string()])
+            .build();
+
+        let client = SQSListenerClientBuilder::new(Region::UsEast1)
+            .listener(SQSListener::new("queue".to_string(), |_message| {}))
+            .config(config)
+            .priv_build()
+            .expect("all required fields are set");
+
+        let mut messages = vec![
+            message_with_priority("1", Some("low")),
+            message_with_priority("2", None),
+            message_with_priority("3", Some("high")),
+            message_with_priority("4", Some("low")),
+            message_with_priority("5", Some("high")),
+        ];
+
+        client.sort_by_priority(&mut messages);
+
+        let ids: Vec<_> = messages
+            .iter()
+            .filter_map(|message| message.message_id.as_deref())
+            .collect();
+
+        assert_eq!(ids, vec!["3", "5", "1", "4", "2"]);
+        assert_eq!(
+            client.receive_request().message_attribute_names,
+            Some(vec![
+                canary::CANARY_ATTRIBUTE.to_string(),
+                canary::EXPIRES_AT_ATTRIBUTE.to_string(),
+                "priority".to_string()
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn derives_slow_message_threshold_from_visibility_timeout() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(
+            queue_url,
+            QueueOptions {
+                visibility_timeout: Duration::from_secs(60),
+                ..Default::default()
+            },
+        );
+
+        let mut client = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::new(queue_url.to_string(), |_message| {})),
+        );
+
+        client.tune_from_queue().await.expect("queue exists");
+
+        assert_eq!(
+            client.config.slow_message_threshold,
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            client.receive_request().attribute_names,
+            Some(vec!["ApproximateReceiveCount".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn records_failures_on_the_last_receive_as_dead_lettered() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let dlq_url = "https://sqs.us-east-1.amazonaws.com/000000000000/dlq";
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(dlq_url, QueueOptions::default());
+        emulator.create_queue(
+            queue_url,
+            QueueOptions {
+                visibility_timeout: Duration::from_secs(0),
+                redrive_policy: Some(RedrivePolicy {
+                    dead_letter_queue_url: dlq_url.to_string(),
+                    max_receive_count: 2,
+                }),
+                ..Default::default()
+            },
+        );
+
+        let listener = SQSListener::new_fallible(queue_url.to_string(), |_message| async {
+            Err::<(), _>("handler failed")
+        });
+
+        let (sink, mut outcomes) = ChannelSink::channel();
+
+        let mut client = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default()
+                .listener(listener)
+                .result_sink(sink),
+        );
+
+        client.tune_from_queue().await.expect("queue exists");
+        emulator.push_message(queue_url, Message::default());
+
+        client.poll().await;
+        client.poll().await;
+
+        let mut recorded = Vec::new();
+        while let Ok((_message, outcome)) = outcomes.try_recv() {
+            recorded.push(outcome);
+        }
+
+        assert!(matches!(
+            recorded.as_slice(),
+            [
+                Outcome::Failure(_),
+                Outcome::Failure(_),
+                Outcome::DeadLettered
+            ]
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_expired_receipt_handles() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(queue_url, QueueOptions::default());
+
+        let expired = Arc::new(Mutex::new(Vec::new()));
+        let reported = expired.clone();
+
+        let client = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::new(queue_url.to_string(), |_message| {}))
+                .on_receipt_handle_expired(move |message| {
+                    reported.lock().unwrap().push(message.message_id.clone())
+                }),
+        );
+
+        let mut message = Message {
+            message_id: Some("id".to_string()),
+            receipt_handle: Some("expired".to_string()),
+            ..Default::default()
+        };
+
+        let result = client.handling().ack(&mut message).await;
+
+        assert!(matches!(result, Err(Error::ReceiptHandleExpired(_))));
+        assert_eq!(*expired.lock().unwrap(), vec![Some("id".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn calls_poll_empty_and_acked_hooks() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(queue_url, QueueOptions::default());
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let empty_events = events.clone();
+        let acked_events = events.clone();
+
+        let mut client = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::new(queue_url.to_string(), |_message| {}))
+                .on_poll_empty(move |queue_url| {
+                    empty_events
+                        .lock()
+                        .unwrap()
+                        .push(format!("empty {}", queue_url))
+                })
+                .on_message_acked(move |message| {
+                    acked_events
+                        .lock()
+                        .unwrap()
+                        .push(format!("acked {}", message.message_id.as_deref().unwrap()))
+                }),
+        );
+
+        client.poll().await;
+
+        emulator.push_message(
+            queue_url,
+            Message {
+                message_id: Some("id".to_string()),
+                ..Default::default()
+            },
+        );
+        client.poll().await;
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![format!("empty {}", queue_url), "acked id".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn survives_panicking_handlers() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(queue_url, QueueOptions::default());
+
+        let listener = SQSListener::new(queue_url.to_string(), |message: &Message| {
+            if message.body.as_deref() == Some("panic") {
+                panic!("handler bug")
+            }
+        });
+
+        let mut client = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default().listener(listener),
+        );
+
+        for body in &["panic", "ok"] {
+            emulator.push_message(
+                queue_url,
+                Message {
+                    body: Some(body.to_string()),
+                    ..Default::default()
+                },
+            );
+            client.poll().await;
+        }
+
+        assert_eq!(emulator.in_flight_messages(queue_url).len(), 1);
+        assert_eq!(emulator.deleted_messages(queue_url).len(), 1);
+        assert_eq!(client.metrics.snapshot().handler_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn stops_fifo_group_at_rejected_message() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue.fifo";
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(queue_url, QueueOptions::default());
+
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let recorded = handled.clone();
+
+        let listener = SQSListener::new(queue_url.to_string(), move |message: &Message| {
+            recorded.lock().unwrap().push(message.body.clone().unwrap())
+        });
+
+        let config = ConfigBuilder::default()
+            .max_messages(3)
+            .verify_checksums(true)
+            .build();
+
+        let mut client = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default()
+                .listener(listener)
+                .config(config),
+        );
+
+        // the first message of group a is corrupted
+        for (body, group_id, md5_of_body) in &[
+            ("a1", "a", Some("0".repeat(32))),
+            ("a2", "a", None),
+            ("b1", "b", None),
+        ] {
+            let mut attributes = HashMap::new();
+            attributes.insert("MessageGroupId".to_string(), group_id.to_string());
+
+            emulator.push_message(
+                queue_url,
+                Message {
+                    body: Some(body.to_string()),
+                    md5_of_body: md5_of_body.clone(),
+                    attributes: Some(attributes),
+                    ..Default::default()
+                },
+            );
+        }
+
+        client.poll().await;
+
+        assert_eq!(*handled.lock().unwrap(), vec!["b1".to_string()]);
+        assert_eq!(emulator.deleted_messages(queue_url).len(), 1);
+        assert_eq!(emulator.in_flight_messages(queue_url).len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tracks_health_of_receives() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = SqsEmulator::new();
+
+        let mut client = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::new(queue_url.to_string(), |_message| {})),
+        );
+
+        // the queue does not exist yet, so the receive fails
+        client.poll().await;
+
+        let health = client.health_gauge.snapshot();
+        assert!(!health.alive);
+        assert_eq!(health.last_successful_poll, None);
+        assert_eq!(health.consecutive_errors, 1);
+
+        emulator.create_queue(queue_url, QueueOptions::default());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        client.poll().await;
+
+        let health = client.health_gauge.snapshot();
+        assert!(health.last_successful_poll.is_some());
+        assert_eq!(health.consecutive_errors, 0);
+    }
+
+    #[tokio::test]
+    async fn reads_queue_depth() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(queue_url, QueueOptions::default());
+
+        for _ in 0..3 {
+            emulator.push_message(queue_url, Message::default());
+        }
+
+        let depths = Arc::new(Mutex::new(Vec::new()));
+        let reported = depths.clone();
+
+        let config = ConfigBuilder::default()
+            .queue_depth_interval(Duration::from_secs(60))
+            .build();
+
+        let mut client = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::new(queue_url.to_string(), |_message| {}))
+                .config(config)
+                .on_queue_depth(move |depth| reported.lock().unwrap().push(depth.clone())),
+        );
+
+        // the depth is read once per interval
+        client.poll().await;
+        client.poll().await;
+
+        assert_eq!(
+            *depths.lock().unwrap(),
+            vec![QueueDepth {
+                queue_url: queue_url.to_string(),
+                visible: 2,
+                ..Default::default()
+            }]
+        );
+        assert_eq!(client.metrics.snapshot().queue_visible, 2);
+    }
+
+    #[tokio::test]
+    async fn reports_receive_errors() {
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let reported = errors.clone();
+
+        // the queue does not exist, so the receive fails
+        let mut client = testing::emulated_client(
+            &SqsEmulator::new(),
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::new("queue".to_string(), |_message| {}))
+                .on_error(move |error| reported.lock().unwrap().push(error.to_string())),
+        );
+
+        client.poll().await;
+
+        assert_eq!(errors.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn updates_config_of_running_listener() {
+        let mut client = client("https://sqs.us-east-1.amazonaws.com/123456789012/queue");
+
+        let config = ConfigBuilder::default()
+            .max_messages(5)
+            .wait_time_seconds(10)
+            .build();
+
+        client.update_config(config).await.unwrap();
+
+        assert_eq!(client.receive_request().max_number_of_messages, Some(5));
+        assert_eq!(client.receive_request().wait_time_seconds, Some(10));
+        assert_eq!(client.handling().config.max_messages, 5);
+    }
+
+    #[test]
+    fn long_polls_for_at_most_20_seconds() {
+        let config = ConfigBuilder::default().wait_time_seconds(30).build();
+
+        let client = SQSListenerClientBuilder::new(Region::UsEast1)
+            .listener(SQSListener::new("queue".to_string(), |_message| {}))
+            .config(config)
+            .priv_build()
+            .expect("all required fields are set");
+
+        assert_eq!(client.receive_request().wait_time_seconds, Some(20));
+    }
+
+    #[test]
+    fn overrides_visibility_timeout_of_receives() {
+        let config = ConfigBuilder::default()
+            .visibility_timeout(Duration::from_secs(180))
+            .build();
+
+        let client = SQSListenerClientBuilder::new(Region::UsEast1)
+            .listener(SQSListener::new("queue".to_string(), |_message| {}))
+            .config(config)
+            .priv_build()
+            .expect("all required fields are set");
+
+        assert_eq!(client.receive_request().visibility_timeout, Some(180));
+    }
+
+    #[test]
+    fn requests_message_attributes() {
+        let config = ConfigBuilder::default()
+            .message_attribute_names(vec!["tenant".to_string(), "priority".to_string()])
+            .priority_attribute("priority".to_string())
+            .build();
+
+        let client = SQSListenerClientBuilder::new(Region::UsEast1)
+            .listener(SQSListener::new("queue".to_string(), |_message| {}))
+            .config(config)
+            .priv_build()
+            .expect("all required fields are set");
+
+        assert_eq!(
+            client.receive_request().message_attribute_names,
+            Some(vec![
+                canary::CANARY_ATTRIBUTE.to_string(),
+                canary::EXPIRES_AT_ATTRIBUTE.to_string(),
+                "tenant".to_string(),
+                "priority".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn requests_configured_attribute_names() {
+        let config = ConfigBuilder::default()
+            .attribute_names(vec![
+                "SentTimestamp".to_string(),
+                "ApproximateReceiveCount".to_string(),
+            ])
+            .build();
+
+        let client = SQSListenerClientBuilder::new(Region::UsEast1)
+            .listener(SQSListener::new("queue".to_string(), |_message| {}))
+            .config(config)
+            .priv_build()
+            .expect("all required fields are set");
+
+        assert_eq!(
+            client.receive_request().attribute_names,
+            Some(vec![
+                "SentTimestamp".to_string(),
+                "ApproximateReceiveCount".to_string()
+            ])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handles_messages_concurrently() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(queue_url, QueueOptions::default());
+        for _ in 0..3 {
+            emulator.push_message(queue_url, Message::default());
+        }
+
+        let listener = SQSListener::new_async(queue_url.to_string(), |_message| async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let config = ConfigBuilder::default()
+            .max_messages(3)
+            .concurrency(3)
+            .build();
+
+        let mut client = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default()
+                .listener(listener)
+                .config(config),
+        );
+
+        let started_at = Instant::now();
+        client.poll().await;
+
+        assert!(started_at.elapsed() < Duration::from_secs(20));
+        assert_eq!(emulator.deleted_messages(queue_url).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn self_test_leaves_canaries_of_other_consumers() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(queue_url, QueueOptions::default());
+
+        let builder = || {
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::new(queue_url.to_string(), |_message| {}))
+                .config(ConfigBuilder::default().max_messages(10).build())
+        };
+
+        let starting = testing::emulated_client(&emulator, builder());
+        let mut running = testing::emulated_client(&emulator, builder());
+
+        // the canary of a consumer that is already running is on the queue
+        running.check_canary(Duration::from_secs(60)).await;
+
+        let result = starting.self_test(Duration::from_secs(5)).await;
+
+        assert!(result.is_ok());
+        assert_eq!(emulator.deleted_messages(queue_url).len(), 1);
+        assert_eq!(emulator.queued_messages(queue_url).len(), 1);
+
+        running.poll().await;
+
+        assert_eq!(running.pending_canary, None);
+        assert_eq!(emulator.deleted_messages(queue_url).len(), 2);
+        assert!(emulator.queued_messages(queue_url).is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_the_sender_deletes_a_periodic_canary() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(queue_url, QueueOptions::default());
+
+        let config = ConfigBuilder::default()
+            .canary_interval(Duration::from_secs(60))
+            .build();
+
+        let mut sender = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::new(queue_url.to_string(), |_message| {}))
+                .config(config),
+        );
+
+        let mut other = testing::emulated_client(
+            &emulator,
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::new(queue_url.to_string(), |_message| {})),
+        );
+
+        // nothing to receive yet, the canary is sent after the receive
+        sender.poll().await;
+        assert!(sender.pending_canary.is_some());
+
+        // the other consumer receives the canary first and leaves it on the queue
+        other.poll().await;
+        assert!(emulator.deleted_messages(queue_url).is_empty());
+        assert_eq!(emulator.queued_messages(queue_url).len(), 1);
+
+        sender.poll().await;
+
+        assert_eq!(sender.pending_canary, None);
+        assert_eq!(emulator.deleted_messages(queue_url).len(), 1);
+    }
 }
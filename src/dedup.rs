@@ -0,0 +1,649 @@
+//! Deduplication of messages across consumers, so a message that is delivered more than once is
+//! only handled once even when the deliveries go to different instances
+//!
+//! Wrap the handler in a [DedupHandler] with a [DedupStore] shared by all consumers. Enable the
+//! `dynamodb` feature for [DynamoDbDedupStore] or the `redis` feature for [RedisDedupStore],
+//! [MemoryDedupStore] only deduplicates within a process
+//!
+//! ```rust,ignore
+//! let store = DynamoDbDedupStore::new(DynamoDbClient::new(Region::UsEast1), "processed-orders");
+//!
+//! let handler = DedupHandler::new(handler, store)
+//!     .lease(Duration::from_secs(60))
+//!     .ttl(Duration::from_secs(24 * 60 * 60));
+//!
+//! let listener = SQSListener::with_handler(queue_url, handler);
+//! ```
+use async_trait::async_trait;
+use rusoto_sqs::Message;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::telemetry::{debug, warn};
+use super::{Error, Handler, HandlerError};
+
+/// State of a deduplication key, returned when claiming it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Claim {
+    /// The key was free and is now claimed by this consumer
+    Claimed,
+
+    /// A message with the key was already handled
+    Done,
+
+    /// Another consumer claimed the key and is still handling its message
+    InProgress,
+}
+
+/// Where the keys of handled messages are kept
+#[async_trait]
+pub trait DedupStore: Send + Sync + 'static {
+    /// Claims `key` for `lease`, unless it is claimed or done. A claim that is neither completed
+    /// nor released expires after the lease, so a consumer that crashed does not block the key
+    async fn claim(&self, key: &str, lease: Duration) -> Result<Claim, Error>;
+
+    /// Marks the claimed `key` as done for `ttl`
+    async fn complete(&self, key: &str, ttl: Duration) -> Result<(), Error>;
+
+    /// Releases the claim on `key`, so the next delivery of its message is handled again. A key
+    /// that is done is kept
+    async fn release(&self, key: &str) -> Result<(), Error>;
+}
+
+/// Handler passing every message to the wrapped handler only the first time its key is seen,
+/// later deliveries are acked without calling it
+///
+/// A message whose key is claimed by another consumer fails, so it is redelivered after its
+/// visibility timeout and skipped once the other consumer is done. When the wrapped handler
+/// fails the key is released and the message is handled again on its next delivery
+pub struct DedupHandler<H> {
+    handler: H,
+    store: Arc<dyn DedupStore>,
+    key: Arc<dyn Fn(&Message) -> Option<String> + Send + Sync>,
+    lease: Duration,
+    ttl: Duration,
+}
+
+impl<H: Handler> DedupHandler<H> {
+    /// Deduplicate `handler` on the message id, with a lease of 30 seconds and a ttl of a day
+    pub fn new(handler: H, store: impl DedupStore) -> Self {
+        Self {
+            handler,
+            store: Arc::new(store),
+            key: Arc::new(|message: &Message| message.message_id.clone()),
+            lease: Duration::from_secs(30),
+            ttl: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+
+    /// Deduplicate on another key than the message id, for example a business id in a message
+    /// attribute when producers may send the same message twice. Messages without a key are
+    /// always handled
+    pub fn key(mut self, key: impl Fn(&Message) -> Option<String> + Send + Sync + 'static) -> Self {
+        self.key = Arc::new(key);
+        self
+    }
+
+    /// How long a claim lasts while the message is handled, set it to the visibility timeout of
+    /// the queue. Defaults to 30 seconds
+    pub fn lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    /// How long the key of a handled message is kept, longer than a message can be redelivered.
+    /// Defaults to a day
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+}
+
+#[async_trait]
+impl<H: Handler> Handler for DedupHandler<H> {
+    async fn handle(&self, message: &Message) -> Result<(), HandlerError> {
+        let key = match (self.key)(message) {
+            Some(key) => key,
+            None => return self.handler.handle(message).await,
+        };
+
+        match self.store.claim(&key, self.lease).await? {
+            Claim::Claimed => {}
+            Claim::Done => {
+                debug!("Skipping message {}, it was already handled", key);
+                return Ok(());
+            }
+            Claim::InProgress => {
+                return Err(format!("message {} is being handled by another consumer", key).into())
+            }
+        }
+
+        match self.handler.handle(message).await {
+            Ok(()) => {
+                // the message was handled, failing it now would only handle it again
+                if let Err(error) = self.store.complete(&key, self.ttl).await {
+                    warn!("Message {} may be handled again: {}", key, error);
+                }
+
+                Ok(())
+            }
+            Err(error) => {
+                if let Err(error) = self.store.release(&key).await {
+                    warn!(
+                        "Message {} is claimed until its lease expires: {}",
+                        key, error
+                    );
+                }
+
+                Err(error)
+            }
+        }
+    }
+
+    async fn after_ack(&self, message: &Message, acked: bool) {
+        self.handler.after_ack(message, acked).await
+    }
+
+    fn keeps_receipt_handles(&self) -> bool {
+        self.handler.keeps_receipt_handles()
+    }
+
+    fn receive_failed(&self, error: Error) {
+        self.handler.receive_failed(error)
+    }
+}
+
+/// Keeps the keys in memory, deduplicating the deliveries to one process only
+#[derive(Default)]
+pub struct MemoryDedupStore {
+    // done or not, and when the key expires
+    keys: Mutex<HashMap<String, (bool, Instant)>>,
+}
+
+impl MemoryDedupStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl DedupStore for MemoryDedupStore {
+    async fn claim(&self, key: &str, lease: Duration) -> Result<Claim, Error> {
+        let mut keys = self.keys.lock().expect("dedup keys poisoned");
+        let now = Instant::now();
+
+        keys.retain(|_key, (_done, expires_at)| *expires_at > now);
+
+        match keys.get(key) {
+            Some((true, _expires_at)) => Ok(Claim::Done),
+            Some((false, _expires_at)) => Ok(Claim::InProgress),
+            None => {
+                keys.insert(key.to_string(), (false, now + lease));
+                Ok(Claim::Claimed)
+            }
+        }
+    }
+
+    async fn complete(&self, key: &str, ttl: Duration) -> Result<(), Error> {
+        let mut keys = self.keys.lock().expect("dedup keys poisoned");
+        keys.insert(key.to_string(), (true, Instant::now() + ttl));
+        Ok(())
+    }
+
+    async fn release(&self, key: &str) -> Result<(), Error> {
+        let mut keys = self.keys.lock().expect("dedup keys poisoned");
+
+        if matches!(keys.get(key), Some((false, _))) {
+            keys.remove(key);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S: DedupStore> DedupStore for Arc<S> {
+    async fn claim(&self, key: &str, lease: Duration) -> Result<Claim, Error> {
+        (**self).claim(key, lease).await
+    }
+
+    async fn complete(&self, key: &str, ttl: Duration) -> Result<(), Error> {
+        (**self).complete(key, ttl).await
+    }
+
+    async fn release(&self, key: &str) -> Result<(), Error> {
+        (**self).release(key).await
+    }
+}
+
+// states of a key in the shared stores
+#[cfg(any(feature = "dynamodb", feature = "redis"))]
+const IN_PROGRESS: &str = "in_progress";
+#[cfg(any(feature = "dynamodb", feature = "redis"))]
+const DONE: &str = "done";
+
+#[cfg(any(feature = "dynamodb", feature = "redis"))]
+fn dedup_error(
+    key: &str,
+    operation: &'static str,
+    source: impl std::error::Error + Send + Sync + 'static,
+) -> Error {
+    Error::DedupStore {
+        operation,
+        key: key.to_string(),
+        source: Box::new(source),
+    }
+}
+
+#[cfg(feature = "dynamodb")]
+pub use self::dynamodb::DynamoDbDedupStore;
+
+#[cfg(feature = "dynamodb")]
+mod dynamodb {
+    use async_trait::async_trait;
+    use rusoto_core::RusotoError;
+    use rusoto_dynamodb::{
+        AttributeValue, DeleteItemError, DeleteItemInput, DynamoDb, DynamoDbClient, GetItemInput,
+        PutItemError, PutItemInput, UpdateItemInput,
+    };
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::{dedup_error, Claim, DedupStore, DONE, IN_PROGRESS};
+    use crate::Error;
+
+    /// Keeps the keys in a DynamoDB table, claimed with conditional writes so only one consumer
+    /// wins a key
+    ///
+    /// The table has a string partition key `id`, every item has a `state` and an `expires_at` in
+    /// seconds since the epoch. Enable time to live on `expires_at` to remove expired keys, they
+    /// are claimed again before DynamoDB gets to them
+    #[derive(Clone)]
+    pub struct DynamoDbDedupStore {
+        client: DynamoDbClient,
+        table_name: String,
+    }
+
+    impl DynamoDbDedupStore {
+        pub fn new(client: DynamoDbClient, table_name: &str) -> Self {
+            Self {
+                client,
+                table_name: table_name.to_string(),
+            }
+        }
+
+        fn key(&self, key: &str) -> HashMap<String, AttributeValue> {
+            let mut item = HashMap::new();
+            item.insert("id".to_string(), string(key));
+            item
+        }
+    }
+
+    fn string(value: &str) -> AttributeValue {
+        AttributeValue {
+            s: Some(value.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn number(value: u64) -> AttributeValue {
+        AttributeValue {
+            n: Some(value.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn seconds_since_epoch() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default()
+    }
+
+    fn names(names: &[(&str, &str)]) -> Option<HashMap<String, String>> {
+        Some(
+            names
+                .iter()
+                .map(|(name, attribute)| (name.to_string(), attribute.to_string()))
+                .collect(),
+        )
+    }
+
+    fn values(values: Vec<(&str, AttributeValue)>) -> Option<HashMap<String, AttributeValue>> {
+        Some(
+            values
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        )
+    }
+
+    #[async_trait]
+    impl DedupStore for DynamoDbDedupStore {
+        async fn claim(&self, key: &str, lease: Duration) -> Result<Claim, Error> {
+            let now = seconds_since_epoch();
+
+            let mut item = self.key(key);
+            item.insert("state".to_string(), string(IN_PROGRESS));
+            item.insert("expires_at".to_string(), number(now + lease.as_secs()));
+
+            // items are only deleted some time after they expire
+            let claimed = self
+                .client
+                .put_item(PutItemInput {
+                    table_name: self.table_name.clone(),
+                    item,
+                    condition_expression: Some(
+                        "attribute_not_exists(#id) OR #expires_at < :now".to_string(),
+                    ),
+                    expression_attribute_names: names(&[
+                        ("#id", "id"),
+                        ("#expires_at", "expires_at"),
+                    ]),
+                    expression_attribute_values: values(vec![(":now", number(now))]),
+                    ..Default::default()
+                })
+                .await;
+
+            match claimed {
+                Ok(_output) => return Ok(Claim::Claimed),
+                Err(RusotoError::Service(PutItemError::ConditionalCheckFailed(_))) => {}
+                Err(error) => return Err(dedup_error(key, "claim", error)),
+            }
+
+            let output = self
+                .client
+                .get_item(GetItemInput {
+                    table_name: self.table_name.clone(),
+                    key: self.key(key),
+                    consistent_read: Some(true),
+                    ..Default::default()
+                })
+                .await
+                .map_err(|error| dedup_error(key, "claim", error))?;
+
+            let state = output
+                .item
+                .as_ref()
+                .and_then(|item| item.get("state"))
+                .and_then(|state| state.s.as_deref());
+
+            // an item deleted in between is claimed on the next delivery
+            if state == Some(DONE) {
+                Ok(Claim::Done)
+            } else {
+                Ok(Claim::InProgress)
+            }
+        }
+
+        async fn complete(&self, key: &str, ttl: Duration) -> Result<(), Error> {
+            self.client
+                .update_item(UpdateItemInput {
+                    table_name: self.table_name.clone(),
+                    key: self.key(key),
+                    update_expression: Some(
+                        "SET #state = :done, #expires_at = :expires_at".to_string(),
+                    ),
+                    expression_attribute_names: names(&[
+                        ("#state", "state"),
+                        ("#expires_at", "expires_at"),
+                    ]),
+                    expression_attribute_values: values(vec![
+                        (":done", string(DONE)),
+                        (":expires_at", number(seconds_since_epoch() + ttl.as_secs())),
+                    ]),
+                    ..Default::default()
+                })
+                .await
+                .map_err(|error| dedup_error(key, "complete", error))?;
+
+            Ok(())
+        }
+
+        async fn release(&self, key: &str) -> Result<(), Error> {
+            let released = self
+                .client
+                .delete_item(DeleteItemInput {
+                    table_name: self.table_name.clone(),
+                    key: self.key(key),
+                    condition_expression: Some("#state = :in_progress".to_string()),
+                    expression_attribute_names: names(&[("#state", "state")]),
+                    expression_attribute_values: values(vec![(
+                        ":in_progress",
+                        string(IN_PROGRESS),
+                    )]),
+                    ..Default::default()
+                })
+                .await;
+
+            match released {
+                Ok(_output) => Ok(()),
+                // done in the meantime, it is kept
+                Err(RusotoError::Service(DeleteItemError::ConditionalCheckFailed(_))) => Ok(()),
+                Err(error) => Err(dedup_error(key, "release", error)),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+pub use self::redis_store::RedisDedupStore;
+
+#[cfg(feature = "redis")]
+mod redis_store {
+    use async_trait::async_trait;
+    use redis::aio::MultiplexedConnection;
+    use redis::Script;
+    use std::time::Duration;
+
+    use super::{dedup_error, Claim, DedupStore, DONE, IN_PROGRESS};
+    use crate::Error;
+
+    // deletes the key only while it is claimed, a key that is done is kept
+    const RELEASE: &str = r#"
+        if redis.call("GET", KEYS[1]) == ARGV[1] then
+            return redis.call("DEL", KEYS[1])
+        end
+        return 0
+    "#;
+
+    /// Keeps the keys in Redis, claimed with `SET NX` so only one consumer wins a key and
+    /// expired with `PX`
+    ///
+    /// ```rust,ignore
+    /// let client = redis::Client::open("redis://127.0.0.1/")?;
+    /// let store = RedisDedupStore::new(client.get_multiplexed_tokio_connection().await?);
+    /// ```
+    #[derive(Clone)]
+    pub struct RedisDedupStore {
+        connection: MultiplexedConnection,
+        key_prefix: String,
+    }
+
+    impl RedisDedupStore {
+        pub fn new(connection: MultiplexedConnection) -> Self {
+            Self {
+                connection,
+                key_prefix: "sqs-dedup:".to_string(),
+            }
+        }
+
+        /// Prefix of the Redis keys, to share a database with other data. Defaults to
+        /// `sqs-dedup:`
+        pub fn key_prefix(mut self, key_prefix: &str) -> Self {
+            self.key_prefix = key_prefix.to_string();
+            self
+        }
+
+        fn key(&self, key: &str) -> String {
+            format!("{}{}", self.key_prefix, key)
+        }
+    }
+
+    // redis rejects an expiry of 0
+    fn millis(duration: Duration) -> u64 {
+        (duration.as_millis() as u64).max(1)
+    }
+
+    #[async_trait]
+    impl DedupStore for RedisDedupStore {
+        async fn claim(&self, key: &str, lease: Duration) -> Result<Claim, Error> {
+            let mut connection = self.connection.clone();
+
+            let claimed: Option<String> = redis::cmd("SET")
+                .arg(self.key(key))
+                .arg(IN_PROGRESS)
+                .arg("NX")
+                .arg("PX")
+                .arg(millis(lease))
+                .query_async(&mut connection)
+                .await
+                .map_err(|error| dedup_error(key, "claim", error))?;
+
+            if claimed.is_some() {
+                return Ok(Claim::Claimed);
+            }
+
+            let state: Option<String> = redis::cmd("GET")
+                .arg(self.key(key))
+                .query_async(&mut connection)
+                .await
+                .map_err(|error| dedup_error(key, "claim", error))?;
+
+            // a key that expired in between is claimed on the next delivery
+            if state.as_deref() == Some(DONE) {
+                Ok(Claim::Done)
+            } else {
+                Ok(Claim::InProgress)
+            }
+        }
+
+        async fn complete(&self, key: &str, ttl: Duration) -> Result<(), Error> {
+            let mut connection = self.connection.clone();
+
+            redis::cmd("SET")
+                .arg(self.key(key))
+                .arg(DONE)
+                .arg("PX")
+                .arg(millis(ttl))
+                .query_async::<_, ()>(&mut connection)
+                .await
+                .map_err(|error| dedup_error(key, "complete", error))
+        }
+
+        async fn release(&self, key: &str) -> Result<(), Error> {
+            let mut connection = self.connection.clone();
+
+            Script::new(RELEASE)
+                .key(self.key(key))
+                .arg(IN_PROGRESS)
+                .invoke_async::<_, i64>(&mut connection)
+                .await
+                .map_err(|error| dedup_error(key, "release", error))?;
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::FallibleHandler;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn message(message_id: &str) -> Message {
+        Message {
+            message_id: Some(message_id.to_string()),
+            body: Some("hello".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn handles_a_message_once_across_consumers() {
+        let store = Arc::new(MemoryDedupStore::new());
+        let handled = Arc::new(Mutex::new(Vec::new()));
+
+        let consumer = |name: &'static str| {
+            let handled = handled.clone();
+            DedupHandler::new(
+                move |message: &Message| {
+                    handled
+                        .lock()
+                        .unwrap()
+                        .push((name, message.message_id.clone().unwrap_or_default()))
+                },
+                store.clone(),
+            )
+        };
+
+        let first = consumer("first");
+        let second = consumer("second");
+
+        first.handle(&message("1")).await.unwrap();
+        second.handle(&message("1")).await.unwrap();
+        second.handle(&message("2")).await.unwrap();
+
+        assert_eq!(
+            *handled.lock().unwrap(),
+            vec![("first", "1".to_string()), ("second", "2".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_messages_claimed_by_another_consumer() {
+        let store = Arc::new(MemoryDedupStore::new());
+        assert_eq!(
+            store.claim("1", Duration::from_secs(30)).await.unwrap(),
+            Claim::Claimed
+        );
+
+        let handler = DedupHandler::new(|_message: &Message| {}, store.clone());
+        assert!(handler.handle(&message("1")).await.is_err());
+
+        // the other consumer gave up, so the next delivery is handled
+        store.release("1").await.unwrap();
+        handler.handle(&message("1")).await.unwrap();
+        assert_eq!(
+            store.claim("1", Duration::from_secs(30)).await.unwrap(),
+            Claim::Done
+        );
+
+        // an expired lease is claimed again
+        store.claim("2", Duration::from_secs(0)).await.unwrap();
+        assert_eq!(
+            store.claim("2", Duration::from_secs(30)).await.unwrap(),
+            Claim::Claimed
+        );
+    }
+
+    #[tokio::test]
+    async fn releases_the_key_when_the_handler_fails() {
+        let store = Arc::new(MemoryDedupStore::new());
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let attempted = attempts.clone();
+
+        let handler = DedupHandler::new(
+            FallibleHandler(move |_message: Message| {
+                let attempt = attempted.fetch_add(1, Ordering::SeqCst);
+
+                async move {
+                    if attempt == 0 {
+                        Err(HandlerError::from("failed"))
+                    } else {
+                        Ok(())
+                    }
+                }
+            }),
+            store,
+        );
+
+        assert!(handler.handle(&message("1")).await.is_err());
+        handler.handle(&message("1")).await.unwrap();
+        handler.handle(&message("1")).await.unwrap();
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,704 @@
+//! A small in process SQS emulator, to run listeners in integration tests without LocalStack or
+//! docker
+//!
+//! Supports visibility timeouts, delayed messages and dead-letter queue redrive. Timing uses
+//! tokio's clock, so tests can skip ahead with `tokio::time::pause()` and `advance()`
+//!
+//! Enable with the `emulator` feature
+//!
+//! ```rust,ignore
+//! let emulator = SqsEmulator::new();
+//! let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/orders";
+//! emulator.create_queue(queue_url, QueueOptions::default());
+//!
+//! let client = SQSListenerClientBuilder::new_with_emulator(emulator.clone())
+//!     .listener(SQSListener::new(queue_url.to_string(), |message| {
+//!         println!("Message received {:#?}", message)
+//!     }))
+//!     .build()?;
+//! ```
+use async_trait::async_trait;
+use rusoto_core::RusotoError;
+use rusoto_sqs::{
+    BatchResultErrorEntry, ChangeMessageVisibilityError, ChangeMessageVisibilityRequest,
+    DeleteMessageBatchError, DeleteMessageBatchRequest, DeleteMessageBatchResult,
+    DeleteMessageBatchResultEntry, DeleteMessageError, DeleteMessageRequest,
+    GetQueueAttributesError, GetQueueAttributesRequest, GetQueueAttributesResult, Message,
+    ReceiveMessageError, ReceiveMessageRequest, ReceiveMessageResult, SendMessageBatchError,
+    SendMessageBatchRequest, SendMessageBatchResult, SendMessageBatchResultEntry, SendMessageError,
+    SendMessageRequest, SendMessageResult,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+use super::checksum;
+use super::transport::Transport;
+use super::unique_id;
+
+/// Settings for an emulated queue
+#[derive(Debug, Clone)]
+pub struct QueueOptions {
+    /// How long a received message stays invisible to other receives, defaults to 30 seconds
+    pub visibility_timeout: Duration,
+
+    /// How long a new message stays invisible before it can be received, defaults to 0
+    pub delay: Duration,
+
+    /// Move messages to a dead-letter queue once they have been received too often
+    pub redrive_policy: Option<RedrivePolicy>,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        Self {
+            visibility_timeout: Duration::from_secs(30),
+            delay: Duration::from_secs(0),
+            redrive_policy: None,
+        }
+    }
+}
+
+/// Where and when messages are moved to a dead-letter queue
+#[derive(Debug, Clone)]
+pub struct RedrivePolicy {
+    /// Url of the dead-letter queue, has to be created on the same emulator
+    pub dead_letter_queue_url: String,
+
+    /// How often a message can be received before it is moved to the dead-letter queue
+    pub max_receive_count: u32,
+}
+
+/// In process SQS emulator, clones share the same queues
+#[derive(Debug, Clone, Default)]
+pub struct SqsEmulator {
+    queues: Arc<Mutex<HashMap<String, Queue>>>,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    options: QueueOptions,
+    messages: Vec<StoredMessage>,
+    deleted: Vec<Message>,
+    // last sequence number of a FIFO queue
+    sequence_number: u64,
+}
+
+#[derive(Debug)]
+struct StoredMessage {
+    message: Message,
+    sent_at: u128,
+    visible_at: Instant,
+    receive_count: u32,
+}
+
+impl SqsEmulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a queue, the listener should use the same `queue_url`
+    pub fn create_queue(&self, queue_url: &str, options: QueueOptions) {
+        self.queues().insert(
+            queue_url.to_string(),
+            Queue {
+                options,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Add a message to a queue, a message id is generated if missing. Returns the message id
+    ///
+    /// Panics if the queue does not exist
+    pub fn push_message(&self, queue_url: &str, mut message: Message) -> String {
+        let message_id = message.message_id.get_or_insert_with(unique_id).to_string();
+
+        message.receipt_handle = None;
+
+        let mut queues = self.queues();
+        let queue = queues
+            .get_mut(queue_url)
+            .unwrap_or_else(|| panic!("queue {} does not exist", queue_url));
+
+        let delay = queue.options.delay;
+        queue.push(message, delay);
+
+        message_id
+    }
+
+    /// Messages waiting to be received, including delayed messages
+    pub fn queued_messages(&self, queue_url: &str) -> Vec<Message> {
+        self.messages(queue_url, |stored, now| {
+            stored.visible_at <= now || stored.receive_count == 0
+        })
+    }
+
+    /// Messages that have been received and are still invisible
+    pub fn in_flight_messages(&self, queue_url: &str) -> Vec<Message> {
+        self.messages(queue_url, |stored, now| {
+            stored.visible_at > now && stored.receive_count > 0
+        })
+    }
+
+    /// Messages that have been deleted from the queue
+    pub fn deleted_messages(&self, queue_url: &str) -> Vec<Message> {
+        self.queues()
+            .get(queue_url)
+            .map(|queue| queue.deleted.clone())
+            .unwrap_or_default()
+    }
+
+    fn messages(
+        &self,
+        queue_url: &str,
+        filter: impl Fn(&StoredMessage, Instant) -> bool,
+    ) -> Vec<Message> {
+        let now = Instant::now();
+
+        self.queues()
+            .get(queue_url)
+            .map(|queue| {
+                queue
+                    .messages
+                    .iter()
+                    .filter(|stored| filter(stored, now))
+                    .map(|stored| stored.message.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn queues(&self) -> MutexGuard<'_, HashMap<String, Queue>> {
+        self.queues.lock().expect("emulator lock poisoned")
+    }
+
+    fn receive(&self, input: &ReceiveMessageRequest) -> Option<Vec<Message>> {
+        let mut queues = self.queues();
+        let queue = queues.get_mut(&input.queue_url)?;
+
+        let now = Instant::now();
+        let max_messages = input.max_number_of_messages.unwrap_or(1).clamp(1, 10) as usize;
+        let visibility_timeout = input
+            .visibility_timeout
+            .map(|seconds| Duration::from_secs(seconds.max(0) as u64))
+            .unwrap_or(queue.options.visibility_timeout);
+
+        let redrive_policy = queue.options.redrive_policy.clone();
+        let mut received = Vec::new();
+        let mut dead_lettered = Vec::new();
+        let mut index = 0;
+
+        while index < queue.messages.len() && received.len() < max_messages {
+            if queue.messages[index].visible_at > now {
+                index += 1;
+                continue;
+            }
+
+            if let Some(redrive_policy) = &redrive_policy {
+                if queue.messages[index].receive_count >= redrive_policy.max_receive_count {
+                    dead_lettered.push(queue.messages.remove(index));
+                    continue;
+                }
+            }
+
+            let stored = &mut queue.messages[index];
+            stored.receive_count += 1;
+            stored.visible_at = now + visibility_timeout;
+            stored.message.receipt_handle = Some(unique_id());
+
+            received.push(stored.received(input));
+            index += 1;
+        }
+
+        if let Some(redrive_policy) = redrive_policy {
+            if let Some(dead_letter_queue) = queues.get_mut(&redrive_policy.dead_letter_queue_url) {
+                for stored in dead_lettered {
+                    dead_letter_queue.push(stored.message, Duration::from_secs(0));
+                }
+            }
+        }
+
+        Some(received)
+    }
+
+    fn delete(&self, input: &DeleteMessageRequest) -> bool {
+        let mut queues = self.queues();
+
+        let queue = match queues.get_mut(&input.queue_url) {
+            Some(queue) => queue,
+            None => return false,
+        };
+
+        let position = queue.messages.iter().position(|stored| {
+            stored.message.receipt_handle.as_ref() == Some(&input.receipt_handle)
+        });
+
+        match position {
+            Some(position) => {
+                let stored = queue.messages.remove(position);
+                queue.deleted.push(stored.message);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn change_visibility(&self, input: &ChangeMessageVisibilityRequest) -> bool {
+        let mut queues = self.queues();
+
+        let queue = match queues.get_mut(&input.queue_url) {
+            Some(queue) => queue,
+            None => return false,
+        };
+
+        let stored = queue
+            .messages
+            .iter_mut()
+            .find(|stored| stored.message.receipt_handle.as_ref() == Some(&input.receipt_handle));
+
+        match stored {
+            Some(stored) => {
+                let visibility_timeout =
+                    Duration::from_secs(input.visibility_timeout.max(0) as u64);
+                stored.visible_at = Instant::now() + visibility_timeout;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn attributes(&self, queue_url: &str) -> Option<HashMap<String, String>> {
+        let queues = self.queues();
+        let queue = queues.get(queue_url)?;
+        let now = Instant::now();
+
+        let count = |filter: &dyn Fn(&StoredMessage) -> bool| {
+            queue
+                .messages
+                .iter()
+                .filter(|stored| filter(stored))
+                .count()
+                .to_string()
+        };
+
+        let mut attributes = HashMap::new();
+
+        attributes.insert(
+            "ApproximateNumberOfMessages".to_string(),
+            count(&|stored| stored.visible_at <= now),
+        );
+
+        attributes.insert(
+            "ApproximateNumberOfMessagesNotVisible".to_string(),
+            count(&|stored| stored.visible_at > now && stored.receive_count > 0),
+        );
+
+        attributes.insert(
+            "ApproximateNumberOfMessagesDelayed".to_string(),
+            count(&|stored| stored.visible_at > now && stored.receive_count == 0),
+        );
+
+        attributes.insert(
+            "VisibilityTimeout".to_string(),
+            queue.options.visibility_timeout.as_secs().to_string(),
+        );
+
+        attributes.insert(
+            "DelaySeconds".to_string(),
+            queue.options.delay.as_secs().to_string(),
+        );
+
+        if let Some(redrive_policy) = &queue.options.redrive_policy {
+            let redrive_policy = serde_json::json!({
+                "deadLetterTargetArn": queue_arn(&redrive_policy.dead_letter_queue_url),
+                "maxReceiveCount": redrive_policy.max_receive_count,
+            });
+
+            attributes.insert("RedrivePolicy".to_string(), redrive_policy.to_string());
+        }
+
+        Some(attributes)
+    }
+}
+
+impl Queue {
+    fn push(&mut self, message: Message, delay: Duration) {
+        let sent_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis())
+            .unwrap_or_default();
+
+        self.messages.push(StoredMessage {
+            message,
+            sent_at,
+            visible_at: Instant::now() + delay,
+            receive_count: 0,
+        });
+    }
+}
+
+impl StoredMessage {
+    // the message as returned by a receive, with only the requested attributes
+    fn received(&self, input: &ReceiveMessageRequest) -> Message {
+        let requested = |names: &Option<Vec<String>>, name: &str| {
+            names.iter().flatten().any(|requested| {
+                requested == "All"
+                    || requested == name
+                    || (requested.ends_with(".*")
+                        && name.starts_with(requested.trim_end_matches('*')))
+                    || requested == ".*"
+            })
+        };
+
+        // attributes the message was pushed with, like a `MessageGroupId`
+        let mut attributes: HashMap<String, String> = self
+            .message
+            .attributes
+            .iter()
+            .flatten()
+            .filter(|(name, _value)| requested(&input.attribute_names, name))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+
+        if requested(&input.attribute_names, "ApproximateReceiveCount") {
+            attributes.insert(
+                "ApproximateReceiveCount".to_string(),
+                self.receive_count.to_string(),
+            );
+        }
+
+        if requested(&input.attribute_names, "SentTimestamp") {
+            attributes.insert("SentTimestamp".to_string(), self.sent_at.to_string());
+        }
+
+        let message_attributes = self.message.message_attributes.as_ref().map(|all| {
+            all.iter()
+                .filter(|(name, _value)| requested(&input.message_attribute_names, name))
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect::<HashMap<_, _>>()
+        });
+
+        Message {
+            attributes: Some(attributes).filter(|attributes| !attributes.is_empty()),
+            message_attributes: message_attributes.filter(|attributes| !attributes.is_empty()),
+            ..self.message.clone()
+        }
+    }
+}
+
+// `arn:aws:sqs:region:account_id:queue_name` for urls like
+// `https://sqs.region.amazonaws.com/account_id/queue_name`
+fn queue_arn(queue_url: &str) -> String {
+    let mut parts = queue_url.trim_start_matches("https://").split('/');
+    let region = parts
+        .next()
+        .and_then(|host| host.split('.').nth(1))
+        .unwrap_or_default();
+    let account_id = parts.next().unwrap_or_default();
+    let queue_name = parts.next().unwrap_or_default();
+
+    format!("arn:aws:sqs:{}:{}:{}", region, account_id, queue_name)
+}
+
+fn non_existent_queue<E>(queue_url: &str) -> RusotoError<E> {
+    RusotoError::Validation(format!(
+        "AWS.SimpleQueueService.NonExistentQueue: queue {} does not exist",
+        queue_url
+    ))
+}
+
+#[async_trait]
+impl Transport for SqsEmulator {
+    async fn receive_message(
+        &self,
+        input: ReceiveMessageRequest,
+    ) -> Result<ReceiveMessageResult, RusotoError<ReceiveMessageError>> {
+        let messages = self
+            .receive(&input)
+            .ok_or_else(|| non_existent_queue::<ReceiveMessageError>(&input.queue_url))?;
+
+        Ok(ReceiveMessageResult {
+            messages: Some(messages),
+        })
+    }
+
+    async fn delete_message(
+        &self,
+        input: DeleteMessageRequest,
+    ) -> Result<(), RusotoError<DeleteMessageError>> {
+        if self.delete(&input) {
+            return Ok(());
+        }
+
+        Err(RusotoError::Service(
+            DeleteMessageError::ReceiptHandleIsInvalid(input.receipt_handle),
+        ))
+    }
+
+    async fn delete_message_batch(
+        &self,
+        input: DeleteMessageBatchRequest,
+    ) -> Result<DeleteMessageBatchResult, RusotoError<DeleteMessageBatchError>> {
+        if !self.queues().contains_key(&input.queue_url) {
+            return Err(non_existent_queue(&input.queue_url));
+        }
+
+        let mut result = DeleteMessageBatchResult::default();
+
+        for entry in input.entries {
+            let deleted = self.delete(&DeleteMessageRequest {
+                queue_url: input.queue_url.clone(),
+                receipt_handle: entry.receipt_handle,
+            });
+
+            if deleted {
+                result
+                    .successful
+                    .push(DeleteMessageBatchResultEntry { id: entry.id });
+            } else {
+                result.failed.push(BatchResultErrorEntry {
+                    code: "ReceiptHandleIsInvalid".to_string(),
+                    id: entry.id,
+                    message: Some("The receipt handle is not valid".to_string()),
+                    sender_fault: true,
+                });
+            }
+        }
+
+        Ok(result)
+    }
+
+    async fn change_message_visibility(
+        &self,
+        input: ChangeMessageVisibilityRequest,
+    ) -> Result<(), RusotoError<ChangeMessageVisibilityError>> {
+        if self.change_visibility(&input) {
+            return Ok(());
+        }
+
+        Err(RusotoError::Service(
+            ChangeMessageVisibilityError::ReceiptHandleIsInvalid(input.receipt_handle),
+        ))
+    }
+
+    async fn send_message(
+        &self,
+        input: SendMessageRequest,
+    ) -> Result<SendMessageResult, RusotoError<SendMessageError>> {
+        let message_id = unique_id();
+        let mut queues = self.queues();
+
+        let queue = queues
+            .get_mut(&input.queue_url)
+            .ok_or_else(|| non_existent_queue::<SendMessageError>(&input.queue_url))?;
+
+        let delay = input
+            .delay_seconds
+            .map(|seconds| Duration::from_secs(seconds.max(0) as u64))
+            .unwrap_or(queue.options.delay);
+
+        let md5_of_body = checksum::body_md5(&input.message_body);
+        let md5_of_message_attributes = input
+            .message_attributes
+            .as_ref()
+            .filter(|attributes| !attributes.is_empty())
+            .map(checksum::attributes_md5);
+
+        let message = Message {
+            body: Some(input.message_body),
+            md5_of_body: Some(md5_of_body.clone()),
+            message_attributes: input.message_attributes,
+            md5_of_message_attributes: md5_of_message_attributes.clone(),
+            message_id: Some(message_id.clone()),
+            ..Default::default()
+        };
+
+        queue.push(message, delay);
+
+        let sequence_number = if input.queue_url.ends_with(".fifo") {
+            queue.sequence_number += 1;
+            Some(queue.sequence_number.to_string())
+        } else {
+            None
+        };
+
+        Ok(SendMessageResult {
+            message_id: Some(message_id),
+            md5_of_message_body: Some(md5_of_body),
+            md5_of_message_attributes,
+            sequence_number,
+            ..Default::default()
+        })
+    }
+
+    async fn send_message_batch(
+        &self,
+        input: SendMessageBatchRequest,
+    ) -> Result<SendMessageBatchResult, RusotoError<SendMessageBatchError>> {
+        if !self.queues().contains_key(&input.queue_url) {
+            return Err(non_existent_queue::<SendMessageBatchError>(
+                &input.queue_url,
+            ));
+        }
+
+        let mut successful = Vec::with_capacity(input.entries.len());
+
+        for entry in input.entries {
+            let request = SendMessageRequest {
+                queue_url: input.queue_url.clone(),
+                message_body: entry.message_body,
+                message_attributes: entry.message_attributes,
+                delay_seconds: entry.delay_seconds,
+                ..Default::default()
+            };
+
+            let sent = Transport::send_message(self, request)
+                .await
+                .map_err(|_error| non_existent_queue::<SendMessageBatchError>(&input.queue_url))?;
+
+            successful.push(SendMessageBatchResultEntry {
+                id: entry.id,
+                message_id: sent.message_id.unwrap_or_default(),
+                md5_of_message_body: sent.md5_of_message_body.unwrap_or_default(),
+                md5_of_message_attributes: sent.md5_of_message_attributes,
+                sequence_number: sent.sequence_number,
+                ..Default::default()
+            });
+        }
+
+        Ok(SendMessageBatchResult {
+            successful,
+            failed: Vec::new(),
+        })
+    }
+
+    async fn get_queue_attributes(
+        &self,
+        input: GetQueueAttributesRequest,
+    ) -> Result<GetQueueAttributesResult, RusotoError<GetQueueAttributesError>> {
+        let attributes = self
+            .attributes(&input.queue_url)
+            .ok_or_else(|| non_existent_queue::<GetQueueAttributesError>(&input.queue_url))?;
+
+        Ok(GetQueueAttributesResult {
+            attributes: Some(attributes),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+    const DLQ_URL: &str = "https://sqs.us-east-1.amazonaws.com/000000000000/dlq";
+
+    fn receive_request(queue_url: &str) -> ReceiveMessageRequest {
+        ReceiveMessageRequest {
+            queue_url: queue_url.to_string(),
+            max_number_of_messages: Some(10),
+            attribute_names: Some(vec!["ApproximateReceiveCount".to_string()]),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hides_received_messages_until_visibility_timeout() {
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(QUEUE_URL, QueueOptions::default());
+        emulator.push_message(QUEUE_URL, Message::default());
+
+        let received = emulator.receive(&receive_request(QUEUE_URL)).unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(emulator.in_flight_messages(QUEUE_URL).len(), 1);
+        assert!(emulator
+            .receive(&receive_request(QUEUE_URL))
+            .unwrap()
+            .is_empty());
+
+        tokio::time::advance(Duration::from_secs(31)).await;
+
+        let received = emulator.receive(&receive_request(QUEUE_URL)).unwrap();
+        let receive_count =
+            received[0].attributes.as_ref().unwrap()["ApproximateReceiveCount"].clone();
+        assert_eq!(receive_count, "2");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delays_new_messages() {
+        let emulator = SqsEmulator::new();
+        let options = QueueOptions {
+            delay: Duration::from_secs(10),
+            ..Default::default()
+        };
+
+        emulator.create_queue(QUEUE_URL, options);
+        emulator.push_message(QUEUE_URL, Message::default());
+
+        assert!(emulator
+            .receive(&receive_request(QUEUE_URL))
+            .unwrap()
+            .is_empty());
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(
+            emulator.receive(&receive_request(QUEUE_URL)).unwrap().len(),
+            1
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn moves_messages_to_dead_letter_queue() {
+        let emulator = SqsEmulator::new();
+        let options = QueueOptions {
+            visibility_timeout: Duration::from_secs(1),
+            redrive_policy: Some(RedrivePolicy {
+                dead_letter_queue_url: DLQ_URL.to_string(),
+                max_receive_count: 2,
+            }),
+            ..Default::default()
+        };
+
+        emulator.create_queue(QUEUE_URL, options);
+        emulator.create_queue(DLQ_URL, QueueOptions::default());
+        emulator.push_message(QUEUE_URL, Message::default());
+
+        for _ in 0..2 {
+            assert_eq!(
+                emulator.receive(&receive_request(QUEUE_URL)).unwrap().len(),
+                1
+            );
+            tokio::time::advance(Duration::from_secs(2)).await;
+        }
+
+        assert!(emulator
+            .receive(&receive_request(QUEUE_URL))
+            .unwrap()
+            .is_empty());
+        assert_eq!(emulator.queued_messages(DLQ_URL).len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn changes_visibility_of_received_messages() {
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(QUEUE_URL, QueueOptions::default());
+        emulator.push_message(QUEUE_URL, Message::default());
+
+        let received = emulator.receive(&receive_request(QUEUE_URL)).unwrap();
+
+        let changed = emulator.change_visibility(&ChangeMessageVisibilityRequest {
+            queue_url: QUEUE_URL.to_string(),
+            receipt_handle: received[0].receipt_handle.clone().unwrap(),
+            visibility_timeout: 5,
+        });
+        assert!(changed);
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(
+            emulator.receive(&receive_request(QUEUE_URL)).unwrap().len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//! SQS endpoints of a region, to target FIPS endpoints (GovCloud, FedRAMP) or dual-stack endpoints
+//! for IPv6 only VPCs
+//!
+//! ```rust,ignore
+//! let options = EndpointOptions {
+//!     fips: true,
+//!     ..Default::default()
+//! };
+//!
+//! let client = SQSListenerClientBuilder::new_with_endpoint(Region::UsGovWest1, options)
+//!     .listener(listener)
+//!     .build()?;
+//! ```
+use rusoto_core::Region;
+
+/// Which of the SQS endpoints of a region to use
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EndpointOptions {
+    /// Use the FIPS 140-2 validated endpoint
+    pub fips: bool,
+
+    /// Use the dual-stack endpoint, reachable over IPv4 and IPv6
+    pub dual_stack: bool,
+}
+
+impl EndpointOptions {
+    /// The region with its endpoint set to the selected SQS endpoint, a
+    /// [Custom](Region::Custom) region is returned unchanged
+    pub fn region(&self, region: Region) -> Region {
+        if let Region::Custom { .. } = region {
+            return region;
+        }
+
+        if !self.fips && !self.dual_stack {
+            return region;
+        }
+
+        let name = region.name();
+        let service = if self.fips { "sqs-fips" } else { "sqs" };
+
+        Region::Custom {
+            name: name.to_string(),
+            endpoint: format!(
+                "https://{}.{}.{}",
+                service,
+                name,
+                domain(partition(name), self.dual_stack)
+            ),
+        }
+    }
+}
+
+/// The partition a region belongs to
+pub(crate) fn partition(region_name: &str) -> &'static str {
+    if region_name.starts_with("cn-") {
+        "aws-cn"
+    } else if region_name.starts_with("us-gov-") {
+        "aws-us-gov"
+    } else {
+        "aws"
+    }
+}
+
+/// The partition of a queue url, `None` if its host is not an AWS endpoint
+pub(crate) fn queue_url_partition(queue_url: &str) -> Option<&'static str> {
+    let host = queue_url.split("://").nth(1)?.split('/').next()?;
+
+    if host.ends_with(".amazonaws.com.cn") || host.ends_with(".amazonwebservices.com.cn") {
+        Some("aws-cn")
+    } else if host.ends_with(".amazonaws.com") || host.ends_with(".api.aws") {
+        Some(partition(host.split('.').nth(1).unwrap_or_default()))
+    } else {
+        None
+    }
+}
+
+/// The domain of the endpoints in a partition
+pub(crate) fn domain(partition: &str, dual_stack: bool) -> &'static str {
+    match (partition, dual_stack) {
+        ("aws-cn", false) => "amazonaws.com.cn",
+        ("aws-cn", true) => "api.amazonwebservices.com.cn",
+        (_, false) => "amazonaws.com",
+        (_, true) => "api.aws",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(region: Region, fips: bool, dual_stack: bool) -> String {
+        match (EndpointOptions { fips, dual_stack }).region(region) {
+            Region::Custom { endpoint, .. } => endpoint,
+            region => panic!("expected a custom region, got {:?}", region),
+        }
+    }
+
+    #[test]
+    fn selects_fips_and_dual_stack_endpoints() {
+        assert_eq!(
+            endpoint(Region::UsGovWest1, true, false),
+            "https://sqs-fips.us-gov-west-1.amazonaws.com"
+        );
+        assert_eq!(
+            endpoint(Region::UsEast1, false, true),
+            "https://sqs.us-east-1.api.aws"
+        );
+        assert_eq!(
+            endpoint(Region::UsEast2, true, true),
+            "https://sqs-fips.us-east-2.api.aws"
+        );
+        assert_eq!(
+            endpoint(Region::CnNorth1, false, true),
+            "https://sqs.cn-north-1.api.amazonwebservices.com.cn"
+        );
+
+        assert_eq!(
+            EndpointOptions::default().region(Region::EuWest1),
+            Region::EuWest1
+        );
+    }
+
+    #[test]
+    fn finds_queue_url_partition() {
+        let url_partition =
+            |host: &str| queue_url_partition(&format!("https://{}/123456789012/queue", host));
+
+        assert_eq!(url_partition("sqs.us-east-1.amazonaws.com"), Some("aws"));
+        assert_eq!(
+            url_partition("sqs-fips.us-gov-west-1.amazonaws.com"),
+            Some("aws-us-gov")
+        );
+        assert_eq!(
+            url_partition("sqs.cn-north-1.amazonaws.com.cn"),
+            Some("aws-cn")
+        );
+        assert_eq!(url_partition("sqs.eu-west-1.api.aws"), Some("aws"));
+        assert_eq!(url_partition("localhost:9324"), None);
+        assert_eq!(queue_url_partition("queue"), None);
+    }
+}
@@ -0,0 +1,257 @@
+//! Large messages sent with the SQS Extended Client, whose payload is stored in S3 and the body is
+//! only a pointer to it
+//!
+//! Enable the `s3-extended` feature and add [S3Payloads] to the client, the payload is downloaded
+//! before the handler is called so it sees the real body
+//!
+//! ```rust,ignore
+//! let client = SQSListenerClientBuilder::new(Region::UsEast1)
+//!     .listener(listener)
+//!     .extended_payloads(S3Payloads::new(S3Client::new(Region::UsEast1)).delete_after_ack(true))
+//!     .build()?;
+//! ```
+//!
+//! The other way around, a [BufferedSender](crate::producer::BufferedSender) with
+//! [`offload_payloads()`](crate::producer::BufferedSender::offload_payloads) uploads payloads too
+//! large for a message with [S3Uploads] and sends a pointer in the same format
+use async_trait::async_trait;
+use rusoto_sqs::Message;
+use serde::{Deserialize, Serialize};
+
+use super::Error;
+
+/// Message attributes the extended client sets on messages with a payload in S3
+pub(crate) const PAYLOAD_SIZE_ATTRIBUTES: [&str; 2] =
+    ["ExtendedPayloadSize", "SQSLargePayloadSize"];
+
+// first element of the pointer, written by the current and the legacy java extended client
+const POINTER_CLASSES: [&str; 2] = [
+    "software.amazon.payloadoffloading.PayloadS3Pointer",
+    "com.amazon.sqs.javamessaging.MessageS3Pointer",
+];
+
+/// Location of a payload in S3
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct S3Pointer {
+    pub s3_bucket_name: String,
+    pub s3_key: String,
+}
+
+/// Where extended payloads are stored
+#[async_trait]
+pub trait PayloadStore: Send + Sync {
+    /// The payload the pointer refers to
+    async fn get(&self, pointer: &S3Pointer) -> Result<String, Error>;
+
+    /// Deletes the payload, called once the message is acked if
+    /// [`delete_after_ack()`](PayloadStore::delete_after_ack) is true
+    async fn delete(&self, pointer: &S3Pointer) -> Result<(), Error>;
+
+    fn delete_after_ack(&self) -> bool {
+        false
+    }
+}
+
+/// Where a [BufferedSender](crate::producer::BufferedSender) stores payloads too large for a
+/// message
+#[async_trait]
+pub trait PayloadUploader: Send + Sync {
+    /// Stores the payload, returns where it was stored
+    async fn put(&self, payload: &str) -> Result<S3Pointer, Error>;
+}
+
+/// The pointer of a message sent by the extended client, `None` for messages with their payload
+/// in the body
+pub(crate) fn pointer(message: &Message) -> Option<S3Pointer> {
+    let attributes = message.message_attributes.as_ref()?;
+
+    if !PAYLOAD_SIZE_ATTRIBUTES
+        .iter()
+        .any(|name| attributes.contains_key(*name))
+    {
+        return None;
+    }
+
+    let (class, pointer): (String, S3Pointer) =
+        serde_json::from_str(message.body.as_deref()?).ok()?;
+
+    if POINTER_CLASSES.contains(&class.as_str()) {
+        Some(pointer)
+    } else {
+        None
+    }
+}
+
+/// The body of a message whose payload is stored at `pointer`, as written by the current extended
+/// client
+pub(crate) fn pointer_body(pointer: &S3Pointer) -> String {
+    serde_json::to_string(&(POINTER_CLASSES[0], pointer)).expect("a pointer always serializes")
+}
+
+#[cfg(feature = "s3-extended")]
+pub use self::s3::{S3Payloads, S3Uploads};
+
+#[cfg(feature = "s3-extended")]
+mod s3 {
+    use async_trait::async_trait;
+    use futures::StreamExt;
+    use rusoto_s3::{DeleteObjectRequest, GetObjectRequest, PutObjectRequest, S3Client, S3};
+
+    use super::{PayloadStore, PayloadUploader, S3Pointer};
+    use crate::{unique_id, Error};
+
+    /// Downloads extended payloads from S3
+    #[derive(Clone)]
+    pub struct S3Payloads {
+        client: S3Client,
+        delete_after_ack: bool,
+    }
+
+    impl S3Payloads {
+        pub fn new(client: S3Client) -> Self {
+            Self {
+                client,
+                delete_after_ack: false,
+            }
+        }
+
+        /// Delete the payload from S3 once the message was acked, defaults to false. Only enable
+        /// it if no other consumer reads the same payload
+        pub fn delete_after_ack(mut self, delete_after_ack: bool) -> Self {
+            self.delete_after_ack = delete_after_ack;
+            self
+        }
+    }
+
+    fn payload_error(
+        pointer: &S3Pointer,
+        operation: &'static str,
+        source: impl std::error::Error + Send + Sync + 'static,
+    ) -> Error {
+        Error::ExtendedPayload {
+            operation,
+            bucket: pointer.s3_bucket_name.clone(),
+            key: pointer.s3_key.clone(),
+            source: Box::new(source),
+        }
+    }
+
+    #[async_trait]
+    impl PayloadStore for S3Payloads {
+        async fn get(&self, pointer: &S3Pointer) -> Result<String, Error> {
+            let output = self
+                .client
+                .get_object(GetObjectRequest {
+                    bucket: pointer.s3_bucket_name.clone(),
+                    key: pointer.s3_key.clone(),
+                    ..Default::default()
+                })
+                .await
+                .map_err(|error| payload_error(pointer, "download", error))?;
+
+            let mut bytes = Vec::new();
+
+            if let Some(mut body) = output.body {
+                while let Some(chunk) = body.next().await {
+                    let chunk = chunk.map_err(|error| payload_error(pointer, "download", error))?;
+                    bytes.extend_from_slice(&chunk);
+                }
+            }
+
+            String::from_utf8(bytes).map_err(|error| payload_error(pointer, "decode", error))
+        }
+
+        async fn delete(&self, pointer: &S3Pointer) -> Result<(), Error> {
+            self.client
+                .delete_object(DeleteObjectRequest {
+                    bucket: pointer.s3_bucket_name.clone(),
+                    key: pointer.s3_key.clone(),
+                    ..Default::default()
+                })
+                .await
+                .map_err(|error| payload_error(pointer, "delete", error))?;
+
+            Ok(())
+        }
+
+        fn delete_after_ack(&self) -> bool {
+            self.delete_after_ack
+        }
+    }
+
+    /// Uploads the payloads of large messages to a bucket
+    #[derive(Clone)]
+    pub struct S3Uploads {
+        client: S3Client,
+        bucket: String,
+    }
+
+    impl S3Uploads {
+        pub fn new(client: S3Client, bucket: String) -> Self {
+            Self { client, bucket }
+        }
+    }
+
+    #[async_trait]
+    impl PayloadUploader for S3Uploads {
+        async fn put(&self, payload: &str) -> Result<S3Pointer, Error> {
+            let pointer = S3Pointer {
+                s3_bucket_name: self.bucket.clone(),
+                s3_key: unique_id(),
+            };
+
+            self.client
+                .put_object(PutObjectRequest {
+                    bucket: pointer.s3_bucket_name.clone(),
+                    key: pointer.s3_key.clone(),
+                    body: Some(payload.as_bytes().to_vec().into()),
+                    ..Default::default()
+                })
+                .await
+                .map_err(|error| payload_error(&pointer, "upload", error))?;
+
+            Ok(pointer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_sqs::MessageAttributeValue;
+    use std::collections::HashMap;
+
+    #[test]
+    fn parses_pointers_of_extended_client_messages() {
+        let mut attributes = HashMap::new();
+        attributes.insert(
+            "ExtendedPayloadSize".to_string(),
+            MessageAttributeValue {
+                data_type: "Number".to_string(),
+                string_value: Some("300000".to_string()),
+                ..Default::default()
+            },
+        );
+
+        let mut message = Message {
+            body: Some(
+                r#"["software.amazon.payloadoffloading.PayloadS3Pointer",{"s3BucketName":"payloads","s3Key":"a1b2"}]"#
+                    .to_string(),
+            ),
+            message_attributes: Some(attributes),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            pointer(&message),
+            Some(S3Pointer {
+                s3_bucket_name: "payloads".to_string(),
+                s3_key: "a1b2".to_string(),
+            })
+        );
+
+        message.message_attributes = None;
+        assert_eq!(pointer(&message), None);
+    }
+}
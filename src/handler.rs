@@ -0,0 +1,504 @@
+//! Handlers are run by the listener for every received message
+//!
+//! Closures taking a `&Message` are handlers, use [`SQSListener::new_async()`](crate::SQSListener::new_async)
+//! for async closures, [`SQSListener::new_fallible()`](crate::SQSListener::new_fallible) for
+//! async closures returning a `Result`, or implement [Handler] for handlers that keep state
+//!
+//! [`SQSListener::new_batch()`](crate::SQSListener::new_batch) passes all messages of a receive to
+//! the handler at once, for example to insert them in a single transaction
+//!
+//! [`SQSListener::new_typed()`](crate::SQSListener::new_typed) deserializes JSON bodies before
+//! calling the handler, use a [TypedHandler] to choose what happens to bodies that can't be
+//! deserialized
+//!
+//! [`SQSListener::new_two_phase()`](crate::SQSListener::new_two_phase) splits the handler into a
+//! `prepare` stage before the message is deleted and a `commit` stage after it
+//!
+//! [`SQSListener::new_stream()`](crate::SQSListener::new_stream) has no handler, consume the
+//! messages as a [Stream](futures::Stream) with
+//! [`SQSListenerClient::into_stream()`](crate::SQSListenerClient::into_stream) instead
+//!
+//! ```rust,ignore
+//! let listener = SQSListener::new_fallible(queue_url, |message| async move {
+//!     save_to_database(message).await
+//! });
+//! ```
+use async_trait::async_trait;
+use rusoto_sqs::Message;
+use serde::de::{Deserialize, DeserializeOwned};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+use super::producer::BufferedSender;
+use super::router::JsonPath;
+use super::telemetry::warn;
+use super::Error;
+
+/// Error returned by a failed [Handler]
+pub type HandlerError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Handles received messages, the listener awaits the handler before acknowledging the message
+///
+/// A message is only acknowledged if its handler returns `Ok`, a failed message is left on the
+/// queue and redelivered once its visibility timeout expires
+#[async_trait]
+pub trait Handler: Send + Sync + 'static {
+    async fn handle(&self, message: &Message) -> Result<(), HandlerError>;
+
+    /// Pass all messages of a receive to [`handle_batch()`](Handler::handle_batch) instead of
+    /// handling them one at a time, defaults to false
+    fn handles_batches(&self) -> bool {
+        false
+    }
+
+    /// Handles the messages of a receive together, they are all acknowledged if it returns `Ok`
+    /// and none of them if it fails. Handles them one at a time by default
+    async fn handle_batch(&self, messages: &[Message]) -> Result<(), HandlerError> {
+        for message in messages {
+            self.handle(message).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Called once a message the handler returned `Ok` for was acknowledged, `acked` is false if
+    /// deleting it failed or `auto_ack` is off. Does nothing by default
+    async fn after_ack(&self, _message: &Message, _acked: bool) {}
+
+    /// Keep the receipt handle of the messages passed to
+    /// [`after_ack()`](Handler::after_ack), it is otherwise moved out to acknowledge them.
+    /// Defaults to false
+    fn keeps_receipt_handles(&self) -> bool {
+        false
+    }
+
+    /// Called with the error of a failed receive, after it was logged and passed to the
+    /// `on_error` hook. Does nothing by default
+    fn receive_failed(&self, _error: Error) {}
+}
+
+#[async_trait]
+impl<F: Fn(&Message) + Send + Sync + 'static> Handler for F {
+    async fn handle(&self, message: &Message) -> Result<(), HandlerError> {
+        self(message);
+        Ok(())
+    }
+}
+
+/// Boxed handlers let listeners with different handlers share a client, see
+/// [`listeners()`](crate::SQSListenerClientBuilder::listeners)
+#[async_trait]
+impl Handler for Box<dyn Handler> {
+    async fn handle(&self, message: &Message) -> Result<(), HandlerError> {
+        (**self).handle(message).await
+    }
+
+    fn handles_batches(&self) -> bool {
+        (**self).handles_batches()
+    }
+
+    async fn handle_batch(&self, messages: &[Message]) -> Result<(), HandlerError> {
+        (**self).handle_batch(messages).await
+    }
+
+    async fn after_ack(&self, message: &Message, acked: bool) {
+        (**self).after_ack(message, acked).await
+    }
+
+    fn keeps_receipt_handles(&self) -> bool {
+        (**self).keeps_receipt_handles()
+    }
+
+    fn receive_failed(&self, error: Error) {
+        (**self).receive_failed(error)
+    }
+}
+
+/// Handler for closures taking an owned [Message], created by
+/// [`SQSListener::new_owned()`](crate::SQSListener::new_owned)
+#[derive(Debug, Clone)]
+pub struct OwnedHandler<F>(pub(crate) F);
+
+#[async_trait]
+impl<F: Fn(Message) + Send + Sync + 'static> Handler for OwnedHandler<F> {
+    async fn handle(&self, message: &Message) -> Result<(), HandlerError> {
+        // the listener keeps the original to acknowledge the message and report its outcome
+        (self.0)(message.clone());
+        Ok(())
+    }
+}
+
+/// Handler for async closures, created by [`SQSListener::new_async()`](crate::SQSListener::new_async)
+#[derive(Debug, Clone)]
+pub struct AsyncHandler<F>(pub(crate) F);
+
+#[async_trait]
+impl<F, Fut> Handler for AsyncHandler<F>
+where
+    F: Fn(Message) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    async fn handle(&self, message: &Message) -> Result<(), HandlerError> {
+        // the future can outlive the borrow of the message, so it gets its own copy
+        (self.0)(message.clone()).await;
+        Ok(())
+    }
+}
+
+/// Handler for async closures returning a `Result`, created by
+/// [`SQSListener::new_fallible()`](crate::SQSListener::new_fallible)
+#[derive(Debug, Clone)]
+pub struct FallibleHandler<F>(pub(crate) F);
+
+#[async_trait]
+impl<F, Fut, E> Handler for FallibleHandler<F>
+where
+    F: Fn(Message) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+    E: Into<HandlerError> + 'static,
+{
+    async fn handle(&self, message: &Message) -> Result<(), HandlerError> {
+        (self.0)(message.clone()).await.map_err(Into::into)
+    }
+}
+
+/// Handler for async closures taking all messages of a receive, created by
+/// [`SQSListener::new_batch()`](crate::SQSListener::new_batch)
+#[derive(Debug, Clone)]
+pub struct BatchHandler<F>(pub(crate) F);
+
+#[async_trait]
+impl<F, Fut, E> Handler for BatchHandler<F>
+where
+    F: Fn(Vec<Message>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+    E: Into<HandlerError> + 'static,
+{
+    async fn handle(&self, message: &Message) -> Result<(), HandlerError> {
+        self.handle_batch(std::slice::from_ref(message)).await
+    }
+
+    fn handles_batches(&self) -> bool {
+        true
+    }
+
+    async fn handle_batch(&self, messages: &[Message]) -> Result<(), HandlerError> {
+        (self.0)(messages.to_vec()).await.map_err(Into::into)
+    }
+}
+
+/// Handler in two stages around the acknowledgement, created by
+/// [`SQSListener::new_two_phase()`](crate::SQSListener::new_two_phase)
+///
+/// `prepare` runs first, the message is deleted once it returns `Ok` and `commit` is then called
+/// with the token `prepare` returned. If the delete fails, or `auto_ack` is off, the token is
+/// dropped instead of committed, so a token that rolls back on drop keeps both sides consistent
+pub struct TwoPhaseHandler<P, C, T> {
+    prepare: P,
+    commit: C,
+    // the prepared tokens by receipt handle, so every delivery of a message gets its own, until
+    // the message is acknowledged
+    tokens: Mutex<HashMap<String, T>>,
+}
+
+impl<P, C, T> TwoPhaseHandler<P, C, T> {
+    pub(crate) fn new(prepare: P, commit: C) -> Self {
+        Self {
+            prepare,
+            commit,
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl<P, PFut, C, CFut, T, E> Handler for TwoPhaseHandler<P, C, T>
+where
+    P: Fn(Message) -> PFut + Send + Sync + 'static,
+    PFut: Future<Output = Result<T, E>> + Send + 'static,
+    C: Fn(T) -> CFut + Send + Sync + 'static,
+    CFut: Future<Output = ()> + Send + 'static,
+    T: Send + 'static,
+    E: Into<HandlerError> + 'static,
+{
+    async fn handle(&self, message: &Message) -> Result<(), HandlerError> {
+        let receipt_handle = message
+            .receipt_handle
+            .clone()
+            .ok_or("message has no receipt handle to keep its prepared token by")?;
+
+        let token = (self.prepare)(message.clone()).await.map_err(Into::into)?;
+
+        self.tokens
+            .lock()
+            .expect("tokens poisoned")
+            .insert(receipt_handle, token);
+
+        Ok(())
+    }
+
+    async fn after_ack(&self, message: &Message, acked: bool) {
+        let token = match &message.receipt_handle {
+            Some(receipt_handle) => self
+                .tokens
+                .lock()
+                .expect("tokens poisoned")
+                .remove(receipt_handle),
+            None => None,
+        };
+
+        if let (Some(token), true) = (token, acked) {
+            (self.commit)(token).await
+        }
+    }
+
+    fn keeps_receipt_handles(&self) -> bool {
+        true
+    }
+}
+
+/// Passes messages on to the stream of
+/// [`SQSListenerClient::into_stream()`](crate::SQSListenerClient::into_stream), created by
+/// [`SQSListener::new_stream()`](crate::SQSListener::new_stream)
+#[derive(Debug)]
+pub struct StreamHandler {
+    sender: mpsc::Sender<Result<Message, Error>>,
+    receiver: Mutex<Option<mpsc::Receiver<Result<Message, Error>>>>,
+}
+
+impl StreamHandler {
+    pub(crate) fn new() -> Self {
+        // no buffer beyond a single message, so the listener doesn't receive ahead of the stream
+        let (sender, receiver) = mpsc::channel(1);
+
+        Self {
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+        }
+    }
+
+    pub(crate) fn take_receiver(&self) -> Option<mpsc::Receiver<Result<Message, Error>>> {
+        self.receiver.lock().expect("receiver poisoned").take()
+    }
+}
+
+#[async_trait]
+impl Handler for StreamHandler {
+    async fn handle(&self, message: &Message) -> Result<(), HandlerError> {
+        // waits for room in the stream, fails once the stream was dropped
+        self.sender
+            .send(Ok(message.clone()))
+            .await
+            .map_err(|_error| HandlerError::from("the stream was dropped"))
+    }
+
+    fn receive_failed(&self, error: Error) {
+        // dropped while the stream is full, the consumer is busy and the error was logged anyway
+        let _ = self.sender.try_send(Err(error));
+    }
+}
+
+/// What a [TypedHandler] does with a message whose body can't be deserialized
+#[derive(Clone)]
+pub enum InvalidBody {
+    /// Drop the message without calling the handler, it is acked if `auto_ack` is set. The default
+    Ack,
+
+    /// Fail the message, it is redelivered after its visibility timeout and eventually moved to
+    /// the dead-letter queue of the queue
+    Leave,
+
+    /// Send the body to another queue, for example a queue of invalid messages, then ack it if
+    /// `auto_ack` is set. The message is left on the queue if it can't be sent
+    Route(BufferedSender),
+}
+
+/// Handler deserializing JSON bodies into `T`, created by
+/// [`SQSListener::new_typed()`](crate::SQSListener::new_typed)
+///
+/// Bodies of older versions are deserialized into their own type and upgraded to `T`, selected
+/// by the `version` message attribute. Only the part of an envelope at a [JsonPath] is
+/// deserialized with [`extract()`](TypedHandler::extract)
+///
+/// ```rust,ignore
+/// let handler = TypedHandler::new(|order: Order, _message: &Message| println!("{:?}", order))
+///     .upgrade_from("1", |order: OrderV1| Order::from(OrderV2::from(order)))
+///     .upgrade_from("2", |order: OrderV2| Order::from(order))
+///     .extract(JsonPath::parse("$.detail")?)
+///     .on_invalid_body(InvalidBody::Leave);
+///
+/// let listener = SQSListener::with_handler(queue_url, handler);
+/// ```
+pub struct TypedHandler<T, F> {
+    handler: F,
+    on_invalid_body: InvalidBody,
+    base64_body: bool,
+    version_attribute: String,
+    extract: Option<JsonPath>,
+    // deserialize the body of an older version and upgrade it to `T`, by version
+    upgrades: HashMap<String, Box<dyn Fn(&Document) -> Result<T, HandlerError> + Send + Sync>>,
+}
+
+// the JSON that is deserialized, the body or the part of it selected by `extract`
+enum Document<'a> {
+    Body(&'a [u8]),
+    Extracted(&'a Value),
+}
+
+impl Document<'_> {
+    fn deserialize<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        match self {
+            Document::Body(body) => serde_json::from_slice(body),
+            Document::Extracted(value) => <T as Deserialize>::deserialize(*value),
+        }
+    }
+}
+
+impl<T, F> TypedHandler<T, F>
+where
+    T: DeserializeOwned + 'static,
+    F: Fn(T, &Message) + Send + Sync + 'static,
+{
+    /// Wrap a handler taking the deserialized body and the message
+    pub fn new(handler: F) -> Self {
+        Self {
+            handler,
+            on_invalid_body: InvalidBody::Ack,
+            base64_body: false,
+            version_attribute: "version".to_string(),
+            extract: None,
+            upgrades: HashMap::new(),
+        }
+    }
+
+    /// What to do with bodies that can't be deserialized, defaults to [`InvalidBody::Ack`]
+    pub fn on_invalid_body(mut self, on_invalid_body: InvalidBody) -> Self {
+        self.on_invalid_body = on_invalid_body;
+        self
+    }
+
+    /// Decode the body from base64 before deserializing it, for producers that encode binary or
+    /// non UTF-8 payloads. Defaults to false
+    pub fn base64_body(mut self, base64_body: bool) -> Self {
+        self.base64_body = base64_body;
+        self
+    }
+
+    /// Message attribute with the version of the body, defaults to `version`. Request it with the
+    /// `message_attribute_names` [Config](crate::ConfigBuilder) option, without it every body is
+    /// deserialized into `T`
+    pub fn version_attribute(mut self, version_attribute: &str) -> Self {
+        self.version_attribute = version_attribute.to_string();
+        self
+    }
+
+    /// Deserialize only the value at `path` of the body into `T`, for example the `detail` of an
+    /// EventBridge envelope. Bodies without a value at `path` are invalid
+    pub fn extract(mut self, path: JsonPath) -> Self {
+        self.extract = Some(path);
+        self
+    }
+
+    /// Deserialize bodies of `version` into `V` and pass them to the handler upgraded to `T`, so
+    /// consumers keep working while producers are upgraded. Chain the upgrades of versions that
+    /// are more than one behind in `upgrade`. Bodies without a version, or of a version that is
+    /// not registered, are deserialized into `T`
+    pub fn upgrade_from<V, U>(mut self, version: &str, upgrade: U) -> Self
+    where
+        V: DeserializeOwned + 'static,
+        U: Fn(V) -> T + Send + Sync + 'static,
+    {
+        let decode = move |document: &Document| -> Result<T, HandlerError> {
+            let body: V = document.deserialize()?;
+            Ok(upgrade(body))
+        };
+
+        self.upgrades.insert(version.to_string(), Box::new(decode));
+        self
+    }
+
+    fn decode(&self, message: &Message) -> Result<T, HandlerError> {
+        let body = message.body.as_deref().unwrap_or_default();
+
+        let bytes = if self.base64_body {
+            Cow::Owned(base64::decode(body.trim())?)
+        } else {
+            Cow::Borrowed(body.as_bytes())
+        };
+
+        let envelope: Value;
+
+        let document = match &self.extract {
+            Some(path) => {
+                envelope = serde_json::from_slice(&bytes)?;
+                let value = path
+                    .select(&envelope)
+                    .ok_or_else(|| format!("body has no value at {}", path))?;
+
+                Document::Extracted(value)
+            }
+            None => Document::Body(bytes.as_ref()),
+        };
+
+        match self
+            .version(message)
+            .and_then(|version| self.upgrades.get(version))
+        {
+            Some(upgrade) => upgrade(&document),
+            None => Ok(document.deserialize()?),
+        }
+    }
+
+    fn version<'a>(&self, message: &'a Message) -> Option<&'a str> {
+        message
+            .message_attributes
+            .as_ref()?
+            .get(&self.version_attribute)?
+            .string_value
+            .as_deref()
+    }
+
+    async fn invalid_body(
+        &self,
+        message: &Message,
+        error: HandlerError,
+    ) -> Result<(), HandlerError> {
+        let message_id = message.message_id.as_deref().unwrap_or_default();
+
+        match &self.on_invalid_body {
+            InvalidBody::Ack => {
+                warn!("Dropping message {}, invalid body: {}", message_id, error);
+                Ok(())
+            }
+            InvalidBody::Leave => Err(error),
+            InvalidBody::Route(sender) => {
+                let body = message.body.clone().unwrap_or_default();
+                sender.send(body).await?.sent().await?;
+
+                warn!("Routed message {}, invalid body: {}", message_id, error);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<T, F> Handler for TypedHandler<T, F>
+where
+    T: DeserializeOwned + 'static,
+    F: Fn(T, &Message) + Send + Sync + 'static,
+{
+    async fn handle(&self, message: &Message) -> Result<(), HandlerError> {
+        match self.decode(message) {
+            Ok(body) => {
+                (self.handler)(body, message);
+                Ok(())
+            }
+            Err(error) => self.invalid_body(message, error).await,
+        }
+    }
+}
@@ -20,7 +20,7 @@ async fn main() -> eyre::Result<()> {
         .listener(listener)
         .build()?;
 
-    let _ = client.start().await;
+    client.start().await?;
 
     Ok(())
 }
@@ -60,27 +60,78 @@ async fn main() -> eyre::Result<()> {
     .listener(listener)
     .build()?;
 
-    let _ = client.start().await;
+    client.start().await?;
 
     Ok(())
 }
 ```
 */
+mod acker;
+#[cfg(feature = "aws-sdk")]
+mod aws_sdk;
+pub mod backoff;
+mod canary;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+#[cfg(not(feature = "chaos"))]
+#[allow(dead_code)]
+mod chaos;
+mod checksum;
 pub mod client;
+pub mod dedup;
+#[cfg(any(test, feature = "emulator"))]
+pub mod emulator;
+pub mod endpoint;
+pub mod extended;
+pub mod handler;
+pub mod metrics;
+pub mod producer;
+pub mod redact;
+pub mod redrive;
+pub mod router;
+pub mod shutdown;
+pub mod sink;
+mod telemetry;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod transport;
+pub mod visibility;
+mod worker;
 
 use act_zero::runtimes::tokio::spawn_actor;
 use act_zero::*;
 use derive_builder::Builder;
+use futures::future::join_all;
+use futures::stream::{self, Stream, StreamExt};
+use rusoto_core::request::HttpClient;
 use rusoto_core::{DispatchSignedRequest, RusotoError};
-use rusoto_sqs::{DeleteMessageError, ReceiveMessageError, SqsClient};
-use std::time::Duration;
+use rusoto_sqs::{
+    ChangeMessageVisibilityError, DeleteMessageBatchError, DeleteMessageError,
+    GetQueueAttributesError, ReceiveMessageError, SendMessageBatchError, SendMessageError,
+    SqsClient,
+};
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use handler::{
+    AsyncHandler, BatchHandler, FallibleHandler, OwnedHandler, StreamHandler, TwoPhaseHandler,
+    TypedHandler,
+};
+use telemetry::{debug, info, warn};
 
+pub use handler::{Handler, HandlerError};
 pub use rusoto_core::{
     credential,
     region::{self, Region},
     request,
 };
 pub use rusoto_sqs::Message;
+pub use shutdown::ShutdownHandle;
 
 /// Used to build a new [SQSListenerClient]
 pub type SQSListenerClientBuilder<F> = client::SQSListenerClientBuilder<F>;
@@ -106,21 +157,257 @@ pub enum Error {
     #[error("unable to acknowledge message: {0}")]
     AckMessage(#[from] RusotoError<DeleteMessageError>),
 
+    #[error("unable to acknowledge messages: {0}")]
+    AckMessageBatch(#[from] RusotoError<DeleteMessageBatchError>),
+
+    #[error("message was not acknowledged: {code} {message}")]
+    AckFailed { code: String, message: String },
+
+    #[error("SQS did not return the result of the acknowledgement")]
+    UnknownAckResult,
+
     #[error("Message did not contain a message handle to use for acknowledging")]
     NoMessageHandle,
 
     #[error("Listener has stopped")]
     ListenerStopped,
 
+    #[error("unable to change message visibility: {0}")]
+    ChangeVisibility(#[from] RusotoError<ChangeMessageVisibilityError>),
+
+    #[error("in flight messages were not drained within {0:?} of the shutdown")]
+    DrainTimeout(Duration),
+
     #[error("unable to receive messages")]
     UnknownReceiveMessages,
+
+    #[error("queue does not exist or can not be accessed: {0}")]
+    QueueUnavailable(#[from] RusotoError<GetQueueAttributesError>),
+
+    #[error("unable to send message: {0}")]
+    SendMessage(#[from] RusotoError<SendMessageError>),
+
+    #[error("queue {0} has no dead-letter queue")]
+    NoDeadLetterQueue(String),
+
+    #[error("redrive policy of the queue can not be parsed: {0}")]
+    InvalidRedrivePolicy(String),
+
+    #[error("self test canary was not received within {0:?}")]
+    SelfTestTimeout(Duration),
+
+    #[error("unable to load credentials: {0}")]
+    Credentials(#[from] credential::CredentialsError),
+
+    #[error("AWS profile {profile} not found in {path:?}")]
+    ProfileNotFound { profile: String, path: PathBuf },
+
+    #[error("unable to send messages: {0}")]
+    SendMessageBatch(#[from] RusotoError<SendMessageBatchError>),
+
+    #[error("message was not sent: {code} {message}")]
+    SendMessageFailed { code: String, message: String },
+
+    #[error("Producer has stopped")]
+    ProducerStopped,
+
+    #[error("message was rejected by an interceptor: {0}")]
+    MessageRejected(#[source] HandlerError),
+
+    #[error("listener was not created with an SqsClient")]
+    NoSqsClient,
+
+    #[error(
+        "checksum of the message {part} does not match, expected {expected}, computed {computed}"
+    )]
+    ChecksumMismatch {
+        part: &'static str,
+        expected: String,
+        computed: String,
+    },
+
+    #[error("unable to acknowledge message, the receipt handle expired and the message will be redelivered: {0}")]
+    ReceiptHandleExpired(#[source] RusotoError<DeleteMessageError>),
+
+    #[error("handler failed: {0}")]
+    Handler(#[source] HandlerError),
+
+    #[error("unable to {operation} extended payload s3://{bucket}/{key}: {source}")]
+    ExtendedPayload {
+        operation: &'static str,
+        bucket: String,
+        key: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("unable to {operation} deduplication key {key}: {source}")]
+    DedupStore {
+        operation: &'static str,
+        key: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("JSONPath {path:?} is not supported: {reason}")]
+    InvalidJsonPath { path: String, reason: &'static str },
+
+    #[error("container credentials endpoint not set, AWS_CONTAINER_CREDENTIALS_RELATIVE_URI or AWS_CONTAINER_CREDENTIALS_FULL_URI is missing")]
+    ContainerCredentialsUnavailable,
+
+    #[cfg(feature = "prometheus")]
+    #[error("unable to register metrics: {0}")]
+    Prometheus(#[from] prometheus::Error),
+}
+
+impl Error {
+    // an invalid receipt handle is almost always an expired one, the handle came from SQS
+    pub(crate) fn from_ack(error: RusotoError<DeleteMessageError>) -> Self {
+        let expired = match &error {
+            RusotoError::Service(DeleteMessageError::ReceiptHandleIsInvalid(_)) => true,
+            RusotoError::Unknown(response) => {
+                let body = response.body_as_str();
+                body.contains("ReceiptHandleIsInvalid")
+                    || body.contains("receipt handle has expired")
+            }
+            _ => false,
+        };
+
+        if expired {
+            Error::ReceiptHandleExpired(error)
+        } else {
+            Error::AckMessage(error)
+        }
+    }
+
+    // the message was deleted or became visible again, extending it can never succeed
+    pub(crate) fn is_invalid_receipt_handle(&self) -> bool {
+        match self {
+            Error::ChangeVisibility(RusotoError::Service(
+                ChangeMessageVisibilityError::ReceiptHandleIsInvalid(_),
+            )) => true,
+            Error::ChangeVisibility(RusotoError::Unknown(response)) => {
+                response.body_as_str().contains("ReceiptHandleIsInvalid")
+            }
+            _ => false,
+        }
+    }
+
+    // the request never reached SQS, for example a DNS or connection failure
+    pub(crate) fn is_connection_failure(&self) -> bool {
+        match self {
+            Error::ReceiveMessages(RusotoError::HttpDispatch(_)) => true,
+            Error::AckMessage(RusotoError::HttpDispatch(_)) => true,
+            Error::AckMessageBatch(RusotoError::HttpDispatch(_)) => true,
+            _ => false,
+        }
+    }
+
+    // the session is dead, retrying with the same credentials will never succeed
+    pub(crate) fn is_expired_credentials(&self) -> bool {
+        fn expired<E>(error: &RusotoError<E>) -> bool {
+            match error {
+                RusotoError::Credentials(_) => true,
+                RusotoError::Unknown(response) => {
+                    let body = response.body_as_str();
+                    body.contains("ExpiredToken") || body.contains("InvalidClientTokenId")
+                }
+                _ => false,
+            }
+        }
+
+        match self {
+            Error::ReceiveMessages(error) => expired(error),
+            Error::AckMessage(error) => expired(error),
+            Error::AckMessageBatch(error) => expired(error),
+            Error::ChangeVisibility(error) => expired(error),
+            Error::SendMessage(error) => expired(error),
+            Error::QueueUnavailable(error) => expired(error),
+            _ => false,
+        }
+    }
 }
 
 /// Create a new Builder
-impl<F: Fn(&Message) + Send + Sync> SQSListenerClientBuilder<F> {
+impl<F: Handler> SQSListenerClientBuilder<F> {
     /// Create a new listener the default AWS client and queue_url
     pub fn new(region: Region) -> Self {
-        Self::new_with_client(SqsClient::new(region))
+        let factory_region = region.clone();
+
+        Self::new_with_client(SqsClient::new(region.clone()))
+            .region(region)
+            .client_factory(move || SqsClient::new(factory_region.clone()))
+    }
+
+    /// Create a new listener using a FIPS or dual-stack SQS endpoint of the region, see
+    /// [EndpointOptions](endpoint::EndpointOptions)
+    pub fn new_with_endpoint(region: Region, options: endpoint::EndpointOptions) -> Self {
+        Self::new(options.region(region))
+    }
+
+    /// Create a new listener using the ambient AWS setup, the region is read from `AWS_DEFAULT_REGION`,
+    /// `AWS_REGION` or the profile config (falling back to `us-east-1`) and credentials come from
+    /// the default credential chain
+    pub fn from_env() -> Self {
+        Self::new(Region::default())
+    }
+
+    /// Create a new listener using the credentials of a named profile, from the shared credentials
+    /// file (`~/.aws/credentials` or `AWS_SHARED_CREDENTIALS_FILE`) or the config file
+    ///
+    /// Returns an error if the profile does not exist, instead of failing on the first poll
+    pub fn new_with_profile(profile: &str, region: Region) -> Result<Self, Error> {
+        let provider = credential::ProfileProvider::with_default_credentials(profile)?;
+
+        if !profile_exists(provider.file_path(), profile) {
+            return Err(Error::ProfileNotFound {
+                profile: profile.to_string(),
+                path: provider.file_path().to_path_buf(),
+            });
+        }
+
+        let factory_provider = provider.clone();
+        let factory_region = region.clone();
+
+        Ok(Self::new_with(
+            HttpClient::new().expect("failed to create request dispatcher"),
+            provider,
+            region,
+        )
+        .client_factory(move || {
+            SqsClient::new_with(
+                HttpClient::new().expect("failed to create request dispatcher"),
+                factory_provider.clone(),
+                factory_region.clone(),
+            )
+        }))
+    }
+
+    /// Create a new listener using the ECS task role, always fetching credentials from the container
+    /// credentials endpoint (`AWS_CONTAINER_CREDENTIALS_RELATIVE_URI` or
+    /// `AWS_CONTAINER_CREDENTIALS_FULL_URI`) instead of the first source found by the default chain
+    ///
+    /// Returns an error if the listener is not running in a container with a task role
+    pub fn new_with_container_credentials(region: Region) -> Result<Self, Error> {
+        let endpoint_set = [
+            "AWS_CONTAINER_CREDENTIALS_RELATIVE_URI",
+            "AWS_CONTAINER_CREDENTIALS_FULL_URI",
+        ]
+        .iter()
+        .any(|name| std::env::var_os(name).map_or(false, |value| !value.is_empty()));
+
+        if !endpoint_set {
+            return Err(Error::ContainerCredentialsUnavailable);
+        }
+
+        let provider =
+            credential::AutoRefreshingProvider::new(credential::ContainerProvider::new())?;
+
+        Ok(Self::new_with(
+            HttpClient::new().expect("failed to create request dispatcher"),
+            provider,
+            region,
+        ))
     }
 
     /// Create a new listener with custom credentials, request dispatcher, region and queue_url
@@ -132,8 +419,9 @@ impl<F: Fn(&Message) + Send + Sync> SQSListenerClientBuilder<F> {
         Self::new_with_client(SqsClient::new_with(
             request_dispatcher,
             credentials_provider,
-            region,
+            region.clone(),
         ))
+        .region(region)
     }
 
     /// Create new listener with a client and queue_url
@@ -141,59 +429,538 @@ impl<F: Fn(&Message) + Send + Sync> SQSListenerClientBuilder<F> {
         client::SQSListenerClientBuilder::priv_new_with_client(client)
     }
 
+    /// Create a new listener with any [Sqs](rusoto_sqs::Sqs) implementation, for example a mock or
+    /// a hand written fake in tests, or an alternative transport
+    pub fn new_with_sqs(sqs: impl rusoto_sqs::Sqs + 'static) -> Self {
+        Self::default().client(sqs)
+    }
+
+    /// Create a new listener on the official [aws-sdk-sqs](https://docs.rs/aws-sdk-sqs) client
+    /// instead of rusoto, with the credentials, region and endpoint configured on the SDK client.
+    /// Enable with the `aws-sdk` feature
+    #[cfg(feature = "aws-sdk")]
+    pub fn new_with_aws_sdk(client: aws_sdk_sqs::Client) -> Self {
+        let region = client
+            .config()
+            .region()
+            .and_then(|region| region.as_ref().parse::<Region>().ok());
+
+        let builder = Self::default().transport(Arc::new(aws_sdk::AwsSdkTransport::new(client)));
+
+        match region {
+            Some(region) => builder.region(region),
+            None => builder,
+        }
+    }
+
+    /// Create a new listener running against an in process [SqsEmulator](emulator::SqsEmulator)
+    #[cfg(any(test, feature = "emulator"))]
+    pub fn new_with_emulator(emulator: emulator::SqsEmulator) -> Self {
+        Self::default().transport(Arc::new(emulator))
+    }
+
     pub fn build(
         self: SQSListenerClientBuilder<F>,
     ) -> Result<SQSListenerClient<F>, SQSListenerClientBuilderError> {
-        let inner: client::SQSListenerClient<F> = self.priv_build()?;
+        let mut inner: client::SQSListenerClient<F> = self.priv_build()?;
+        let extra_listeners = inner.split_listeners()?;
+
+        let mut inner = vec![inner];
+        inner.extend(extra_listeners);
 
         Ok(SQSListenerClient {
-            inner: Some(inner),
-            addr: Addr::detached(),
+            dispatch_gauge: inner[0].dispatch_gauge.clone(),
+            health_gauges: inner
+                .iter()
+                .map(|client| client.health_gauge.clone())
+                .collect(),
+            metrics: inner[0].metrics.clone(),
+            inner,
+            pollers: Arc::new(Mutex::new(Vec::new())),
+            shutdown: Arc::new(shutdown::ShutdownState::new()),
         })
     }
 }
 
 /// Listener for a `queue_url` with a handler function to be run on each received message
 ///
-/// The handler function should take a [Message] and return a unit `()`
+/// The handler function should take a [Message] and return a unit `()`, see [handler] for async
+/// handlers
 #[derive(Debug)]
-pub struct SQSListener<F: Fn(&Message)> {
+pub struct SQSListener<F: Handler> {
     /// Url for the SQS queue that you want to listen to
     queue_url: String,
 
-    /// Function to call when a new message is received
-    handler: F,
+    /// Function to call when a new message is received, shared by the workers
+    handler: Arc<F>,
+
+    /// Endpoint for the requests of this listener, instead of the one of the client
+    endpoint: Option<String>,
+}
+
+impl<F: Handler> SQSListener<F> {
+    /// Create a new listener with any [Handler], `queue` can either be the queue url or the queue
+    /// ARN (`arn:aws:sqs:region:account_id:queue_name`)
+    pub fn with_handler(queue: String, handler: F) -> Self {
+        let queue_url = queue_url_from_arn(&queue).unwrap_or(queue);
+
+        Self {
+            queue_url,
+            handler: Arc::new(handler),
+            endpoint: None,
+        }
+    }
+
+    /// Send the requests of this listener to another endpoint, for example the DNS name of a VPC
+    /// interface endpoint, while the other [`listeners()`](SQSListenerClientBuilder::listeners)
+    /// keep the endpoint of the client. The listener gets its own SQS client, with the region of
+    /// the builder and the default credential chain
+    pub fn endpoint(mut self, endpoint: String) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+}
+
+impl<F: Fn(&Message) + Send + Sync + 'static> SQSListener<F> {
+    /// Create a new listener, `queue` can either be the queue url or the queue ARN
+    /// (`arn:aws:sqs:region:account_id:queue_name`)
+    pub fn new(queue: String, handler: F) -> Self {
+        Self::with_handler(queue, handler)
+    }
+}
+
+impl<F: Fn(Message) + Send + Sync + 'static> SQSListener<OwnedHandler<F>> {
+    /// Create a new listener with a handler taking the [Message] by value, to move its fields
+    /// into a spawned task without cloning each of them. The message is cloned once, the listener
+    /// keeps the original to acknowledge it. `queue` can either be the queue url or the queue ARN
+    pub fn new_owned(queue: String, handler: F) -> Self {
+        Self::with_handler(queue, OwnedHandler(handler))
+    }
+}
+
+impl<F, Fut> SQSListener<AsyncHandler<F>>
+where
+    F: Fn(Message) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    /// Create a new listener with an async handler, the message is acked once the returned future
+    /// completes. `queue` can either be the queue url or the queue ARN
+    pub fn new_async(queue: String, handler: F) -> Self {
+        Self::with_handler(queue, AsyncHandler(handler))
+    }
+}
+
+impl<F, Fut, E> SQSListener<BatchHandler<F>>
+where
+    F: Fn(Vec<Message>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+    E: Into<HandlerError> + 'static,
+{
+    /// Create a new listener whose handler receives all messages of a receive at once, up to the
+    /// `max_messages` [Config](ConfigBuilder) option. The messages are acked together if the
+    /// handler returns `Ok`, otherwise none of them are. `queue` can either be the queue url or
+    /// the queue ARN
+    pub fn new_batch(queue: String, handler: F) -> Self {
+        Self::with_handler(queue, BatchHandler(handler))
+    }
+}
+
+impl<F, Fut, E> SQSListener<FallibleHandler<F>>
+where
+    F: Fn(Message) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+    E: Into<HandlerError> + 'static,
+{
+    /// Create a new listener with an async handler returning a `Result`, the message is only
+    /// acked if the handler returns `Ok`. `queue` can either be the queue url or the queue ARN
+    pub fn new_fallible(queue: String, handler: F) -> Self {
+        Self::with_handler(queue, FallibleHandler(handler))
+    }
+}
+
+impl<P, PFut, C, CFut, T, E> SQSListener<TwoPhaseHandler<P, C, T>>
+where
+    P: Fn(Message) -> PFut + Send + Sync + 'static,
+    PFut: Future<Output = Result<T, E>> + Send + 'static,
+    C: Fn(T) -> CFut + Send + Sync + 'static,
+    CFut: Future<Output = ()> + Send + 'static,
+    T: Send + 'static,
+    E: Into<HandlerError> + 'static,
+{
+    /// Create a new listener whose handler runs in two stages around the acknowledgement: the
+    /// message is deleted once `prepare` returns `Ok`, then `commit` is called with the token
+    /// `prepare` returned, see [TwoPhaseHandler]. `queue` can either be the queue url or the queue
+    /// ARN
+    pub fn new_two_phase(queue: String, prepare: P, commit: C) -> Self {
+        Self::with_handler(queue, TwoPhaseHandler::new(prepare, commit))
+    }
+}
+
+impl<T, F> SQSListener<TypedHandler<T, F>>
+where
+    T: serde::de::DeserializeOwned + 'static,
+    F: Fn(T, &Message) + Send + Sync + 'static,
+{
+    /// Create a new listener whose handler receives the JSON body deserialized into `T`. Messages
+    /// whose body can't be deserialized are acked without calling the handler, use a
+    /// [TypedHandler] with [`with_handler()`](SQSListener::with_handler) to leave or route them
+    /// instead. `queue` can either be the queue url or the queue ARN
+    pub fn new_typed(queue: String, handler: F) -> Self {
+        Self::with_handler(queue, TypedHandler::new(handler))
+    }
+}
+
+impl SQSListener<StreamHandler> {
+    /// Create a new listener without a handler, consume its messages with
+    /// [`SQSListenerClient::into_stream()`]. `queue` can either be the queue url or the queue ARN
+    pub fn new_stream(queue: String) -> Self {
+        Self::with_handler(queue, StreamHandler::new())
+    }
+}
+
+/// A message whose handler took longer than the `slow_message_threshold`, passed to the
+/// [`on_slow_message`](SQSListenerClientBuilder::on_slow_message) hook
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowMessage {
+    pub message_id: Option<String>,
+
+    /// How often the message has been received, including this time
+    pub receive_count: Option<u32>,
+
+    /// Hash of the message body, identifies the same payload across deliveries
+    pub body_hash: u64,
+
+    /// How long the handler took
+    pub duration: Duration,
+}
+
+impl SlowMessage {
+    fn new(message: &Message, duration: Duration) -> Self {
+        let mut hasher = DefaultHasher::new();
+        message.body.hash(&mut hasher);
+
+        let receive_count = telemetry::receive_count(message);
+
+        Self {
+            message_id: message.message_id.clone(),
+            receive_count,
+            body_hash: hasher.finish(),
+            duration,
+        }
+    }
+}
+
+// a profile is a `[name]` section in the credentials file, or `[profile name]` in the config file
+fn profile_exists(credentials_file: &Path, profile: &str) -> bool {
+    let config_file = std::env::var_os("AWS_CONFIG_FILE")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| credentials_file.with_file_name("config"));
+
+    let has_section = |path: &Path, section: &str| {
+        std::fs::read_to_string(path)
+            .map(|contents| contents.lines().any(|line| line.trim() == section))
+            .unwrap_or(false)
+    };
+
+    has_section(credentials_file, &format!("[{}]", profile))
+        || has_section(&config_file, &format!("[profile {}]", profile))
+        || has_section(&config_file, &format!("[{}]", profile))
+}
+
+/// Why the SQS client was rebuilt, passed to the
+/// [`on_client_rebuilt`](SQSListenerClientBuilder::on_client_rebuilt) hook
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum ClientRebuild {
+    /// Requests failed with `ExpiredToken` or `InvalidClientTokenId`
+    ExpiredCredentials,
+
+    /// Receives have failed with connection errors for this long
+    ConnectionFailures(Duration),
+}
+
+impl std::fmt::Display for ClientRebuild {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientRebuild::ExpiredCredentials => write!(f, "Credentials expired"),
+            ClientRebuild::ConnectionFailures(duration) => {
+                write!(f, "Connections have been failing for {:?}", duration)
+            }
+        }
+    }
+}
+
+/// Creates a new id, unique to this process
+pub(crate) fn unique_id() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+
+    format!(
+        "{}-{}-{}",
+        std::process::id(),
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
 }
 
-impl<F: Fn(&Message)> SQSListener<F> {
-    pub fn new(queue_url: String, handler: F) -> Self {
-        Self { queue_url, handler }
+/// Converts a queue ARN into its queue url, returns `None` if `arn` is not an SQS queue ARN
+fn queue_url_from_arn(arn: &str) -> Option<String> {
+    let mut parts = arn.splitn(6, ':');
+
+    if parts.next()? != "arn" {
+        return None;
+    }
+
+    let partition = parts.next()?;
+
+    if parts.next()? != "sqs" {
+        return None;
+    }
+
+    let region = parts.next()?;
+    let account_id = parts.next()?;
+    let queue_name = parts.next()?;
+
+    if region.is_empty() || account_id.is_empty() || queue_name.is_empty() {
+        return None;
     }
+
+    Some(format!(
+        "https://sqs.{}.{}/{}/{}",
+        region,
+        endpoint::domain(partition, false),
+        account_id,
+        queue_name
+    ))
 }
 
 /// Listener client, first build using [SQSListenerClientBuilder] and start by
 /// calling [`start()`](SQSListenerClient::start())
 ///
-/// Can also be used to manually [`ack()`](SQSListenerClient::ack_message()) messages
-pub struct SQSListenerClient<F: Fn(&Message) + Sync + Send + 'static> {
-    addr: Addr<client::SQSListenerClient<F>>,
-    inner: Option<client::SQSListenerClient<F>>,
+/// Can also be used to manually [`ack()`](SQSListenerClient::ack_message()) messages, clone it
+/// before starting to ack from your handler
+pub struct SQSListenerClient<F: Handler> {
+    // a poller for every listener with its queue url, set on start and shared with the clones
+    pollers: Arc<Mutex<Vec<(String, Addr<client::SQSListenerClient<F>>)>>>,
+    inner: Vec<client::SQSListenerClient<F>>,
+    dispatch_gauge: Arc<client::DispatchGauge>,
+    health_gauges: Vec<Arc<client::HealthGauge>>,
+    metrics: Arc<metrics::Metrics>,
+    shutdown: Arc<shutdown::ShutdownState>,
 }
 
-impl<F: Fn(&Message) + Sync + Send> Clone for SQSListenerClient<F> {
+impl<F: Handler> Clone for SQSListenerClient<F> {
     fn clone(&self) -> Self {
         Self {
-            addr: self.addr.clone(),
-            inner: None,
+            pollers: self.pollers.clone(),
+            inner: Vec::new(),
+            dispatch_gauge: self.dispatch_gauge.clone(),
+            health_gauges: self.health_gauges.clone(),
+            metrics: self.metrics.clone(),
+            shutdown: self.shutdown.clone(),
         }
     }
 }
 
-impl<F: Fn(&Message) + Sync + Send> SQSListenerClient<F> {
-    /// Starts the service, this will run forever until your application exits.
-    pub async fn start(mut self) {
-        self.addr = spawn_actor(self.inner.expect("impossible to not be set"));
-        self.addr.termination().await
+impl SQSListenerClient<StreamHandler> {
+    /// Starts the listener and returns its messages as a [Stream], for listeners created with
+    /// [`SQSListener::new_stream()`]. The messages of every listener are merged into one stream
+    ///
+    /// With `auto_ack`, the default, a message is acked as soon as the stream has room for it,
+    /// before it is processed. Delivery is at most once, a message is lost if the consumer fails
+    /// while processing it. For at least once delivery set `auto_ack` to false, and ack every
+    /// processed message with [`ack_message()`](SQSListenerClient::ack_message) on a clone of the
+    /// client taken before calling `into_stream()`
+    ///
+    /// Failed receives are yielded as errors and the stream carries on. An error is dropped if the
+    /// stream is full, it is logged and passed to `on_error` either way. The stream ends when the
+    /// listener stops, with an error if [`start()`](Self::start) failed
+    ///
+    /// ```rust,ignore
+    /// let acks = client.clone();
+    /// let mut messages = client.into_stream();
+    ///
+    /// while let Some(message) = messages.next().await {
+    ///     match message {
+    ///         Ok(message) => {
+    ///             process(&message).await?;
+    ///             acks.clone().ack_message(message).await?;
+    ///         }
+    ///         Err(error) => eprintln!("{}", error),
+    ///     }
+    /// }
+    /// ```
+    pub fn into_stream(self) -> impl Stream<Item = Result<Message, Error>> + Send + 'static {
+        let receivers: Vec<_> = self
+            .inner
+            .iter()
+            .filter_map(|inner| inner.listener.handler.take_receiver())
+            .map(|receiver| {
+                stream::unfold(receiver, |mut receiver| async move {
+                    let message = receiver.recv().await?;
+                    Some((message, receiver))
+                })
+                .boxed()
+            })
+            .collect();
+
+        let running = tokio::spawn(self.start());
+
+        // the receivers end once the listener stopped and dropped its handlers
+        let stopped = stream::once(running).filter_map(|result| async move {
+            match result {
+                Ok(Err(error)) => Some(Err(error)),
+                _ => None,
+            }
+        });
+
+        stream::select_all(receivers).chain(stopped)
+    }
+}
+
+/// How far behind the listener is, returned by [`dispatch_lag()`](SQSListenerClient::dispatch_lag)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DispatchLag {
+    /// How much later than scheduled the last poll started. Grows when a slow handler or an
+    /// overloaded runtime is delaying polling
+    pub poll_lag: Duration,
+
+    /// Manual acknowledgments waiting for the listener to process them
+    pub pending_acks: usize,
+
+    /// Messages received and not yet handled by the workers
+    pub in_flight: usize,
+}
+
+/// Health of the listener, returned by [`health()`](SQSListenerClient::health), for readiness
+/// and liveness probes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Health {
+    /// Whether the listener is polling, false before it was started and once it stopped
+    pub alive: bool,
+
+    /// When a receive last succeeded, `None` if none has yet
+    pub last_successful_poll: Option<SystemTime>,
+
+    /// Receives that failed in a row since the last successful one
+    pub consecutive_errors: u32,
+}
+
+impl<F: Handler> SQSListenerClient<F> {
+    /// Starts the service, this will run until a [ShutdownHandle] stops it or your application
+    /// exits. Returns [`Error::DrainTimeout`] if the messages in flight at the shutdown were not
+    /// handled within the `drain_timeout`
+    ///
+    /// If `validate_queue` is set in the [Config](ConfigBuilder), the queue is checked before
+    /// polling starts and an error is returned if it is missing or can't be accessed. The same
+    /// request warms up the connection when `preconnect` is set
+    ///
+    /// If `self_test_timeout` is set, a canary message is sent and must be received and acked
+    /// within the timeout, otherwise an error is returned
+    pub async fn start(mut self) -> Result<(), Error> {
+        let shutdown = self.shutdown.clone();
+
+        shutdown.started();
+        let result = self.run().await;
+        shutdown.stopped();
+
+        result
+    }
+
+    async fn run(&mut self) -> Result<(), Error> {
+        let mut listeners = std::mem::take(&mut self.inner);
+        let first = listeners.first().expect("impossible to not be set");
+
+        if first.shutdown_on_signals {
+            shutdown::request_on_signals(&self.shutdown);
+        }
+
+        if self.shutdown.is_requested() {
+            return Ok(());
+        }
+
+        for inner in &mut listeners {
+            if inner.config.validate_queue || inner.config.preconnect {
+                let latency = inner.validate_queue().await?;
+                debug!("Connected to the queue in {:?}", latency);
+            }
+
+            if inner.config.tune_from_queue {
+                inner.tune_from_queue().await?;
+            }
+
+            if let Some(timeout) = inner.config.self_test_timeout {
+                let round_trip = inner.self_test(timeout).await?;
+                info!("Self test passed, canary round trip took {:?}", round_trip);
+            }
+        }
+
+        let startup_delay = listeners[0].startup_delay();
+        if startup_delay > Duration::from_secs(0) {
+            debug!("Delaying first poll by {:?}", startup_delay);
+            tokio::time::sleep(startup_delay).await;
+        }
+
+        if self.shutdown.is_requested() {
+            return Ok(());
+        }
+
+        let drain_timeout = listeners[0].config.drain_timeout;
+
+        let pollers: Vec<_> = listeners
+            .into_iter()
+            .map(|mut inner| {
+                inner.shutdown = Some(self.shutdown.clone());
+                (inner.listener.queue_url.clone(), spawn_actor(inner))
+            })
+            .collect();
+
+        *self.pollers.lock().expect("pollers poisoned") = pollers.clone();
+
+        // the actors stop after the message, so polls in progress finish first
+        let shutdown = self.shutdown.clone();
+        let addrs: Vec<_> = pollers.iter().map(|(_, addr)| addr.downgrade()).collect();
+        tokio::spawn(async move {
+            shutdown.requested().await;
+
+            for addr in addrs {
+                send!(addr.shutdown());
+            }
+        });
+
+        let terminations = join_all(pollers.iter().map(|(_, addr)| addr.termination()));
+
+        tokio::select! {
+            _ = terminations => Ok(()),
+            _ = self.shutdown.drain_elapsed(drain_timeout) => {
+                warn!("Shutting down with messages still in flight after {:?}", drain_timeout);
+                Err(Error::DrainTimeout(drain_timeout))
+            }
+        }
+    }
+
+    // the poller of the listener for `queue_url`, or of the first listener
+    fn poller(&self, queue_url: Option<&str>) -> Addr<client::SQSListenerClient<F>> {
+        let pollers = self.pollers.lock().expect("pollers poisoned");
+
+        let poller = match queue_url {
+            Some(queue_url) => pollers.iter().find(|(url, _)| url == queue_url),
+            None => pollers.first(),
+        };
+
+        poller
+            .map(|(_, addr)| addr.clone())
+            .unwrap_or_else(Addr::detached)
+    }
+
+    /// Handle to stop the listener once started, see [shutdown]
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(self.shutdown.clone())
     }
 
     /// If you set `auto_ack` [Config](ConfigBuilder) option to false, you will need to manually
@@ -201,13 +968,164 @@ impl<F: Fn(&Message) + Sync + Send> SQSListenerClient<F> {
     ///
     /// Use this function to manually acknowledge messages. If `auto_ack` is to true, you will not
     /// need to use this function
+    ///
+    /// With several [`listeners()`](SQSListenerClientBuilder::listeners) the message is
+    /// acknowledged on the queue of the first one, use
+    /// [`ack_message_from()`](SQSListenerClient::ack_message_from) for the others
     pub async fn ack_message(self, message: Message) -> Result<(), Error> {
-        call!(self.addr.ack_message(message))
+        self.ack_on(None, message).await
+    }
+
+    /// Manually acknowledge a message received by the listener of `queue_url`
+    pub async fn ack_message_from(self, queue_url: &str, message: Message) -> Result<(), Error> {
+        self.ack_on(Some(queue_url), message).await
+    }
+
+    /// Manually acknowledge several messages with `DeleteMessageBatch`, 10 per request. Returns
+    /// the result of every message, in order. Messages are acknowledged on the queue of the first
+    /// listener
+    pub async fn ack_messages(self, messages: Vec<Message>) -> Vec<Result<(), Arc<Error>>> {
+        let poller = self.poller(None);
+        let count = messages.len();
+
+        self.dispatch_gauge
+            .pending_acks
+            .fetch_add(count, Ordering::Relaxed);
+
+        let results = call!(poller.ack_messages(messages)).await;
+
+        self.dispatch_gauge
+            .pending_acks
+            .fetch_sub(count, Ordering::Relaxed);
+
+        results.unwrap_or_else(|_err| {
+            let stopped = Arc::new(Error::ListenerStopped);
+            vec![Err(stopped); count]
+        })
+    }
+
+    /// Negatively acknowledge a message received by the listener: it becomes visible again after
+    /// `delay` instead of once its visibility timeout expires, so it is retried sooner. A delay of
+    /// 0 makes it visible right away. The delay is capped at 12 hours
+    ///
+    /// With several [`listeners()`](SQSListenerClientBuilder::listeners) the message is
+    /// returned to the queue of the first one, use
+    /// [`nack_message_from()`](SQSListenerClient::nack_message_from) for the others
+    pub async fn nack_message(self, message: Message, delay: Duration) -> Result<(), Error> {
+        let poller = self.poller(None);
+
+        call!(poller.nack_message(message, delay))
+            .await
+            .map_err(|_err| Error::ListenerStopped)?
+    }
+
+    /// Negatively acknowledge a message received by the listener of `queue_url`
+    pub async fn nack_message_from(
+        self,
+        queue_url: &str,
+        message: Message,
+        delay: Duration,
+    ) -> Result<(), Error> {
+        let poller = self.poller(Some(queue_url));
+
+        call!(poller.nack_message(message, delay))
             .await
-            .map_err(|_err| Error::ListenerStopped)??;
+            .map_err(|_err| Error::ListenerStopped)?
+    }
+
+    /// Apply a new [Config](ConfigBuilder) to the running listeners, from their next poll on, for
+    /// example to change the `check_interval`, `max_messages` or `concurrency` from an admin
+    /// endpoint without a restart. Settings that are only read on start, `workers`,
+    /// `startup_delay`, `startup_jitter`, `validate_queue`, `preconnect`, `tune_from_queue`,
+    /// `self_test_timeout` and `drain_timeout`, keep their value until the next start. Returns
+    /// [`Error::ListenerStopped`] if the listener is not running
+    pub async fn update_config(&self, config: Config) -> Result<(), Error> {
+        let pollers = self.pollers.lock().expect("pollers poisoned").clone();
+
+        if pollers.is_empty() {
+            return Err(Error::ListenerStopped);
+        }
+
+        for (_, poller) in pollers {
+            let config = config.clone();
+
+            call!(poller.update_config(config))
+                .await
+                .map_err(|_err| Error::ListenerStopped)?;
+        }
+
+        Ok(())
+    }
+
+    async fn ack_on(&self, queue_url: Option<&str>, message: Message) -> Result<(), Error> {
+        let poller = self.poller(queue_url);
+
+        self.dispatch_gauge
+            .pending_acks
+            .fetch_add(1, Ordering::Relaxed);
+
+        let result = call!(poller.ack_message(message)).await;
+
+        self.dispatch_gauge
+            .pending_acks
+            .fetch_sub(1, Ordering::Relaxed);
+
+        result.map_err(|_err| Error::ListenerStopped)??;
 
         Ok(())
     }
+
+    /// Run an operation the crate does not wrap yet with the underlying [SqsClient], for example
+    /// `change_message_visibility` or `purge_queue`. Runs inside the listener, so it never overlaps
+    /// with polling or acknowledging
+    ///
+    /// Returns an error if the listener was created with another [Sqs](rusoto_sqs::Sqs)
+    /// implementation than [SqsClient]
+    pub async fn raw<R, Fut, G>(&self, operation: G) -> Result<R, Error>
+    where
+        G: FnOnce(SqsClient) -> Fut + Send + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let poller = self.poller(None);
+
+        call!(poller.raw(operation))
+            .await
+            .map_err(|_err| Error::ListenerStopped)?
+    }
+
+    /// Gauge of how far behind the listener is, use it to detect a slow handler or an overloaded
+    /// runtime delaying polling. Reports the first listener when there are several
+    pub fn dispatch_lag(&self) -> DispatchLag {
+        self.dispatch_gauge.snapshot()
+    }
+
+    /// Whether the listener is polling, when a receive last succeeded and how many failed since.
+    /// With several listeners reports the least healthy: alive only if all of them are, the oldest
+    /// successful receive and the most consecutive errors
+    pub fn health(&self) -> Health {
+        let mut listeners = self.health_gauges.iter().map(|gauge| gauge.snapshot());
+        let first = listeners.next().unwrap_or(Health {
+            alive: false,
+            last_successful_poll: None,
+            consecutive_errors: 0,
+        });
+
+        listeners.fold(first, |health, listener| Health {
+            alive: health.alive && listener.alive,
+            last_successful_poll: health
+                .last_successful_poll
+                .zip(listener.last_successful_poll)
+                .map(|(last, listener_last)| last.min(listener_last)),
+            consecutive_errors: health.consecutive_errors.max(listener.consecutive_errors),
+        })
+    }
+
+    /// Messages received, handled, acked and nacked, handler failures, receive errors and poll
+    /// cycles since the client was built, over all listeners. See [metrics]
+    pub fn stats(&self) -> metrics::Stats {
+        self.metrics.snapshot()
+    }
 }
 
 #[derive(Clone, Builder, Debug)]
@@ -216,13 +1134,197 @@ impl<F: Fn(&Message) + Sync + Send> SQSListenerClient<F> {
 #[builder(build_fn(name = "build_private", private))]
 pub struct Config {
     #[builder(default = "Duration::from_secs(5_u64)")]
-    /// How often to check for new messages, defaults to 5 seconds
+    /// How long to wait before receiving again after a receive returned no messages, defaults to
+    /// 5 seconds. A receive that returned messages is followed by the next one right away
     check_interval: Duration,
 
+    #[builder(default = "None", setter(strip_option))]
+    /// Wait less after an empty receive while messages are flowing, halving the interval after
+    /// every receive that returned messages down to this floor. Setting it or
+    /// `max_check_interval` enables adaptive polling, which starts at `check_interval`. Disabled
+    /// by default
+    min_check_interval: Option<Duration>,
+
+    #[builder(default = "None", setter(strip_option))]
+    /// Poll less often while the queue is idle, doubling the interval after every empty receive up
+    /// to this ceiling. Disabled by default
+    max_check_interval: Option<Duration>,
+
     #[builder(default = "true")]
     /// Determines if messages should be automatically acknowledges.
     /// Defaults to true, if disabled you must manually ack the message by calling [`sqs_listener_client.ack(message)`](SQSListenerClient::ack_message)
     auto_ack: bool,
+
+    #[builder(default = "false")]
+    /// Acknowledge messages with `DeleteMessageBatch`, up to 10 per request, instead of one
+    /// `DeleteMessage` per message. Defaults to false. Acks are buffered until 10 are waiting or
+    /// the `ack_flush_interval` has passed, and flushed on shutdown. Failed entries are retried
+    /// and invalid receipt handles are reported like failed single acks
+    batch_acks: bool,
+
+    #[builder(default = "Duration::from_secs(1_u64)")]
+    /// With `batch_acks`, the longest an ack waits in the buffer, defaults to 1 second. Keep it
+    /// well below the visibility timeout, an ack that is not flushed in time is redelivered
+    ack_flush_interval: Duration,
+
+    #[builder(default = "false")]
+    /// Check that the queue exists and can be accessed before polling starts, defaults to false.
+    /// If enabled [`start()`](SQSListenerClient::start) returns an error instead of polling a queue
+    /// that can't be reached
+    validate_queue: bool,
+
+    #[builder(default = "false")]
+    /// Open the connection to the SQS endpoint on start, so the first receive doesn't pay for DNS
+    /// resolution and the TLS handshake, defaults to false. Implies `validate_queue`. Polling keeps
+    /// the connection warm as long as `check_interval` is shorter than the 90 second idle timeout
+    /// of the connection pool
+    preconnect: bool,
+
+    #[builder(default = "false")]
+    /// Read the visibility timeout and long poll wait time of the queue on start, defaults to
+    /// false. An unset `slow_message_threshold` becomes half the visibility timeout, and settings
+    /// that don't fit the queue are logged as warnings
+    tune_from_queue: bool,
+
+    #[builder(default = "None", setter(strip_option))]
+    /// Run a self test on start, sending a canary message which has to be received and acked
+    /// within this timeout. Proves credentials, permissions and connectivity, disabled by default
+    self_test_timeout: Option<Duration>,
+
+    #[builder(default = "None", setter(strip_option))]
+    /// Periodically send a canary message to the queue, alerting through the
+    /// [`on_canary_missed`](SQSListenerClientBuilder::on_canary_missed) hook if it is not consumed
+    /// within `canary_timeout`. Detects a listener that is running but not processing messages
+    canary_interval: Option<Duration>,
+
+    #[builder(default = "Duration::from_secs(60_u64)")]
+    /// How long a periodic canary may take to be consumed, defaults to 60 seconds
+    canary_timeout: Duration,
+
+    #[builder(default = "None", setter(strip_option))]
+    /// Read the approximate number of visible, in flight and delayed messages of the queue with
+    /// `GetQueueAttributes` this often, reported by [`stats()`](SQSListenerClient::stats) and the
+    /// [`on_queue_depth`](SQSListenerClientBuilder::on_queue_depth) hook. Disabled by default
+    queue_depth_interval: Option<Duration>,
+
+    #[builder(default = "Duration::from_secs(0_u64)")]
+    /// Wait this long after starting before polling, defaults to 0
+    startup_delay: Duration,
+
+    #[builder(default = "Duration::from_secs(0_u64)")]
+    /// Add a random delay of up to this long to `startup_delay`, so a fleet of listeners restarted
+    /// by a deploy don't poll in lockstep. Defaults to 0
+    startup_jitter: Duration,
+
+    #[builder(default = "Duration::from_secs(30_u64)")]
+    /// How long a shutdown waits for the messages in flight to be handled and acked, defaults to
+    /// 30 seconds, the termination grace period of a Kubernetes pod
+    drain_timeout: Duration,
+
+    #[builder(default = "Duration::from_secs(300_u64)")]
+    /// Rebuild the SQS client once receives have failed with connection errors for this long,
+    /// defaults to 5 minutes. Needs a `client_factory`, which the constructors taking a region set
+    rebuild_client_after: Duration,
+
+    #[builder(default = "1")]
+    /// Maximum number of messages to receive per poll, from 1 to 10. Defaults to 1
+    max_messages: i64,
+
+    #[builder(default = "1")]
+    /// Handle up to this many messages of a receive at the same time in each worker, defaults to
+    /// 1. Lets async handlers wait on I/O together, so one slow message doesn't hold up the rest
+    /// of the batch
+    concurrency: usize,
+
+    #[builder(default = "1")]
+    /// Number of workers handling messages, defaults to 1. The listener keeps polling and
+    /// acknowledging while the workers are busy. Messages with the same `MessageGroupId`
+    /// attribute go to the same worker and are handled in order. With 0 workers messages are
+    /// handled by the poller, which doesn't poll again until they are done
+    workers: usize,
+
+    #[builder(default = "false")]
+    /// Keep the order of FIFO queues, enabled automatically for queues whose url ends in `.fifo`.
+    /// Messages of the same `MessageGroupId` are handled one after the other while different
+    /// groups are handled concurrently. After a failure the rest of the group is left on the
+    /// queue, to be redelivered after the failed message. Priorities are ignored
+    fifo: bool,
+
+    #[builder(default = "None", setter(strip_option))]
+    /// Stop receiving while this many messages are waiting for or being handled by the workers,
+    /// defaults to `max_messages` per worker. Received messages stay invisible to other consumers
+    /// while they wait, so keep it well below what can be handled within the visibility timeout
+    max_in_flight: Option<usize>,
+
+    #[builder(default = "None", setter(strip_option))]
+    /// Long poll for up to this many seconds, from 0 to 20, waiting for messages to arrive instead
+    /// of returning empty receives. Uses the `ReceiveMessageWaitTimeSeconds` of the queue by
+    /// default. The listener waits for the receive to return before handling acks, so combine it
+    /// with a short `check_interval`
+    wait_time_seconds: Option<i64>,
+
+    #[builder(default = "None", setter(strip_option))]
+    /// How long received messages stay hidden from other consumers, up to 12 hours. Overrides the
+    /// visibility timeout of the queue for this listener only. Uses the queue's by default
+    visibility_timeout: Option<Duration>,
+
+    #[builder(default = "None", setter(strip_option))]
+    /// Start with a single message per poll after starting, ramping up to `max_messages` over this
+    /// period. Protects cold caches and downstream connection pools when a listener restarts into
+    /// a deep backlog. Disabled by default
+    warm_up: Option<Duration>,
+
+    #[builder(default = "None", setter(strip_option))]
+    /// Warn about messages whose handler takes longer than this, logging the message id, receive
+    /// count and a hash of the body so recurring slow payloads can be identified. Disabled by default
+    slow_message_threshold: Option<Duration>,
+
+    #[builder(default = "Vec::new()")]
+    /// System attributes to request with every message, for example `SentTimestamp`,
+    /// `ApproximateReceiveCount`, `MessageGroupId` or `All`. Defaults to none, request only the
+    /// attributes you need to keep responses small. `ApproximateReceiveCount` is added for the
+    /// `slow_message_threshold` and `tracing`, `MessageGroupId` for `fifo`
+    attribute_names: Vec<String>,
+
+    #[builder(default = "Vec::new()")]
+    /// Message attributes to request with every message, for example `tenant`, `All` or a prefix
+    /// like `tenant.*`. Defaults to none. The `sqs_listener_canary` and
+    /// `sqs_listener_canary_expires_at` attributes of canary messages are always requested, as are
+    /// the `priority_attribute` and, with extended payloads enabled, the `ExtendedPayloadSize` and
+    /// `SQSLargePayloadSize` attributes
+    message_attribute_names: Vec<String>,
+
+    #[builder(default = "None", setter(strip_option))]
+    /// Message attribute that sets the priority of a message, for example `priority`. Messages
+    /// received in the same batch are handled in the order of `priority_values`
+    priority_attribute: Option<String>,
+
+    #[builder(default = "Vec::new()")]
+    /// Values of the `priority_attribute` from highest to lowest priority, for example
+    /// `["high", "low"]`. Messages without a listed value are handled last, in the order received
+    priority_values: Vec<String>,
+
+    #[builder(default = "1.0")]
+    /// Fraction of messages passed to the handler, from 0.0 to 1.0. Defaults to 1.0, every message.
+    /// Useful for shadow consumers or evaluating a new handler against production traffic
+    sample_rate: f64,
+
+    #[builder(default = "1.0")]
+    /// With the `tracing` feature, fraction of messages whose handler runs in a span, from 0.0 to
+    /// 1.0. Defaults to 1.0, every message. Lowers the load on the tracing backend of high
+    /// throughput listeners, errors are logged for every message
+    trace_sample_rate: f64,
+
+    #[builder(default = "true")]
+    /// Acknowledge messages that are not sampled, defaults to true. If disabled they are left on
+    /// the queue for other consumers
+    ack_unsampled: bool,
+
+    #[builder(default = "false")]
+    /// Recompute the MD5 checksums of the body and message attributes and compare them with the
+    /// ones SQS returned, defaults to false. Messages that don't match are not handled or acked
+    /// and their outcome is a failure
+    verify_checksums: bool,
 }
 
 impl ConfigBuilder {
@@ -274,4 +1376,304 @@ mod tests {
 
         assert!(client.is_ok())
     }
+
+    #[test]
+    fn creates_from_env() {
+        let listener = SQSListener::new("".to_string(), |_message| {});
+
+        let client = SQSListenerClientBuilder::from_env()
+            .listener(listener)
+            .build();
+
+        assert!(client.is_ok())
+    }
+
+    #[test]
+    fn finds_named_profiles() {
+        let credentials_file = std::env::temp_dir().join(format!("credentials-{}", unique_id()));
+        std::fs::write(
+            &credentials_file,
+            "[default]\naws_access_key_id = a\n\n[dev]\naws_access_key_id = b\n",
+        )
+        .expect("temp dir is writable");
+
+        assert!(profile_exists(&credentials_file, "dev"));
+        assert!(!profile_exists(&credentials_file, "prod"));
+
+        std::fs::remove_file(&credentials_file).expect("file was created");
+    }
+
+    #[test]
+    fn creates_with_sqs_implementation() {
+        let listener = SQSListener::new("".to_string(), |_message| {});
+
+        let client = SQSListenerClientBuilder::new_with_sqs(SqsClient::new(Region::UsEast1))
+            .listener(listener)
+            .build();
+
+        assert!(client.is_ok())
+    }
+
+    #[test]
+    fn converts_queue_arn_to_url() {
+        let listener = SQSListener::new(
+            "arn:aws:sqs:us-east-1:123456789012:my-queue".to_string(),
+            |_message| {},
+        );
+
+        assert_eq!(
+            listener.queue_url,
+            "https://sqs.us-east-1.amazonaws.com/123456789012/my-queue"
+        );
+
+        let listener = SQSListener::new(
+            "arn:aws-cn:sqs:cn-north-1:123456789012:my-queue".to_string(),
+            |_message| {},
+        );
+
+        assert_eq!(
+            listener.queue_url,
+            "https://sqs.cn-north-1.amazonaws.com.cn/123456789012/my-queue"
+        );
+
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/123456789012/my-queue";
+        let listener = SQSListener::new(queue_url.to_string(), |_message| {});
+
+        assert_eq!(listener.queue_url, queue_url);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shuts_down_after_the_poll_in_progress() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = emulator::SqsEmulator::new();
+        emulator.create_queue(queue_url, Default::default());
+        emulator.push_message(
+            queue_url,
+            Message {
+                body: Some("hello".to_string()),
+                ..Default::default()
+            },
+        );
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counter = handled.clone();
+        let listener = SQSListener::new(queue_url.to_string(), move |_message| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let client = SQSListenerClientBuilder::new_with_emulator(emulator.clone())
+            .listener(listener)
+            .build()
+            .expect("all required fields are set");
+
+        let shutdown = client.shutdown_handle();
+        let running = tokio::spawn(client.start());
+
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        shutdown.shutdown().await;
+
+        assert!(running.await.expect("listener did not panic").is_ok());
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+        assert_eq!(emulator.deleted_messages(queue_url).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn does_not_start_after_shutdown() {
+        let listener = SQSListener::new("queue".to_string(), |_message| {});
+        let client = SQSListenerClientBuilder::new_with_emulator(emulator::SqsEmulator::new())
+            .listener(listener)
+            .build()
+            .expect("all required fields are set");
+
+        let shutdown = client.shutdown_handle();
+        shutdown.shutdown().await;
+
+        assert!(shutdown.is_shutdown_requested());
+        assert!(client.start().await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_waiting_for_messages_after_drain_timeout() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = emulator::SqsEmulator::new();
+        emulator.create_queue(queue_url, Default::default());
+        emulator.push_message(queue_url, Message::default());
+
+        let listener = SQSListener::new_async(queue_url.to_string(), |_message| async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+
+        let config = ConfigBuilder::default()
+            .drain_timeout(Duration::from_secs(1))
+            .build();
+
+        let client = SQSListenerClientBuilder::new_with_emulator(emulator.clone())
+            .listener(listener)
+            .config(config)
+            .build()
+            .expect("all required fields are set");
+
+        let shutdown = client.shutdown_handle();
+        let running = tokio::spawn(client.start());
+
+        tokio::time::sleep(Duration::from_secs(6)).await;
+        shutdown.shutdown().await;
+
+        assert!(matches!(
+            running.await.expect("listener did not panic"),
+            Err(Error::DrainTimeout(_))
+        ));
+        assert!(emulator.deleted_messages(queue_url).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_polling_while_workers_are_busy() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = emulator::SqsEmulator::new();
+        emulator.create_queue(queue_url, Default::default());
+        emulator.push_message(queue_url, Message::default());
+        emulator.push_message(queue_url, Message::default());
+
+        let listener = SQSListener::new_async(queue_url.to_string(), |_message| async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+
+        let config = ConfigBuilder::default()
+            .check_interval(Duration::from_secs(1))
+            .workers(2)
+            .build();
+
+        let client = SQSListenerClientBuilder::new_with_emulator(emulator.clone())
+            .listener(listener)
+            .config(config)
+            .build()
+            .expect("all required fields are set");
+
+        let gauge = client.clone();
+        tokio::spawn(client.start());
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+
+        assert_eq!(gauge.dispatch_lag().in_flight, 2);
+        assert!(emulator.queued_messages(queue_url).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_again_once_a_worker_is_done() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = emulator::SqsEmulator::new();
+        emulator.create_queue(queue_url, Default::default());
+        for _ in 0..5 {
+            emulator.push_message(queue_url, Message::default());
+        }
+
+        let listener = SQSListener::new_async(queue_url.to_string(), |_message| async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        });
+
+        // one worker with room for a single message
+        let config = ConfigBuilder::default()
+            .check_interval(Duration::from_secs(60))
+            .build();
+
+        let client = SQSListenerClientBuilder::new_with_emulator(emulator.clone())
+            .listener(listener)
+            .config(config)
+            .build()
+            .expect("all required fields are set");
+
+        tokio::spawn(client.start());
+        tokio::time::sleep(Duration::from_secs(10)).await;
+
+        assert_eq!(emulator.deleted_messages(queue_url).len(), 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_every_listener() {
+        let orders = "https://sqs.us-east-1.amazonaws.com/000000000000/orders";
+        let refunds = "https://sqs.us-east-1.amazonaws.com/000000000000/refunds";
+
+        let emulator = emulator::SqsEmulator::new();
+        for queue_url in &[orders, refunds] {
+            emulator.create_queue(queue_url, Default::default());
+            emulator.push_message(queue_url, Message::default());
+        }
+
+        let listeners: Vec<SQSListener<Box<dyn Handler>>> = vec![
+            SQSListener::with_handler(orders.to_string(), Box::new(|_message: &Message| {})),
+            SQSListener::with_handler(
+                refunds.to_string(),
+                Box::new(handler::FallibleHandler(|_message: Message| async {
+                    Ok::<(), HandlerError>(())
+                })),
+            ),
+        ];
+
+        let client = SQSListenerClientBuilder::new_with_emulator(emulator.clone())
+            .listeners(listeners)
+            .build()
+            .expect("all required fields are set");
+
+        tokio::spawn(client.start());
+        tokio::time::sleep(Duration::from_secs(6)).await;
+
+        assert_eq!(emulator.deleted_messages(orders).len(), 1);
+        assert_eq!(emulator.deleted_messages(refunds).len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn streams_messages() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+        let emulator = emulator::SqsEmulator::new();
+        emulator.create_queue(queue_url, Default::default());
+        emulator.push_message(
+            queue_url,
+            Message {
+                body: Some("hello".to_string()),
+                ..Default::default()
+            },
+        );
+
+        let client = SQSListenerClientBuilder::new_with_emulator(emulator.clone())
+            .listener(SQSListener::new_stream(queue_url.to_string()))
+            .build()
+            .expect("all required fields are set");
+
+        let shutdown = client.shutdown_handle();
+        let mut messages = Box::pin(client.into_stream());
+
+        let message = messages
+            .next()
+            .await
+            .expect("stream is open")
+            .expect("listener is running");
+        assert_eq!(message.body.as_deref(), Some("hello"));
+
+        shutdown.shutdown().await;
+        assert!(messages.next().await.is_none());
+        assert_eq!(emulator.deleted_messages(queue_url).len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn streams_receive_errors() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+
+        // the queue doesn't exist, so every receive fails
+        let client = SQSListenerClientBuilder::new_with_emulator(emulator::SqsEmulator::new())
+            .listener(SQSListener::new_stream(queue_url.to_string()))
+            .build()
+            .expect("all required fields are set");
+
+        let shutdown = client.shutdown_handle();
+        let mut messages = Box::pin(client.into_stream());
+
+        let error = messages
+            .next()
+            .await
+            .expect("stream is open")
+            .expect_err("receiving fails");
+        assert!(matches!(error, Error::ReceiveMessages(_)));
+
+        shutdown.shutdown().await;
+    }
 }
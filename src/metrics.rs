@@ -0,0 +1,339 @@
+//! Counters of what the listener is doing, read with
+//! [`stats()`](crate::SQSListenerClient::stats)
+//!
+//! ```rust,ignore
+//! let stats = client.stats();
+//! println!("{} received, {} failed", stats.received, stats.handler_failures);
+//! ```
+//!
+//! Set the `queue_depth_interval` [Config](crate::ConfigBuilder) option to also read the number of
+//! messages on the queues with `GetQueueAttributes`, for example to autoscale consumers on the
+//! backlog
+//!
+//! With the `prometheus` feature the counters, the queue depths, and histograms of the receive
+//! latency and handler duration, can be registered with a `prometheus::Registry` to be scraped
+//! with the rest of your application's metrics
+//!
+//! ```rust,ignore
+//! let client = SQSListenerClientBuilder::new(Region::UsEast1)
+//!     .listener(listener)
+//!     .prometheus_registry(&registry)?
+//!     .build()?;
+//! ```
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Counts since the client was built, summed over all of its listeners
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    /// Messages returned by receives
+    pub received: u64,
+
+    /// Messages the handler succeeded for. Every message of a successful batch counts
+    pub handled: u64,
+
+    /// Messages deleted from the queue, automatically or manually
+    pub acked: u64,
+
+    /// Messages that could not be deleted from the queue
+    pub ack_failures: u64,
+
+    /// Messages returned to the queue with
+    /// [`nack_message()`](crate::SQSListenerClient::nack_message)
+    pub nacked: u64,
+
+    /// Messages the handler failed for, after all retries. Every message of a failed batch counts
+    pub handler_failures: u64,
+
+    /// Receives that failed
+    pub receive_errors: u64,
+
+    /// Poll cycles run, including the ones that did not receive because the listener was backing
+    /// off or the workers were busy
+    pub poll_cycles: u64,
+
+    /// Messages available on the queues when their depth was last read, summed over the queues.
+    /// Always 0 unless `queue_depth_interval` is set
+    pub queue_visible: u64,
+
+    /// Messages received and not yet deleted from the queues when their depth was last read,
+    /// summed over the queues. Always 0 unless `queue_depth_interval` is set
+    pub queue_not_visible: u64,
+}
+
+/// Approximate number of messages on a queue, read every `queue_depth_interval`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueueDepth {
+    pub queue_url: String,
+
+    /// Messages available for retrieval, `ApproximateNumberOfMessages`
+    pub visible: u64,
+
+    /// Messages received and not yet deleted, `ApproximateNumberOfMessagesNotVisible`
+    pub not_visible: u64,
+
+    /// Messages not available yet because they were sent with a delay,
+    /// `ApproximateNumberOfMessagesDelayed`
+    pub delayed: u64,
+}
+
+impl QueueDepth {
+    /// Queue attributes to request from `GetQueueAttributes`
+    pub(crate) const ATTRIBUTES: [&'static str; 3] = [
+        "ApproximateNumberOfMessages",
+        "ApproximateNumberOfMessagesNotVisible",
+        "ApproximateNumberOfMessagesDelayed",
+    ];
+
+    pub(crate) fn from_attributes(queue_url: &str, attributes: &HashMap<String, String>) -> Self {
+        let count = |name: &str| {
+            attributes
+                .get(name)
+                .and_then(|count| count.parse().ok())
+                .unwrap_or(0)
+        };
+
+        Self {
+            queue_url: queue_url.to_string(),
+            visible: count(Self::ATTRIBUTES[0]),
+            not_visible: count(Self::ATTRIBUTES[1]),
+            delayed: count(Self::ATTRIBUTES[2]),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    received: AtomicU64,
+    handled: AtomicU64,
+    acked: AtomicU64,
+    ack_failures: AtomicU64,
+    nacked: AtomicU64,
+    handler_failures: AtomicU64,
+    receive_errors: AtomicU64,
+    poll_cycles: AtomicU64,
+
+    // the last depth read of every queue
+    queue_depths: Mutex<HashMap<String, QueueDepth>>,
+
+    #[cfg(feature = "prometheus")]
+    exporter: Option<exporter::Exporter>,
+}
+
+impl Metrics {
+    pub(crate) fn poll_cycle(&self) {
+        self.poll_cycles.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "prometheus")]
+        self.export(|exporter| exporter.poll_cycles.inc());
+    }
+
+    pub(crate) fn received(&self, count: usize) {
+        self.received.fetch_add(count as u64, Ordering::Relaxed);
+
+        #[cfg(feature = "prometheus")]
+        self.export(|exporter| exporter.received.inc_by(count as u64));
+    }
+
+    pub(crate) fn receive_failed(&self) {
+        self.receive_errors.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "prometheus")]
+        self.export(|exporter| exporter.receive_errors.inc());
+    }
+
+    // how long a receive took, failed or not
+    pub(crate) fn receive_latency(&self, _latency: Duration) {
+        #[cfg(feature = "prometheus")]
+        self.export(|exporter| exporter.receive_latency.observe(_latency.as_secs_f64()));
+    }
+
+    pub(crate) fn handled(&self, count: usize) {
+        self.handled.fetch_add(count as u64, Ordering::Relaxed);
+
+        #[cfg(feature = "prometheus")]
+        self.export(|exporter| exporter.handled.inc_by(count as u64));
+    }
+
+    pub(crate) fn handler_failed(&self, count: usize) {
+        self.handler_failures
+            .fetch_add(count as u64, Ordering::Relaxed);
+
+        #[cfg(feature = "prometheus")]
+        self.export(|exporter| exporter.handler_failures.inc_by(count as u64));
+    }
+
+    // how long a handler took including retries, once per call of a batch handler
+    pub(crate) fn handler_duration(&self, _duration: Duration) {
+        #[cfg(feature = "prometheus")]
+        self.export(|exporter| exporter.handler_duration.observe(_duration.as_secs_f64()));
+    }
+
+    pub(crate) fn acked(&self, count: usize) {
+        self.acked.fetch_add(count as u64, Ordering::Relaxed);
+
+        #[cfg(feature = "prometheus")]
+        self.export(|exporter| exporter.acked.inc_by(count as u64));
+    }
+
+    pub(crate) fn ack_failed(&self, count: usize) {
+        self.ack_failures.fetch_add(count as u64, Ordering::Relaxed);
+
+        #[cfg(feature = "prometheus")]
+        self.export(|exporter| exporter.ack_failures.inc_by(count as u64));
+    }
+
+    pub(crate) fn nacked(&self) {
+        self.nacked.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "prometheus")]
+        self.export(|exporter| exporter.nacked.inc());
+    }
+
+    pub(crate) fn queue_depth(&self, queue_depth: &QueueDepth) {
+        self.queue_depths
+            .lock()
+            .expect("queue depths poisoned")
+            .insert(queue_depth.queue_url.clone(), queue_depth.clone());
+
+        #[cfg(feature = "prometheus")]
+        self.export(|exporter| {
+            let labels = [queue_depth.queue_url.as_str()];
+
+            exporter
+                .queue_visible
+                .with_label_values(&labels)
+                .set(queue_depth.visible as i64);
+            exporter
+                .queue_not_visible
+                .with_label_values(&labels)
+                .set(queue_depth.not_visible as i64);
+        });
+    }
+
+    pub(crate) fn snapshot(&self) -> Stats {
+        let queue_depths = self.queue_depths.lock().expect("queue depths poisoned");
+
+        Stats {
+            received: self.received.load(Ordering::Relaxed),
+            handled: self.handled.load(Ordering::Relaxed),
+            acked: self.acked.load(Ordering::Relaxed),
+            ack_failures: self.ack_failures.load(Ordering::Relaxed),
+            nacked: self.nacked.load(Ordering::Relaxed),
+            handler_failures: self.handler_failures.load(Ordering::Relaxed),
+            receive_errors: self.receive_errors.load(Ordering::Relaxed),
+            poll_cycles: self.poll_cycles.load(Ordering::Relaxed),
+            queue_visible: queue_depths.values().map(|depth| depth.visible).sum(),
+            queue_not_visible: queue_depths.values().map(|depth| depth.not_visible).sum(),
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+impl Metrics {
+    // counts are kept in both, so `stats()` works the same with or without a registry
+    pub(crate) fn with_registry(registry: &prometheus::Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            exporter: Some(exporter::Exporter::register(registry)?),
+            ..Default::default()
+        })
+    }
+
+    fn export(&self, export: impl FnOnce(&exporter::Exporter)) {
+        if let Some(exporter) = &self.exporter {
+            export(exporter)
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+mod exporter {
+    use prometheus::{Histogram, HistogramOpts, IntCounter, IntGaugeVec, Opts, Registry};
+
+    pub(super) struct Exporter {
+        pub(super) received: IntCounter,
+        pub(super) handled: IntCounter,
+        pub(super) acked: IntCounter,
+        pub(super) ack_failures: IntCounter,
+        pub(super) nacked: IntCounter,
+        pub(super) handler_failures: IntCounter,
+        pub(super) receive_errors: IntCounter,
+        pub(super) poll_cycles: IntCounter,
+        pub(super) receive_latency: Histogram,
+        pub(super) handler_duration: Histogram,
+        pub(super) queue_visible: IntGaugeVec,
+        pub(super) queue_not_visible: IntGaugeVec,
+    }
+
+    impl Exporter {
+        pub(super) fn register(registry: &Registry) -> prometheus::Result<Self> {
+            let counter = |name: &str, help: &str| -> prometheus::Result<IntCounter> {
+                let counter = IntCounter::new(name, help)?;
+                registry.register(Box::new(counter.clone()))?;
+                Ok(counter)
+            };
+
+            let histogram = |name: &str, help: &str| -> prometheus::Result<Histogram> {
+                let histogram = Histogram::with_opts(HistogramOpts::new(name, help))?;
+                registry.register(Box::new(histogram.clone()))?;
+                Ok(histogram)
+            };
+
+            let queue_gauge = |name: &str, help: &str| -> prometheus::Result<IntGaugeVec> {
+                let gauge = IntGaugeVec::new(Opts::new(name, help), &["queue_url"])?;
+                registry.register(Box::new(gauge.clone()))?;
+                Ok(gauge)
+            };
+
+            Ok(Self {
+                received: counter(
+                    "sqs_listener_messages_received_total",
+                    "Messages returned by receives",
+                )?,
+                handled: counter(
+                    "sqs_listener_messages_handled_total",
+                    "Messages the handler succeeded for",
+                )?,
+                acked: counter(
+                    "sqs_listener_messages_acked_total",
+                    "Messages deleted from the queue",
+                )?,
+                ack_failures: counter(
+                    "sqs_listener_ack_failures_total",
+                    "Messages that could not be deleted from the queue",
+                )?,
+                nacked: counter(
+                    "sqs_listener_messages_nacked_total",
+                    "Messages returned to the queue",
+                )?,
+                handler_failures: counter(
+                    "sqs_listener_handler_failures_total",
+                    "Messages the handler failed for",
+                )?,
+                receive_errors: counter(
+                    "sqs_listener_receive_errors_total",
+                    "Receives that failed",
+                )?,
+                poll_cycles: counter("sqs_listener_poll_cycles_total", "Poll cycles run")?,
+                receive_latency: histogram(
+                    "sqs_listener_receive_duration_seconds",
+                    "How long receives took",
+                )?,
+                handler_duration: histogram(
+                    "sqs_listener_handler_duration_seconds",
+                    "How long handlers took, including retries",
+                )?,
+                queue_visible: queue_gauge(
+                    "sqs_listener_queue_visible_messages",
+                    "Messages available on the queue",
+                )?,
+                queue_not_visible: queue_gauge(
+                    "sqs_listener_queue_not_visible_messages",
+                    "Messages received and not yet deleted from the queue",
+                )?,
+            })
+        }
+    }
+}
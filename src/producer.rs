@@ -0,0 +1,1054 @@
+//! Send messages to a queue
+//!
+//! [BufferedSender] accumulates messages and sends them with `SendMessageBatch`, once 10 messages
+//! are buffered or the oldest message has waited `max_latency`, cutting the number of requests
+//! for chatty producers. Must be created inside a tokio runtime
+//!
+//! On FIFO queues messages of a group are sent in order and share a batch. A message is never
+//! retried behind a later message of its group, it fails instead, as do the messages of the batch
+//! behind a message of their group that failed. The [SentMessage] has the sequence number SQS
+//! assigned
+//!
+//! Interceptors added with [`intercept()`](BufferedSender::intercept) see every message before it
+//! is buffered, to add attributes, validate or transform it
+//!
+//! Messages too large for SQS can be uploaded to S3 with
+//! [`offload_payloads()`](BufferedSender::offload_payloads), the message then carries a pointer to
+//! the payload, see [extended](crate::extended)
+//!
+//! ```rust,ignore
+//! let sender = BufferedSender::new(Region::UsEast1, queue_url, SenderConfigBuilder::default().build());
+//!
+//! let receipt = sender.send("hello".to_string()).await?;
+//! sender.flush().await?;
+//!
+//! let sent = receipt.sent().await?;
+//! println!("Message sent {}", sent.message_id);
+//! ```
+use act_zero::runtimes::tokio::{spawn_actor, Timer};
+use act_zero::timer::Tick;
+use act_zero::*;
+use async_trait::async_trait;
+use derive_builder::Builder;
+use rusoto_core::{Region, RusotoError};
+use rusoto_sqs::{
+    GetQueueAttributesRequest, MessageAttributeValue, SendMessageBatchRequest,
+    SendMessageBatchRequestEntry, Sqs, SqsClient,
+};
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use super::extended::{self, PayloadUploader};
+use super::handler::HandlerError;
+use super::telemetry::{error, warn};
+use super::transport::Transport;
+use super::{queue_url_from_arn, unique_id, Error};
+
+/// Most messages SQS accepts in one `SendMessageBatch` call
+const MAX_BATCH_SIZE: usize = 10;
+
+/// Largest message SQS accepts, body and attributes together
+const MAX_MESSAGE_SIZE: usize = 262_144;
+
+#[derive(Clone, Builder, Debug)]
+#[builder(pattern = "owned")]
+#[builder(build_fn(name = "build_private", private))]
+pub struct SenderConfig {
+    #[builder(default = "Duration::from_millis(100_u64)")]
+    /// How long a message may wait in the buffer before it is sent, defaults to 100 milliseconds
+    max_latency: Duration,
+
+    #[builder(default = "3")]
+    /// How often to retry a throttled or failed send before the message is reported as failed,
+    /// defaults to 3
+    max_retries: u32,
+
+    #[builder(default = "Duration::from_millis(100_u64)")]
+    /// How long to wait before the first retry, doubled for every further retry. Defaults to 100
+    /// milliseconds
+    retry_delay: Duration,
+
+    #[builder(default = "false")]
+    /// Give every FIFO message without a deduplication id a generated one, even when the queue has
+    /// content-based deduplication. Messages with the same body are then all delivered. Queues
+    /// without content-based deduplication always get generated ids, defaults to false
+    deduplication_ids: bool,
+}
+
+impl SenderConfigBuilder {
+    pub fn build(self) -> SenderConfig {
+        self.build_private()
+            .expect("will always work because all fields have defaults")
+    }
+}
+
+/// A message that was sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct SentMessage {
+    pub message_id: String,
+
+    /// Only set for FIFO queues
+    pub sequence_number: Option<String>,
+}
+
+/// Resolves once the batch containing the message has been sent
+#[derive(Debug)]
+pub struct Receipt(oneshot::Receiver<Result<SentMessage, Arc<Error>>>);
+
+impl Receipt {
+    /// Wait until the message is sent, a batch failure is shared by all of its messages
+    pub async fn sent(self) -> Result<SentMessage, Arc<Error>> {
+        self.0
+            .await
+            .unwrap_or_else(|_| Err(Arc::new(Error::ProducerStopped)))
+    }
+}
+
+/// Buffers messages and sends them in batches, unsent messages are flushed when the last clone
+/// is dropped
+#[derive(Clone)]
+pub struct BufferedSender {
+    addr: Addr<Buffer>,
+    interceptors: Vec<
+        Arc<dyn Fn(&mut SendMessageBatchRequestEntry) -> Result<(), HandlerError> + Send + Sync>,
+    >,
+    payload_uploader: Option<Arc<dyn PayloadUploader>>,
+}
+
+impl BufferedSender {
+    /// Create a sender using the default AWS client, `queue` can either be the queue url or the
+    /// queue ARN
+    pub fn new(region: Region, queue: String, config: SenderConfig) -> Self {
+        Self::new_with_sqs(SqsClient::new(region), queue, config)
+    }
+
+    /// Create a sender with any [Sqs] implementation
+    pub fn new_with_sqs(sqs: impl Sqs + 'static, queue: String, config: SenderConfig) -> Self {
+        Self::new_with_transport(Arc::new(sqs), queue, config)
+    }
+
+    /// Create a sender on the official [aws-sdk-sqs](https://docs.rs/aws-sdk-sqs) client, enable
+    /// with the `aws-sdk` feature
+    #[cfg(feature = "aws-sdk")]
+    pub fn new_with_aws_sdk(
+        client: aws_sdk_sqs::Client,
+        queue: String,
+        config: SenderConfig,
+    ) -> Self {
+        Self::new_with_transport(
+            Arc::new(crate::aws_sdk::AwsSdkTransport::new(client)),
+            queue,
+            config,
+        )
+    }
+
+    /// Create a sender for a queue of an in process [SqsEmulator](crate::emulator::SqsEmulator)
+    #[cfg(any(test, feature = "emulator"))]
+    pub fn new_with_emulator(
+        emulator: crate::emulator::SqsEmulator,
+        queue: String,
+        config: SenderConfig,
+    ) -> Self {
+        Self::new_with_transport(Arc::new(emulator), queue, config)
+    }
+
+    fn new_with_transport(client: Arc<dyn Transport>, queue: String, config: SenderConfig) -> Self {
+        let buffer = Buffer {
+            client,
+            queue_url: queue_url_from_arn(&queue).unwrap_or(queue),
+            config,
+            pending: Vec::new(),
+            deduplication_ids: None,
+            timer: Timer::default(),
+            pid: WeakAddr::detached(),
+        };
+
+        Self {
+            addr: spawn_actor(buffer),
+            interceptors: Vec::new(),
+            payload_uploader: None,
+        }
+    }
+
+    /// Run `interceptor` on every message before it is buffered, after the interceptors added
+    /// before it. It can add attributes like the trace context or a tenant id, validate or
+    /// transform the message, an error rejects the message with [`Error::MessageRejected`]
+    pub fn intercept(
+        mut self,
+        interceptor: impl Fn(&mut SendMessageBatchRequestEntry) -> Result<(), HandlerError>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Upload the payload of messages larger than the 256 KiB SQS accepts with `uploader`, for
+    /// example [S3Uploads](crate::extended::S3Uploads), and send a pointer to it in the format of
+    /// the SQS Extended Client instead. Listeners with `extended_payloads` download it again
+    pub fn offload_payloads(mut self, uploader: impl PayloadUploader + 'static) -> Self {
+        self.payload_uploader = Some(Arc::new(uploader));
+        self
+    }
+
+    /// Buffer a message with this body
+    pub async fn send(&self, body: String) -> Result<Receipt, Error> {
+        self.send_entry(SendMessageBatchRequestEntry {
+            message_body: body,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Buffer a message with attributes, delay or FIFO ids, the entry id is set by the sender
+    pub async fn send_entry(
+        &self,
+        mut entry: SendMessageBatchRequestEntry,
+    ) -> Result<Receipt, Error> {
+        for interceptor in &self.interceptors {
+            interceptor(&mut entry).map_err(Error::MessageRejected)?;
+        }
+
+        if let Some(uploader) = &self.payload_uploader {
+            if message_size(&entry) > MAX_MESSAGE_SIZE {
+                offload_payload(&**uploader, &mut entry).await?;
+            }
+        }
+
+        let (sender, receiver) = oneshot::channel();
+
+        call!(self.addr.push(Pending { entry, sender }))
+            .await
+            .map_err(|_err| Error::ProducerStopped)?;
+
+        Ok(Receipt(receiver))
+    }
+
+    /// Send all buffered messages now
+    pub async fn flush(&self) -> Result<(), Error> {
+        call!(self.addr.flush())
+            .await
+            .map_err(|_err| Error::ProducerStopped)
+    }
+}
+
+// the size SQS counts against its limit, the body and the name, type and value of every attribute
+fn message_size(entry: &SendMessageBatchRequestEntry) -> usize {
+    let attributes: usize = entry
+        .message_attributes
+        .iter()
+        .flatten()
+        .map(|(name, value)| {
+            name.len()
+                + value.data_type.len()
+                + value.string_value.as_ref().map_or(0, String::len)
+                + value.binary_value.as_ref().map_or(0, |binary| binary.len())
+        })
+        .sum();
+
+    entry.message_body.len() + attributes
+}
+
+// replaces the body with a pointer to the uploaded payload, the size attribute tells listeners the
+// body is a pointer
+async fn offload_payload(
+    uploader: &dyn PayloadUploader,
+    entry: &mut SendMessageBatchRequestEntry,
+) -> Result<(), Error> {
+    let pointer = uploader.put(&entry.message_body).await?;
+
+    let size = MessageAttributeValue {
+        data_type: "Number".to_string(),
+        string_value: Some(entry.message_body.len().to_string()),
+        ..Default::default()
+    };
+
+    entry
+        .message_attributes
+        .get_or_insert_with(HashMap::new)
+        .insert(extended::PAYLOAD_SIZE_ATTRIBUTES[0].to_string(), size);
+    entry.message_body = extended::pointer_body(&pointer);
+
+    Ok(())
+}
+
+struct Pending {
+    entry: SendMessageBatchRequestEntry,
+    sender: oneshot::Sender<Result<SentMessage, Arc<Error>>>,
+}
+
+struct Buffer {
+    client: Arc<dyn Transport>,
+    queue_url: String,
+    config: SenderConfig,
+    pending: Vec<Pending>,
+    // whether FIFO messages need generated deduplication ids, looked up on the first send
+    deduplication_ids: Option<bool>,
+    timer: Timer,
+    pid: WeakAddr<Self>,
+}
+
+#[async_trait]
+impl Actor for Buffer {
+    async fn started(&mut self, pid: Addr<Self>) -> ActorResult<()> {
+        self.pid = pid.downgrade();
+        Produces::ok(())
+    }
+
+    async fn error(&mut self, error: ActorError) -> bool {
+        error!("BufferedSender Error: {:?}", error);
+
+        // do not stop on actor error
+        false
+    }
+}
+
+#[async_trait]
+impl Tick for Buffer {
+    async fn tick(&mut self) -> ActorResult<()> {
+        if self.timer.tick() {
+            self.send_all().await;
+        }
+        Produces::ok(())
+    }
+}
+
+impl Buffer {
+    async fn push(&mut self, pending: Pending) -> ActorResult<()> {
+        // the oldest message sets the deadline of the batch
+        if self.pending.is_empty() {
+            self.timer
+                .set_timeout_for_weak(self.pid.clone(), self.config.max_latency);
+        }
+
+        self.pending.push(pending);
+
+        if self.pending.len() >= MAX_BATCH_SIZE {
+            self.send_all().await;
+        }
+
+        Produces::ok(())
+    }
+
+    async fn flush(&mut self) -> ActorResult<()> {
+        self.send_all().await;
+        Produces::ok(())
+    }
+
+    async fn send_all(&mut self) {
+        self.timer.clear();
+
+        let pending = mem::take(&mut self.pending);
+
+        let deduplication_ids = match self.deduplication_ids {
+            Some(deduplication_ids) => deduplication_ids,
+            None => {
+                let deduplication_ids =
+                    needs_deduplication_ids(&*self.client, &self.queue_url, &self.config).await;
+                self.deduplication_ids = Some(deduplication_ids);
+                deduplication_ids
+            }
+        };
+
+        send_batches(
+            self.client.clone(),
+            self.queue_url.clone(),
+            self.config.clone(),
+            deduplication_ids,
+            pending,
+        )
+        .await;
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let pending = mem::take(&mut self.pending);
+        let client = self.client.clone();
+        let queue_url = self.queue_url.clone();
+        let config = self.config.clone();
+        let deduplication_ids = self.deduplication_ids;
+
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    let deduplication_ids = match deduplication_ids {
+                        Some(deduplication_ids) => deduplication_ids,
+                        None => needs_deduplication_ids(&*client, &queue_url, &config).await,
+                    };
+
+                    send_batches(client, queue_url, config, deduplication_ids, pending).await
+                });
+            }
+            Err(_) => error!(
+                "BufferedSender dropped outside of a tokio runtime, {} messages were not sent",
+                pending.len()
+            ),
+        }
+    }
+}
+
+// FIFO messages need a deduplication id unless the queue derives it from the body, if the queue
+// can't be read the ids are generated since SQS rejects FIFO messages without one otherwise
+async fn needs_deduplication_ids(
+    client: &dyn Transport,
+    queue_url: &str,
+    config: &SenderConfig,
+) -> bool {
+    if !queue_url.ends_with(".fifo") {
+        return false;
+    }
+
+    if config.deduplication_ids {
+        return true;
+    }
+
+    let request = GetQueueAttributesRequest {
+        queue_url: queue_url.to_string(),
+        attribute_names: Some(vec!["ContentBasedDeduplication".to_string()]),
+    };
+
+    match client.get_queue_attributes(request).await {
+        Ok(result) => {
+            let attributes = result.attributes.unwrap_or_default();
+            attributes
+                .get("ContentBasedDeduplication")
+                .map(String::as_str)
+                != Some("true")
+        }
+        Err(error) => {
+            warn!(
+                "Error when reading ContentBasedDeduplication, generating deduplication ids: {}",
+                Error::from(error)
+            );
+            true
+        }
+    }
+}
+
+// batches are sent one after the other, each only once its retries are done, so messages are
+// never reordered across batches
+async fn send_batches(
+    client: Arc<dyn Transport>,
+    queue_url: String,
+    config: SenderConfig,
+    deduplication_ids: bool,
+    mut pending: Vec<Pending>,
+) {
+    while !pending.is_empty() {
+        let rest = pending.split_off(pending.len().min(MAX_BATCH_SIZE));
+        let batch = mem::replace(&mut pending, rest);
+
+        send_batch(&*client, &queue_url, &config, deduplication_ids, batch).await;
+    }
+}
+
+// the entry ids are the positions in the batch, to match results to receipts. Throttled calls and
+// entries that failed on the AWS side are retried, entries rejected as invalid are not. SQS keeps
+// the order of a FIFO group within a batch, so an entry is not retried once a later entry of its
+// group was sent, and the retries of a group stop at its first entry that failed for good
+async fn send_batch(
+    client: &dyn Transport,
+    queue_url: &str,
+    config: &SenderConfig,
+    deduplication_ids: bool,
+    batch: Vec<Pending>,
+) {
+    let mut senders = Vec::with_capacity(batch.len());
+    let mut groups = Vec::with_capacity(batch.len());
+    let mut entries = Vec::with_capacity(batch.len());
+    let mut failed_indexes = Vec::new();
+
+    for (index, Pending { mut entry, sender }) in batch.into_iter().enumerate() {
+        entry.id = index.to_string();
+
+        // the id stays the same across retries, so SQS drops a retried message that was already
+        // sent. With content-based deduplication the unchanged body does the same
+        if deduplication_ids && entry.message_deduplication_id.is_none() {
+            entry.message_deduplication_id = Some(unique_id());
+        }
+
+        groups.push(entry.message_group_id.clone());
+        entries.push(entry);
+        senders.push(Some(sender));
+    }
+
+    let mut retry_delay = config.retry_delay;
+
+    for attempt in 0..=config.max_retries {
+        let last_attempt = attempt == config.max_retries;
+
+        if attempt > 0 {
+            tokio::time::sleep(retry_delay).await;
+            retry_delay *= 2;
+        }
+
+        let result = client
+            .send_message_batch(SendMessageBatchRequest {
+                entries: entries.clone(),
+                queue_url: queue_url.to_string(),
+            })
+            .await;
+
+        let result = match result {
+            Ok(result) => result,
+            Err(error) if is_retryable(&error) && !last_attempt => {
+                warn!("Error when sending messages, retrying: {}", error);
+                continue;
+            }
+            Err(error) => {
+                let error = Arc::new(Error::from(error));
+                error!("Error when sending messages: {}", error);
+
+                for sender in senders.iter_mut().filter_map(Option::take) {
+                    let _ = sender.send(Err(error.clone()));
+                }
+
+                return;
+            }
+        };
+
+        let mut sent_indexes = Vec::new();
+
+        for sent in result.successful {
+            if let Some((index, sender)) = take_sender(&mut senders, &sent.id) {
+                sent_indexes.push(index);
+
+                let _ = sender.send(Ok(SentMessage {
+                    message_id: sent.message_id,
+                    sequence_number: sent.sequence_number,
+                }));
+            }
+        }
+
+        let mut retry_indexes = Vec::new();
+
+        for failed in result.failed {
+            let index = match failed.id.parse::<usize>() {
+                Ok(index) if index < senders.len() => index,
+                _ => continue,
+            };
+
+            // a retry would land behind the later message of its group that was just sent
+            let overtaken = groups[index].is_some()
+                && sent_indexes
+                    .iter()
+                    .any(|&sent| sent > index && groups[sent] == groups[index]);
+
+            if !failed.sender_fault && !last_attempt && !overtaken {
+                retry_indexes.push(index);
+                continue;
+            }
+
+            let error = Arc::new(Error::SendMessageFailed {
+                code: failed.code,
+                message: failed.message.unwrap_or_default(),
+            });
+
+            if let Some(sender) = senders[index].take() {
+                let _ = sender.send(Err(error.clone()));
+            }
+
+            failed_indexes.push((index, error));
+        }
+
+        // the rest of a group is not sent past an earlier message of the group that failed
+        retry_indexes.retain(|&index| {
+            let failed = failed_indexes.iter().find(|(failed, _)| {
+                *failed < index && groups[index].is_some() && groups[*failed] == groups[index]
+            });
+
+            match failed {
+                Some((_, error)) => {
+                    if let Some(sender) = senders[index].take() {
+                        let _ = sender.send(Err(error.clone()));
+                    }
+                    false
+                }
+                None => true,
+            }
+        });
+
+        if retry_indexes.is_empty() {
+            return;
+        }
+
+        warn!("{} messages were not sent, retrying", retry_indexes.len());
+        entries.retain(|entry| {
+            entry
+                .id
+                .parse()
+                .map_or(false, |index: usize| retry_indexes.contains(&index))
+        });
+    }
+}
+
+fn take_sender<T>(senders: &mut [Option<T>], id: &str) -> Option<(usize, T)> {
+    let index = id.parse::<usize>().ok()?;
+    let sender = senders.get_mut(index)?.take()?;
+    Some((index, sender))
+}
+
+// connection errors, server errors and throttling are transient
+pub(crate) fn is_retryable<E>(error: &RusotoError<E>) -> bool {
+    match error {
+        RusotoError::HttpDispatch(_) => true,
+        RusotoError::Unknown(response) => {
+            response.status.is_server_error() || response.body_as_str().contains("Throttl")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{QueueOptions, SqsEmulator};
+    use crate::extended::S3Pointer;
+    use rusoto_sqs::{
+        BatchResultErrorEntry, ChangeMessageVisibilityError, ChangeMessageVisibilityRequest,
+        DeleteMessageBatchError, DeleteMessageBatchRequest, DeleteMessageBatchResult,
+        DeleteMessageError, DeleteMessageRequest, GetQueueAttributesError,
+        GetQueueAttributesRequest, GetQueueAttributesResult, ReceiveMessageError,
+        ReceiveMessageRequest, ReceiveMessageResult, SendMessageBatchError, SendMessageBatchResult,
+        SendMessageBatchResultEntry, SendMessageError, SendMessageRequest, SendMessageResult,
+    };
+    use std::sync::Mutex;
+
+    // fails every entry but the reliable ones once on the AWS side, and records the sent entries
+    #[derive(Default)]
+    struct FlakyTransport {
+        sent: Mutex<Vec<SendMessageBatchRequestEntry>>,
+        reliable_bodies: Vec<&'static str>,
+        content_based_deduplication: bool,
+    }
+
+    #[async_trait]
+    impl Transport for FlakyTransport {
+        async fn receive_message(
+            &self,
+            _input: ReceiveMessageRequest,
+        ) -> Result<ReceiveMessageResult, RusotoError<ReceiveMessageError>> {
+            Ok(Default::default())
+        }
+
+        async fn delete_message(
+            &self,
+            _input: DeleteMessageRequest,
+        ) -> Result<(), RusotoError<DeleteMessageError>> {
+            Ok(Default::default())
+        }
+
+        async fn delete_message_batch(
+            &self,
+            _input: DeleteMessageBatchRequest,
+        ) -> Result<DeleteMessageBatchResult, RusotoError<DeleteMessageBatchError>> {
+            Ok(Default::default())
+        }
+
+        async fn change_message_visibility(
+            &self,
+            _input: ChangeMessageVisibilityRequest,
+        ) -> Result<(), RusotoError<ChangeMessageVisibilityError>> {
+            Ok(Default::default())
+        }
+
+        async fn send_message(
+            &self,
+            _input: SendMessageRequest,
+        ) -> Result<SendMessageResult, RusotoError<SendMessageError>> {
+            Ok(Default::default())
+        }
+
+        async fn send_message_batch(
+            &self,
+            input: SendMessageBatchRequest,
+        ) -> Result<SendMessageBatchResult, RusotoError<SendMessageBatchError>> {
+            let mut sent = self.sent.lock().unwrap();
+            let mut result = SendMessageBatchResult::default();
+
+            for entry in input.entries {
+                let retried = sent
+                    .iter()
+                    .any(|sent| sent.message_deduplication_id == entry.message_deduplication_id);
+
+                if retried || self.reliable_bodies.contains(&entry.message_body.as_str()) {
+                    result.successful.push(SendMessageBatchResultEntry {
+                        id: entry.id.clone(),
+                        message_id: unique_id(),
+                        ..Default::default()
+                    });
+                } else {
+                    result.failed.push(BatchResultErrorEntry {
+                        id: entry.id.clone(),
+                        code: "InternalError".to_string(),
+                        sender_fault: false,
+                        ..Default::default()
+                    });
+                }
+
+                sent.push(entry);
+            }
+
+            Ok(result)
+        }
+
+        async fn get_queue_attributes(
+            &self,
+            _input: GetQueueAttributesRequest,
+        ) -> Result<GetQueueAttributesResult, RusotoError<GetQueueAttributesError>> {
+            let mut attributes = std::collections::HashMap::new();
+            attributes.insert(
+                "ContentBasedDeduplication".to_string(),
+                self.content_based_deduplication.to_string(),
+            );
+
+            Ok(GetQueueAttributesResult {
+                attributes: Some(attributes),
+            })
+        }
+    }
+
+    // keeps the uploaded payloads in memory
+    #[derive(Default)]
+    struct MemoryUploader {
+        payloads: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl PayloadUploader for MemoryUploader {
+        async fn put(&self, payload: &str) -> Result<S3Pointer, Error> {
+            let mut payloads = self.payloads.lock().unwrap();
+            payloads.push(payload.to_string());
+
+            Ok(S3Pointer {
+                s3_bucket_name: "payloads".to_string(),
+                s3_key: (payloads.len() - 1).to_string(),
+            })
+        }
+    }
+
+    const QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+
+    fn sender() -> (BufferedSender, SqsEmulator) {
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(QUEUE_URL, QueueOptions::default());
+
+        let sender = BufferedSender::new_with_emulator(
+            emulator.clone(),
+            QUEUE_URL.to_string(),
+            SenderConfigBuilder::default().build(),
+        );
+
+        (sender, emulator)
+    }
+
+    #[tokio::test]
+    async fn sends_full_batches_and_flushes() {
+        let (sender, emulator) = sender();
+
+        let mut receipts = Vec::new();
+        for index in 0..11 {
+            receipts.push(
+                sender
+                    .send(index.to_string())
+                    .await
+                    .expect("sender is running"),
+            );
+        }
+
+        assert_eq!(emulator.queued_messages(QUEUE_URL).len(), 10);
+
+        sender.flush().await.expect("sender is running");
+        assert_eq!(emulator.queued_messages(QUEUE_URL).len(), 11);
+
+        for receipt in receipts {
+            assert!(receipt.sent().await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn intercepts_messages_before_buffering() {
+        let (sender, emulator) = sender();
+
+        let sender = sender
+            .intercept(|entry| {
+                if entry.message_body.is_empty() {
+                    return Err("empty body".into());
+                }
+
+                Ok(())
+            })
+            .intercept(|entry| {
+                let tenant = MessageAttributeValue {
+                    data_type: "String".to_string(),
+                    string_value: Some("acme".to_string()),
+                    ..Default::default()
+                };
+
+                entry
+                    .message_attributes
+                    .get_or_insert_with(Default::default)
+                    .insert("tenant".to_string(), tenant);
+
+                Ok(())
+            });
+
+        assert!(matches!(
+            sender.send(String::new()).await,
+            Err(Error::MessageRejected(_))
+        ));
+
+        let receipt = sender
+            .send("hello".to_string())
+            .await
+            .expect("sender is running");
+        sender.flush().await.expect("sender is running");
+        assert!(receipt.sent().await.is_ok());
+
+        let queued = emulator.queued_messages(QUEUE_URL);
+        assert_eq!(queued.len(), 1);
+
+        let tenant = queued[0]
+            .message_attributes
+            .as_ref()
+            .and_then(|attributes| attributes.get("tenant"))
+            .and_then(|tenant| tenant.string_value.clone());
+        assert_eq!(tenant.as_deref(), Some("acme"));
+    }
+
+    #[tokio::test]
+    async fn offloads_payloads_too_large_for_a_message() {
+        let (sender, emulator) = sender();
+        let sender = sender.offload_payloads(MemoryUploader::default());
+
+        let large = "x".repeat(MAX_MESSAGE_SIZE + 1);
+
+        for body in vec!["small".to_string(), large] {
+            sender.send(body).await.expect("sender is running");
+        }
+        sender.flush().await.expect("sender is running");
+
+        let queued = emulator.queued_messages(QUEUE_URL);
+        assert_eq!(queued[0].body.as_deref(), Some("small"));
+        assert_eq!(extended::pointer(&queued[0]), None);
+
+        assert_eq!(
+            extended::pointer(&queued[1]),
+            Some(S3Pointer {
+                s3_bucket_name: "payloads".to_string(),
+                s3_key: "0".to_string(),
+            })
+        );
+
+        let size = queued[1]
+            .message_attributes
+            .as_ref()
+            .and_then(|attributes| attributes.get("ExtendedPayloadSize"))
+            .and_then(|size| size.string_value.clone());
+        assert_eq!(size, Some((MAX_MESSAGE_SIZE + 1).to_string()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_after_max_latency() {
+        let (sender, emulator) = sender();
+
+        let receipt = sender
+            .send("hello".to_string())
+            .await
+            .expect("sender is running");
+        assert!(emulator.queued_messages(QUEUE_URL).is_empty());
+
+        // waiting for the receipt lets the paused clock advance to the deadline
+        assert!(receipt.sent().await.is_ok());
+        assert_eq!(emulator.queued_messages(QUEUE_URL).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reports_failed_batches() {
+        // the queue does not exist on this emulator
+        let sender = BufferedSender::new_with_emulator(
+            SqsEmulator::new(),
+            QUEUE_URL.to_string(),
+            SenderConfigBuilder::default().build(),
+        );
+
+        let receipt = sender
+            .send("hello".to_string())
+            .await
+            .expect("sender is running");
+        sender.flush().await.expect("sender is running");
+
+        assert!(receipt.sent().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_fifo_group_order_and_returns_sequence_numbers() {
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/queue.fifo";
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(queue_url, QueueOptions::default());
+
+        let sender = BufferedSender::new_with_emulator(
+            emulator.clone(),
+            queue_url.to_string(),
+            SenderConfigBuilder::default().build(),
+        );
+
+        let mut receipts = Vec::new();
+        for (group, body) in [("a", "a1"), ("a", "a2"), ("b", "b1"), ("a", "a3")].iter() {
+            let entry = SendMessageBatchRequestEntry {
+                message_body: body.to_string(),
+                message_group_id: Some(group.to_string()),
+                ..Default::default()
+            };
+
+            receipts.push(sender.send_entry(entry).await.expect("sender is running"));
+        }
+
+        sender.flush().await.expect("sender is running");
+
+        let bodies: Vec<_> = emulator
+            .queued_messages(queue_url)
+            .into_iter()
+            .filter_map(|message| message.body)
+            .collect();
+
+        assert_eq!(bodies, vec!["a1", "a2", "b1", "a3"]);
+
+        let mut sequence_numbers = Vec::new();
+        for receipt in receipts {
+            let sent = receipt.sent().await.expect("message was sent");
+            let sequence_number = sent.sequence_number.expect("queue is a FIFO queue");
+            sequence_numbers.push(sequence_number.parse::<u64>().unwrap());
+        }
+
+        assert!(sequence_numbers[0] < sequence_numbers[1]);
+        assert!(sequence_numbers[1] < sequence_numbers[3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn never_retries_a_message_behind_a_later_one_of_its_group() {
+        let transport = Arc::new(FlakyTransport {
+            reliable_bodies: vec!["a2"],
+            ..Default::default()
+        });
+
+        let sender = BufferedSender::new_with_transport(
+            transport.clone(),
+            "https://sqs.us-east-1.amazonaws.com/000000000000/queue.fifo".to_string(),
+            SenderConfigBuilder::default().build(),
+        );
+
+        let mut receipts = Vec::new();
+        for (group, body) in [("a", "a1"), ("a", "a2"), ("b", "b1"), ("b", "b2")].iter() {
+            let entry = SendMessageBatchRequestEntry {
+                message_body: body.to_string(),
+                message_group_id: Some(group.to_string()),
+                ..Default::default()
+            };
+
+            receipts.push(sender.send_entry(entry).await.expect("sender is running"));
+        }
+
+        sender.flush().await.expect("sender is running");
+
+        let mut sent = Vec::new();
+        for receipt in receipts {
+            sent.push(receipt.sent().await.is_ok());
+        }
+
+        // a2 was sent before a1 could be retried, both messages of group b were retried
+        assert_eq!(sent, vec![false, true, true, true]);
+        assert_eq!(transport.sent.lock().unwrap().len(), 6);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_failed_entries_with_the_same_deduplication_id() {
+        let transport = Arc::new(FlakyTransport::default());
+
+        let sender = BufferedSender::new_with_transport(
+            transport.clone(),
+            "https://sqs.us-east-1.amazonaws.com/000000000000/queue.fifo".to_string(),
+            SenderConfigBuilder::default().build(),
+        );
+
+        let receipt = sender
+            .send("hello".to_string())
+            .await
+            .expect("sender is running");
+        sender.flush().await.expect("sender is running");
+
+        assert!(receipt.sent().await.is_ok());
+
+        let sent = transport.sent.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[0].message_deduplication_id.is_some());
+        assert_eq!(
+            sent[0].message_deduplication_id,
+            sent[1].message_deduplication_id
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn leaves_deduplication_to_content_based_queues() {
+        let transport = Arc::new(FlakyTransport {
+            content_based_deduplication: true,
+            ..Default::default()
+        });
+
+        let sender = BufferedSender::new_with_transport(
+            transport.clone(),
+            "https://sqs.us-east-1.amazonaws.com/000000000000/queue.fifo".to_string(),
+            SenderConfigBuilder::default().build(),
+        );
+
+        let receipt = sender
+            .send("hello".to_string())
+            .await
+            .expect("sender is running");
+        sender.flush().await.expect("sender is running");
+
+        assert!(receipt.sent().await.is_ok());
+
+        let sent = transport.sent.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        assert!(sent
+            .iter()
+            .all(|entry| entry.message_deduplication_id.is_none()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn generates_deduplication_ids_when_asked_to() {
+        let transport = Arc::new(FlakyTransport {
+            content_based_deduplication: true,
+            ..Default::default()
+        });
+
+        let sender = BufferedSender::new_with_transport(
+            transport.clone(),
+            "https://sqs.us-east-1.amazonaws.com/000000000000/queue.fifo".to_string(),
+            SenderConfigBuilder::default()
+                .deduplication_ids(true)
+                .build(),
+        );
+
+        let receipt = sender
+            .send("hello".to_string())
+            .await
+            .expect("sender is running");
+        sender.flush().await.expect("sender is running");
+
+        assert!(receipt.sent().await.is_ok());
+
+        let sent = transport.sent.lock().unwrap();
+        assert!(sent[0].message_deduplication_id.is_some());
+    }
+}
@@ -0,0 +1,183 @@
+//! Redaction of message bodies, to log messages without leaking personal data
+//!
+//! The listener never logs message bodies itself, use a [Redaction] when logging messages from
+//! your handler instead of debug-printing the [Message]
+//!
+//! ```rust,ignore
+//! let redaction = RedactionBuilder::default()
+//!     .max_body_length(64)
+//!     .json_fields(vec!["email".to_string(), "phone".to_string()])
+//!     .build();
+//!
+//! let listener = SQSListener::new(queue_url, move |message| {
+//!     info!("Message received {:?}", redaction.message(message))
+//! });
+//! ```
+use derive_builder::Builder;
+use rusoto_sqs::Message;
+use serde_json::Value;
+use std::fmt;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// How to redact message bodies, defaults to leaving them untouched
+#[derive(Clone, Builder, Debug, Default)]
+#[builder(pattern = "owned")]
+#[builder(build_fn(name = "build_private", private))]
+pub struct Redaction {
+    #[builder(default = "false")]
+    /// Replace the whole body with its length, defaults to false
+    mask_body: bool,
+
+    #[builder(default = "None", setter(strip_option))]
+    /// Truncate bodies longer than this many bytes, disabled by default
+    max_body_length: Option<usize>,
+
+    #[builder(default = "Vec::new()")]
+    /// Mask the values of these fields at any depth of JSON bodies, bodies that are not JSON are
+    /// left as they are. Applied before truncating
+    json_fields: Vec<String>,
+}
+
+impl RedactionBuilder {
+    pub fn build(self) -> Redaction {
+        self.build_private()
+            .expect("will always work because all fields have defaults")
+    }
+}
+
+impl Redaction {
+    /// The redacted body
+    pub fn body(&self, body: &str) -> String {
+        if self.mask_body {
+            return format!("{} ({} bytes)", REDACTED, body.len());
+        }
+
+        let mut body = self.redact_json_fields(body);
+
+        if let Some(max_length) = self.max_body_length {
+            if body.len() > max_length {
+                let total_length = body.len();
+
+                let mut end = max_length;
+                while !body.is_char_boundary(end) {
+                    end -= 1;
+                }
+
+                body.truncate(end);
+                body.push_str(&format!("... ({} bytes)", total_length));
+            }
+        }
+
+        body
+    }
+
+    /// Wraps the message to debug-print it with its body redacted, message attributes are left
+    /// out except for their names
+    pub fn message<'a>(&'a self, message: &'a Message) -> RedactedMessage<'a> {
+        RedactedMessage {
+            message,
+            redaction: self,
+        }
+    }
+
+    fn redact_json_fields(&self, body: &str) -> String {
+        if self.json_fields.is_empty() {
+            return body.to_string();
+        }
+
+        match serde_json::from_str::<Value>(body) {
+            Ok(mut value) => {
+                self.redact_value(&mut value);
+                value.to_string()
+            }
+            Err(_) => body.to_string(),
+        }
+    }
+
+    fn redact_value(&self, value: &mut Value) {
+        match value {
+            Value::Object(fields) => {
+                for (name, field) in fields.iter_mut() {
+                    if self.json_fields.contains(name) {
+                        *field = Value::String(REDACTED.to_string());
+                    } else {
+                        self.redact_value(field);
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(|value| self.redact_value(value)),
+            _ => {}
+        }
+    }
+}
+
+/// A message with its body redacted when debug-printed, see [`Redaction::message()`]
+pub struct RedactedMessage<'a> {
+    message: &'a Message,
+    redaction: &'a Redaction,
+}
+
+impl fmt::Debug for RedactedMessage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = self.message;
+
+        let attribute_names = message
+            .message_attributes
+            .as_ref()
+            .map(|attributes| attributes.keys().collect::<Vec<_>>());
+
+        f.debug_struct("Message")
+            .field("message_id", &message.message_id)
+            .field("attributes", &message.attributes)
+            .field(
+                "body",
+                &message
+                    .body
+                    .as_deref()
+                    .map(|body| self.redaction.body(body)),
+            )
+            .field("message_attributes", &attribute_names)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_json_fields_and_truncates() {
+        let redaction = RedactionBuilder::default()
+            .json_fields(vec!["email".to_string()])
+            .build();
+
+        assert_eq!(
+            redaction.body(r#"{"id":1,"user":{"email":"jane@example.com"}}"#),
+            r#"{"id":1,"user":{"email":"[REDACTED]"}}"#
+        );
+        assert_eq!(redaction.body("not json"), "not json");
+
+        let redaction = RedactionBuilder::default().max_body_length(5).build();
+        assert_eq!(redaction.body("héllo world"), "héll... (12 bytes)");
+        assert_eq!(redaction.body("hi"), "hi");
+
+        let redaction = RedactionBuilder::default().mask_body(true).build();
+        assert_eq!(redaction.body("secret"), "[REDACTED] (6 bytes)");
+    }
+
+    #[test]
+    fn debug_prints_redacted_message() {
+        let redaction = RedactionBuilder::default().mask_body(true).build();
+        let message = Message {
+            message_id: Some("id".to_string()),
+            body: Some("secret".to_string()),
+            ..Default::default()
+        };
+
+        let printed = format!("{:?}", redaction.message(&message));
+
+        assert!(printed.contains("[REDACTED] (6 bytes)"));
+        assert!(!printed.contains("secret"));
+    }
+}
@@ -0,0 +1,383 @@
+//! Move messages from a dead-letter queue back to the queue they came from, once the bug that
+//! made them fail is fixed
+//!
+//! ```rust,ignore
+//! let sqs = SqsClient::new(Region::UsEast1);
+//!
+//! let options = RedriveOptionsBuilder::default()
+//!     .max_messages(1000)
+//!     .messages_per_second(50)
+//!     .build();
+//!
+//! let report = redrive_dlq(&sqs, dlq_url, queue_url, options).await?;
+//! println!("{} messages moved, {} failed", report.moved, report.failed);
+//!
+//! // or find the dead-letter queue from the RedrivePolicy of the queue
+//! let report = redrive_to_source(&sqs, queue_url, options).await?;
+//! ```
+use derive_builder::Builder;
+use rusoto_sqs::{
+    GetQueueAttributesRequest, Message, ReceiveMessageRequest, SendMessageBatchRequest,
+    SendMessageBatchRequestEntry, Sqs,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::acker;
+use super::telemetry::{info, warn};
+use super::transport::Transport;
+use super::{queue_url_from_arn, Error};
+
+#[derive(Clone, Builder, Debug)]
+#[builder(pattern = "owned")]
+#[builder(build_fn(name = "build_private", private))]
+pub struct RedriveOptions {
+    #[builder(default = "None", setter(strip_option))]
+    /// Stop after moving this many messages, defaults to every message of the dead-letter queue
+    max_messages: Option<usize>,
+
+    #[builder(default = "None", setter(strip_option))]
+    /// Move at most this many messages per second, so the consumers of the queue are not flooded.
+    /// Defaults to as fast as possible
+    messages_per_second: Option<u32>,
+
+    #[builder(default = "1")]
+    /// How long to wait for messages, from 0 to 20 seconds. The redrive ends with the first
+    /// receive that returns no messages. Defaults to 1 second
+    wait_time_seconds: i64,
+}
+
+impl RedriveOptionsBuilder {
+    pub fn build(self) -> RedriveOptions {
+        self.build_private()
+            .expect("will always work because all fields have defaults")
+    }
+}
+
+/// What a redrive did
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RedriveReport {
+    /// Messages sent to the target queue and deleted from the dead-letter queue
+    pub moved: usize,
+
+    /// Messages that could not be sent or deleted, they stay on the dead-letter queue. A message
+    /// that was sent but not deleted is on both queues
+    pub failed: usize,
+}
+
+/// Move the messages of the dead-letter queue to the target queue, keeping their body, message
+/// attributes and, for FIFO queues, their group
+pub async fn redrive_dlq(
+    sqs: &(impl Sqs + 'static),
+    dlq_url: &str,
+    target_queue_url: &str,
+    options: RedriveOptions,
+) -> Result<RedriveReport, Error> {
+    redrive(sqs, dlq_url, target_queue_url, &options).await
+}
+
+/// Move the messages of the dead-letter queue of `queue_url` back to it, the dead-letter queue is
+/// found from the `RedrivePolicy` of the queue
+pub async fn redrive_to_source(
+    sqs: &(impl Sqs + 'static),
+    queue_url: &str,
+    options: RedriveOptions,
+) -> Result<RedriveReport, Error> {
+    let dlq_url = dead_letter_queue_url(sqs, queue_url)
+        .await?
+        .ok_or_else(|| Error::NoDeadLetterQueue(queue_url.to_string()))?;
+
+    redrive(sqs, &dlq_url, queue_url, &options).await
+}
+
+/// Finds the dead-letter queues of queues from their `RedrivePolicy`, so the dead-letter queue
+/// is only configured once, on the queue. Results are cached for the `refresh_interval`
+pub struct DeadLetterQueues {
+    refresh_interval: Duration,
+    cache: Mutex<HashMap<String, (Option<String>, Instant)>>,
+}
+
+impl DeadLetterQueues {
+    pub fn new(refresh_interval: Duration) -> Self {
+        Self {
+            refresh_interval,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The url of the dead-letter queue of `queue_url`, `None` if it has no redrive policy
+    pub async fn resolve(
+        &self,
+        sqs: &(impl Sqs + 'static),
+        queue_url: &str,
+    ) -> Result<Option<String>, Error> {
+        self.resolve_with(sqs, queue_url).await
+    }
+
+    pub(crate) async fn resolve_with(
+        &self,
+        client: &dyn Transport,
+        queue_url: &str,
+    ) -> Result<Option<String>, Error> {
+        if let Some((dlq_url, resolved_at)) = self.cache().get(queue_url) {
+            if resolved_at.elapsed() < self.refresh_interval {
+                return Ok(dlq_url.clone());
+            }
+        }
+
+        let dlq_url = dead_letter_queue_url(client, queue_url).await?;
+
+        self.cache()
+            .insert(queue_url.to_string(), (dlq_url.clone(), Instant::now()));
+
+        Ok(dlq_url)
+    }
+
+    fn cache(&self) -> std::sync::MutexGuard<'_, HashMap<String, (Option<String>, Instant)>> {
+        self.cache.lock().expect("dead-letter queue cache poisoned")
+    }
+}
+
+impl Default for DeadLetterQueues {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(5 * 60))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RedrivePolicy {
+    dead_letter_target_arn: String,
+}
+
+// SQS returns the `maxReceiveCount` of a redrive policy as a number or as a string
+pub(crate) fn max_receive_count(redrive_policy: &str) -> Option<u64> {
+    let redrive_policy: serde_json::Value = serde_json::from_str(redrive_policy).ok()?;
+
+    match redrive_policy.get("maxReceiveCount")? {
+        serde_json::Value::Number(count) => count.as_u64(),
+        serde_json::Value::String(count) => count.parse().ok(),
+        _ => None,
+    }
+}
+
+async fn dead_letter_queue_url(
+    client: &dyn Transport,
+    queue_url: &str,
+) -> Result<Option<String>, Error> {
+    let attributes = client
+        .get_queue_attributes(GetQueueAttributesRequest {
+            queue_url: queue_url.to_string(),
+            attribute_names: Some(vec!["RedrivePolicy".to_string()]),
+        })
+        .await?
+        .attributes
+        .unwrap_or_default();
+
+    let redrive_policy = match attributes.get("RedrivePolicy") {
+        Some(redrive_policy) => redrive_policy,
+        None => return Ok(None),
+    };
+
+    let invalid = || Error::InvalidRedrivePolicy(redrive_policy.to_string());
+
+    let redrive_policy: RedrivePolicy =
+        serde_json::from_str(redrive_policy).map_err(|_| invalid())?;
+
+    queue_url_from_arn(&redrive_policy.dead_letter_target_arn)
+        .map(Some)
+        .ok_or_else(invalid)
+}
+
+async fn redrive(
+    client: &dyn Transport,
+    dlq_url: &str,
+    target_queue_url: &str,
+    options: &RedriveOptions,
+) -> Result<RedriveReport, Error> {
+    let started_at = Instant::now();
+    let mut report = RedriveReport::default();
+
+    loop {
+        let remaining = options.max_messages.map_or(acker::MAX_BATCH_SIZE, |max| {
+            max.saturating_sub(report.moved)
+        });
+
+        if remaining == 0 {
+            break;
+        }
+
+        let messages = client
+            .receive_message(ReceiveMessageRequest {
+                queue_url: dlq_url.to_string(),
+                attribute_names: Some(vec!["MessageGroupId".to_string()]),
+                message_attribute_names: Some(vec!["All".to_string()]),
+                max_number_of_messages: Some(remaining.min(acker::MAX_BATCH_SIZE) as i64),
+                wait_time_seconds: Some(options.wait_time_seconds.clamp(0, 20)),
+                ..Default::default()
+            })
+            .await?
+            .messages
+            .unwrap_or_default();
+
+        if messages.is_empty() {
+            break;
+        }
+
+        let (moved, failed) = move_batch(client, dlq_url, target_queue_url, messages).await?;
+        report.moved += moved;
+        report.failed += failed;
+
+        if let Some(messages_per_second) = options.messages_per_second {
+            let due =
+                Duration::from_secs_f64(report.moved as f64 / messages_per_second.max(1) as f64);
+            let elapsed = started_at.elapsed();
+
+            if due > elapsed {
+                tokio::time::sleep(due - elapsed).await;
+            }
+        }
+    }
+
+    info!(
+        "Redrive from {} to {} done, {} messages moved, {} failed",
+        dlq_url, target_queue_url, report.moved, report.failed
+    );
+
+    Ok(report)
+}
+
+// sends the messages to the target queue and deletes the ones that were sent, returns the number
+// of messages moved and failed
+async fn move_batch(
+    client: &dyn Transport,
+    dlq_url: &str,
+    target_queue_url: &str,
+    messages: Vec<Message>,
+) -> Result<(usize, usize), Error> {
+    let fifo = target_queue_url.ends_with(".fifo");
+
+    let entries = messages
+        .iter()
+        .enumerate()
+        .map(|(index, message)| SendMessageBatchRequestEntry {
+            id: index.to_string(),
+            message_body: message.body.clone().unwrap_or_default(),
+            message_attributes: message.message_attributes.clone(),
+            message_group_id: message
+                .attributes
+                .as_ref()
+                .and_then(|attributes| attributes.get("MessageGroupId"))
+                .cloned()
+                .filter(|_| fifo),
+            // a retried redrive of the same message is dropped by FIFO queues
+            message_deduplication_id: message.message_id.clone().filter(|_| fifo),
+            ..Default::default()
+        })
+        .collect();
+
+    let result = client
+        .send_message_batch(SendMessageBatchRequest {
+            entries,
+            queue_url: target_queue_url.to_string(),
+        })
+        .await?;
+
+    for failed in &result.failed {
+        warn!(
+            "Message was not redriven: {} {}",
+            failed.code,
+            failed.message.as_deref().unwrap_or_default()
+        );
+    }
+
+    let receipt_handles = result
+        .successful
+        .iter()
+        .filter_map(|sent| sent.id.parse::<usize>().ok())
+        .filter_map(|index| messages.get(index)?.receipt_handle.clone())
+        .collect();
+
+    let deleted = acker::delete_all(client, dlq_url, receipt_handles).await;
+    let moved = deleted.iter().filter(|result| result.is_ok()).count();
+
+    for error in deleted.iter().filter_map(|result| result.as_ref().err()) {
+        warn!(
+            "Redriven message was not deleted from the dead-letter queue: {}",
+            error
+        );
+    }
+
+    Ok((moved, messages.len() - moved))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{QueueOptions, RedrivePolicy as EmulatorRedrivePolicy, SqsEmulator};
+
+    const QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/000000000000/queue";
+    const DLQ_URL: &str = "https://sqs.us-east-1.amazonaws.com/000000000000/dlq";
+
+    #[tokio::test(start_paused = true)]
+    async fn moves_messages_back_to_the_source_queue() {
+        let emulator = SqsEmulator::new();
+        emulator.create_queue(DLQ_URL, QueueOptions::default());
+        emulator.create_queue(
+            QUEUE_URL,
+            QueueOptions {
+                redrive_policy: Some(EmulatorRedrivePolicy {
+                    dead_letter_queue_url: DLQ_URL.to_string(),
+                    max_receive_count: 3,
+                }),
+                ..Default::default()
+            },
+        );
+
+        for _ in 0..15 {
+            emulator.push_message(
+                DLQ_URL,
+                Message {
+                    body: Some("failed".to_string()),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let dead_letter_queues = DeadLetterQueues::default();
+        let dlq_url = dead_letter_queues
+            .resolve_with(&emulator, QUEUE_URL)
+            .await
+            .unwrap();
+        assert_eq!(dlq_url.as_deref(), Some(DLQ_URL));
+
+        let options = RedriveOptionsBuilder::default().max_messages(12).build();
+        let report = redrive(&emulator, DLQ_URL, QUEUE_URL, &options)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            report,
+            RedriveReport {
+                moved: 12,
+                failed: 0
+            }
+        );
+        assert_eq!(emulator.queued_messages(QUEUE_URL).len(), 12);
+        assert_eq!(emulator.deleted_messages(DLQ_URL).len(), 12);
+    }
+
+    #[test]
+    fn reads_max_receive_count_as_number_or_string() {
+        let number = r#"{"deadLetterTargetArn":"arn","maxReceiveCount":5}"#;
+        let string = r#"{"deadLetterTargetArn":"arn","maxReceiveCount":"5"}"#;
+        let missing = r#"{"deadLetterTargetArn":"arn"}"#;
+
+        assert_eq!(max_receive_count(number), Some(5));
+        assert_eq!(max_receive_count(string), Some(5));
+        assert_eq!(max_receive_count(missing), None);
+    }
+}
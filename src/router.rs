@@ -0,0 +1,512 @@
+//! Routers pass every message to the handler of the first route whose [Matcher] matches it,
+//! replacing dispatch on message attributes inside a handler with a table of routes
+//!
+//! ```rust,ignore
+//! let router = Router::new()
+//!     .route(Matcher::equals("type", "order.created"), OrderCreated::new(db))
+//!     .route(
+//!         Matcher::prefix("type", "refund.").and(!Matcher::equals("region", "us")),
+//!         Refunds::new(db),
+//!     )
+//!     .fallback(|message: &Message| warn!("No route for {:?}", message.message_id));
+//!
+//! for (index, matcher) in router.routes().enumerate() {
+//!     info!("Route {}: {}", index, matcher);
+//! }
+//!
+//! let listener = SQSListener::with_handler(queue_url, router);
+//! ```
+//!
+//! Matchers only see the message attributes that are received, request them with the
+//! `message_attribute_names` [Config](crate::ConfigBuilder) option. Messages of producers that set
+//! no attributes can be routed on a [JsonPath] of their JSON body, or with the `regex` feature on a
+//! regular expression
+//!
+//! ```rust,ignore
+//! let router = Router::new()
+//!     .route(Matcher::json_path("$.detail-type", "Order Created")?, Orders::new(db))
+//!     .route(Matcher::body_regex(r#""kind":\s*"refund""#)?, Refunds::new(db))
+//!     .fallback(|_message: &Message| {});
+//! ```
+use async_trait::async_trait;
+use rusoto_sqs::Message;
+use serde_json::Value;
+use std::fmt;
+use std::ops::Not;
+
+use super::{Error, Handler, HandlerError};
+
+/// Decides whether a message takes a route, from its message attributes or its body
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Matcher {
+    /// The message attribute has this value
+    Equals { attribute: String, value: String },
+
+    /// The value of the message attribute starts with this prefix
+    Prefix { attribute: String, prefix: String },
+
+    /// The message has the attribute, whatever its value
+    Exists(String),
+
+    /// Every matcher matches
+    All(Vec<Matcher>),
+
+    /// At least one of the matchers matches
+    Any(Vec<Matcher>),
+
+    /// The matcher does not match
+    Not(Box<Matcher>),
+
+    /// The JSON body has this value at the path
+    JsonPath { path: JsonPath, value: Value },
+
+    /// The body of the message matches the regular expression
+    #[cfg(feature = "regex")]
+    BodyRegex(regex::Regex),
+}
+
+impl Matcher {
+    /// Matches messages whose `attribute` is `value`
+    pub fn equals(attribute: &str, value: &str) -> Self {
+        Matcher::Equals {
+            attribute: attribute.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    /// Matches messages whose `attribute` starts with `prefix`
+    pub fn prefix(attribute: &str, prefix: &str) -> Self {
+        Matcher::Prefix {
+            attribute: attribute.to_string(),
+            prefix: prefix.to_string(),
+        }
+    }
+
+    /// Matches messages that have `attribute`
+    pub fn exists(attribute: &str) -> Self {
+        Matcher::Exists(attribute.to_string())
+    }
+
+    /// Matches messages whose JSON body has `value` at `path`, for example
+    /// `Matcher::json_path("$.detail-type", "Order Created")`
+    pub fn json_path(path: &str, value: impl Into<Value>) -> Result<Self, Error> {
+        Ok(Matcher::JsonPath {
+            path: JsonPath::parse(path)?,
+            value: value.into(),
+        })
+    }
+
+    /// Matches messages whose body matches the regular expression `pattern`, which is compiled
+    /// once here instead of for every message
+    #[cfg(feature = "regex")]
+    pub fn body_regex(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Matcher::BodyRegex(regex::Regex::new(pattern)?))
+    }
+
+    /// Matches messages both matchers match
+    pub fn and(self, other: Matcher) -> Self {
+        match self {
+            Matcher::All(mut matchers) => {
+                matchers.push(other);
+                Matcher::All(matchers)
+            }
+            matcher => Matcher::All(vec![matcher, other]),
+        }
+    }
+
+    /// Matches messages either matcher matches
+    pub fn or(self, other: Matcher) -> Self {
+        match self {
+            Matcher::Any(mut matchers) => {
+                matchers.push(other);
+                Matcher::Any(matchers)
+            }
+            matcher => Matcher::Any(vec![matcher, other]),
+        }
+    }
+
+    /// Returns true if the message takes the route of this matcher
+    pub fn matches(&self, message: &Message) -> bool {
+        match self {
+            Matcher::Equals { attribute, value } => {
+                attribute_value(message, attribute) == Some(value.as_str())
+            }
+            Matcher::Prefix { attribute, prefix } => attribute_value(message, attribute)
+                .map_or(false, |value| value.starts_with(prefix.as_str())),
+            Matcher::Exists(attribute) => message
+                .message_attributes
+                .as_ref()
+                .map_or(false, |attributes| attributes.contains_key(attribute)),
+            Matcher::All(matchers) => matchers.iter().all(|matcher| matcher.matches(message)),
+            Matcher::Any(matchers) => matchers.iter().any(|matcher| matcher.matches(message)),
+            Matcher::Not(matcher) => !matcher.matches(message),
+            Matcher::JsonPath { path, value } => message
+                .body
+                .as_deref()
+                .and_then(|body| serde_json::from_str::<Value>(body).ok())
+                .map_or(false, |body| path.select(&body) == Some(value)),
+            #[cfg(feature = "regex")]
+            Matcher::BodyRegex(regex) => message
+                .body
+                .as_deref()
+                .map_or(false, |body| regex.is_match(body)),
+        }
+    }
+}
+
+impl Not for Matcher {
+    type Output = Matcher;
+
+    fn not(self) -> Self::Output {
+        match self {
+            Matcher::Not(matcher) => *matcher,
+            matcher => Matcher::Not(Box::new(matcher)),
+        }
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::Equals { attribute, value } => write!(f, "{} = {:?}", attribute, value),
+            Matcher::Prefix { attribute, prefix } => {
+                write!(f, "{} starts with {:?}", attribute, prefix)
+            }
+            Matcher::Exists(attribute) => write!(f, "{} exists", attribute),
+            Matcher::All(matchers) => join(f, matchers, "and"),
+            Matcher::Any(matchers) => join(f, matchers, "or"),
+            Matcher::Not(matcher) => write!(f, "not {}", matcher),
+            Matcher::JsonPath { path, value } => write!(f, "{} = {}", path, value),
+            #[cfg(feature = "regex")]
+            Matcher::BodyRegex(regex) => write!(f, "body matches /{}/", regex),
+        }
+    }
+}
+
+fn join(f: &mut fmt::Formatter<'_>, matchers: &[Matcher], operator: &str) -> fmt::Result {
+    write!(f, "(")?;
+
+    for (index, matcher) in matchers.iter().enumerate() {
+        if index > 0 {
+            write!(f, " {} ", operator)?;
+        }
+
+        write!(f, "{}", matcher)?;
+    }
+
+    write!(f, ")")
+}
+
+/// Path of a single value of a JSON document, `$.detail-type`, `$.detail.items[0]` or
+/// `$['detail-type']`. Wildcards, filters, slices and recursive descent select more than one value
+/// and are not supported
+#[derive(Debug, Clone)]
+pub struct JsonPath {
+    path: String,
+    // the path as a JSON pointer, `/detail/items/0`
+    pointer: String,
+}
+
+impl JsonPath {
+    /// Parse `path`, failing on the expressions that select more than one value
+    pub fn parse(path: &str) -> Result<Self, Error> {
+        let invalid = |reason| Error::InvalidJsonPath {
+            path: path.to_string(),
+            reason,
+        };
+
+        let mut rest = path
+            .strip_prefix('$')
+            .ok_or_else(|| invalid("it does not start with $"))?;
+
+        let mut pointer = String::new();
+
+        while !rest.is_empty() {
+            let (segment, remaining) = if let Some(member) = rest.strip_prefix('.') {
+                let end = member
+                    .find(|c: char| c == '.' || c == '[')
+                    .unwrap_or(member.len());
+                (&member[..end], &member[end..])
+            } else if let Some(index) = rest.strip_prefix('[') {
+                let end = index
+                    .find(']')
+                    .ok_or_else(|| invalid("a [ is not closed"))?;
+                let segment = &index[..end];
+
+                let quoted = (segment.starts_with('\'') && segment.ends_with('\''))
+                    || (segment.starts_with('"') && segment.ends_with('"'));
+
+                if quoted && segment.len() >= 2 {
+                    (&segment[1..segment.len() - 1], &index[end + 1..])
+                } else if !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()) {
+                    (segment, &index[end + 1..])
+                } else {
+                    return Err(invalid("only member names and indexes can be selected"));
+                }
+            } else {
+                return Err(invalid("expected . or ["));
+            };
+
+            if segment.is_empty() || segment == "*" {
+                return Err(invalid("only member names and indexes can be selected"));
+            }
+
+            pointer.push('/');
+            pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+            rest = remaining;
+        }
+
+        Ok(Self {
+            path: path.to_string(),
+            pointer,
+        })
+    }
+
+    /// The value at the path, if the document has one
+    pub fn select<'a>(&self, document: &'a Value) -> Option<&'a Value> {
+        document.pointer(&self.pointer)
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
+// string and number attributes, binary attributes have no string value
+fn attribute_value<'a>(message: &'a Message, attribute: &str) -> Option<&'a str> {
+    message
+        .message_attributes
+        .as_ref()?
+        .get(attribute)?
+        .string_value
+        .as_deref()
+}
+
+/// Handler passing every message to the handler of the first route that matches it, or to the
+/// fallback. A message no route matches fails without a fallback, so it is redelivered and
+/// eventually moved to the dead-letter queue instead of being lost
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<(Matcher, Box<dyn Handler>)>,
+    fallback: Option<Box<dyn Handler>>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pass the messages `matcher` matches to `handler`, routes are tried in the order they were
+    /// added
+    pub fn route(mut self, matcher: Matcher, handler: impl Handler) -> Self {
+        self.routes.push((matcher, Box::new(handler)));
+        self
+    }
+
+    /// Pass the messages no route matches to `handler`
+    pub fn fallback(mut self, handler: impl Handler) -> Self {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    /// The matchers of the routes, in the order they are tried
+    pub fn routes(&self) -> impl Iterator<Item = &Matcher> {
+        self.routes.iter().map(|(matcher, _handler)| matcher)
+    }
+
+    /// Returns true if messages no route matches go to a fallback
+    pub fn has_fallback(&self) -> bool {
+        self.fallback.is_some()
+    }
+
+    fn handler(&self, message: &Message) -> Option<&dyn Handler> {
+        self.routes
+            .iter()
+            .find(|(matcher, _handler)| matcher.matches(message))
+            .map(|(_matcher, handler)| &**handler)
+            .or_else(|| self.fallback.as_deref())
+    }
+}
+
+#[async_trait]
+impl Handler for Router {
+    async fn handle(&self, message: &Message) -> Result<(), HandlerError> {
+        match self.handler(message) {
+            Some(handler) => handler.handle(message).await,
+            None => Err(format!(
+                "no route matches message {}",
+                message.message_id.as_deref().unwrap_or_default()
+            )
+            .into()),
+        }
+    }
+
+    // the matchers only read the message, so it takes the same route again
+    async fn after_ack(&self, message: &Message, acked: bool) {
+        if let Some(handler) = self.handler(message) {
+            handler.after_ack(message, acked).await
+        }
+    }
+
+    fn keeps_receipt_handles(&self) -> bool {
+        self.routes
+            .iter()
+            .map(|(_matcher, handler)| handler)
+            .chain(&self.fallback)
+            .any(|handler| handler.keeps_receipt_handles())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_sqs::MessageAttributeValue;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    fn message(attributes: &[(&str, &str)]) -> Message {
+        let message_attributes: HashMap<_, _> = attributes
+            .iter()
+            .map(|(name, value)| {
+                let value = MessageAttributeValue {
+                    data_type: "String".to_string(),
+                    string_value: Some(value.to_string()),
+                    ..Default::default()
+                };
+
+                (name.to_string(), value)
+            })
+            .collect();
+
+        Message {
+            message_attributes: Some(message_attributes),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn matches_combined_attributes() {
+        let matcher = Matcher::prefix("type", "refund.")
+            .and(!Matcher::equals("region", "us"))
+            .or(Matcher::exists("urgent"));
+
+        assert!(matcher.matches(&message(&[("type", "refund.created"), ("region", "eu")])));
+        assert!(matcher.matches(&message(&[("type", "refund.created")])));
+        assert!(matcher.matches(&message(&[("type", "order.created"), ("urgent", "yes")])));
+        assert!(!matcher.matches(&message(&[("type", "refund.created"), ("region", "us")])));
+        assert!(!matcher.matches(&message(&[("type", "order.created")])));
+
+        assert_eq!(
+            matcher.to_string(),
+            r#"((type starts with "refund." and not region = "us") or urgent exists)"#
+        );
+    }
+
+    #[test]
+    fn selects_values_at_json_paths() {
+        let document = serde_json::json!({
+            "detail-type": "Order Created",
+            "detail": {"items": [{"sku": "a/1"}], "a/b": true},
+        });
+
+        let select = |path| JsonPath::parse(path).unwrap().select(&document).cloned();
+
+        assert_eq!(select("$.detail-type"), Some("Order Created".into()));
+        assert_eq!(select("$['detail-type']"), Some("Order Created".into()));
+        assert_eq!(select("$.detail.items[0].sku"), Some("a/1".into()));
+        assert_eq!(select(r#"$.detail["a/b"]"#), Some(true.into()));
+        assert_eq!(select("$"), Some(document.clone()));
+        assert_eq!(select("$.detail.items[1]"), None);
+
+        for path in &[
+            "detail",
+            "$..sku",
+            "$.detail.*",
+            "$.items[*]",
+            "$[0:2]",
+            "$.items[0",
+        ] {
+            assert!(JsonPath::parse(path).is_err(), "{} is not supported", path);
+        }
+
+        let matcher = Matcher::json_path("$.detail-type", "Order Created").unwrap();
+        let body = |body: &str| Message {
+            body: Some(body.to_string()),
+            ..Default::default()
+        };
+
+        assert!(matcher.matches(&body(r#"{"detail-type": "Order Created"}"#)));
+        assert!(!matcher.matches(&body(r#"{"detail-type": "Order Paid"}"#)));
+        assert!(!matcher.matches(&body("not json")));
+        assert_eq!(matcher.to_string(), r#"$.detail-type = "Order Created""#);
+    }
+
+    #[cfg(feature = "regex")]
+    #[tokio::test]
+    async fn routes_bodies_on_a_regex() {
+        let matcher = Matcher::body_regex(r#""kind":\s*"refund""#).unwrap();
+        assert!(Matcher::body_regex("(unclosed").is_err());
+
+        let body = |body: &str| Message {
+            body: Some(body.to_string()),
+            ..Default::default()
+        };
+
+        assert!(matcher.matches(&body(r#"{"kind": "refund", "amount": 10}"#)));
+        assert!(!matcher.matches(&body(r#"{"kind": "order"}"#)));
+        assert!(!matcher.matches(&Message::default()));
+        assert_eq!(matcher.to_string(), r#"body matches /"kind":\s*"refund"/"#);
+
+        let refunds = Arc::new(Mutex::new(0));
+        let counted = refunds.clone();
+
+        let router = Router::new().route(matcher, move |_message: &Message| {
+            *counted.lock().unwrap() += 1
+        });
+
+        router.handle(&body(r#"{"kind":"refund"}"#)).await.unwrap();
+
+        // legacy bodies that match no route are left for the dead-letter queue
+        assert!(router.handle(&body(r#"{"kind":"order"}"#)).await.is_err());
+        assert_eq!(*refunds.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn passes_messages_to_the_first_matching_route() {
+        let routed = Arc::new(Mutex::new(Vec::new()));
+
+        let route = |name: &'static str| {
+            let routed = routed.clone();
+            move |_message: &Message| routed.lock().unwrap().push(name)
+        };
+
+        let router = Router::new()
+            .route(Matcher::equals("type", "order.created"), route("created"))
+            .route(Matcher::prefix("type", "order."), route("orders"));
+
+        router
+            .handle(&message(&[("type", "order.created")]))
+            .await
+            .unwrap();
+        router
+            .handle(&message(&[("type", "order.paid")]))
+            .await
+            .unwrap();
+
+        // no route and no fallback, the message is left on the queue
+        assert!(router.handle(&message(&[])).await.is_err());
+
+        let router = router.fallback(route("fallback"));
+        router.handle(&message(&[])).await.unwrap();
+
+        assert_eq!(
+            *routed.lock().unwrap(),
+            vec!["created", "orders", "fallback"]
+        );
+        assert_eq!(router.routes().count(), 2);
+        assert!(router.has_fallback());
+    }
+}
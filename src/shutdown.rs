@@ -0,0 +1,200 @@
+//! Graceful shutdown of a running listener
+//!
+//! Get a [ShutdownHandle] before starting the listener, [`shutdown()`](ShutdownHandle::shutdown)
+//! stops polling, waits for the poll in progress to finish and makes
+//! [`start()`](crate::SQSListenerClient::start) return
+//!
+//! ```rust,ignore
+//! let client = SQSListenerClientBuilder::new(Region::UsEast1)
+//!     .listener(listener)
+//!     .build()?;
+//!
+//! let shutdown = client.shutdown_handle();
+//! let listener = tokio::spawn(client.start());
+//!
+//! // later
+//! shutdown.shutdown().await;
+//! ```
+//!
+//! Set `shutdown_on_signals` on the [builder](crate::SQSListenerClientBuilder) to shut down on
+//! SIGTERM or SIGINT instead, for example when Kubernetes stops a pod
+//!
+//! With the `tokio-graceful-shutdown` feature the client is a subsystem of
+//! [tokio-graceful-shutdown](https://docs.rs/tokio-graceful-shutdown), it shuts down like with a
+//! [ShutdownHandle] when the subsystem is asked to
+//!
+//! ```rust,ignore
+//! Toplevel::new(|s| async move {
+//!     s.start(SubsystemBuilder::new("sqs_listener", client.into_subsystem()));
+//! })
+//! .catch_signals()
+//! .handle_shutdown_requests(Duration::from_secs(30))
+//! .await?;
+//! ```
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+use super::telemetry::{info, warn};
+
+/// Stops a listener, get it with
+/// [`SQSListenerClient::shutdown_handle()`](crate::SQSListenerClient::shutdown_handle)
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+impl std::fmt::Debug for ShutdownHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShutdownHandle")
+            .field("requested", &self.state.is_requested())
+            .field("running", &self.state.running.load(Ordering::SeqCst))
+            .finish()
+    }
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(state: Arc<ShutdownState>) -> Self {
+        Self { state }
+    }
+
+    /// Stops the listener: no new poll is started, the messages already received are handled and
+    /// acked by the workers and then [`start()`](crate::SQSListenerClient::start) returns
+    ///
+    /// If the messages are not drained within the `drain_timeout` of the [Config](crate::Config),
+    /// `start()` returns [`Error::DrainTimeout`](crate::Error::DrainTimeout) and the remaining
+    /// messages are redelivered once their visibility timeout expires
+    ///
+    /// Resolves once the listener has stopped, or right away if it isn't running. A listener that
+    /// hasn't been started yet returns from `start()` without polling
+    pub async fn shutdown(&self) {
+        self.state.request();
+
+        if self.state.running.load(Ordering::SeqCst) {
+            wait_for(self.state.stopped_receiver.clone()).await;
+        }
+    }
+
+    /// True once a shutdown was requested
+    pub fn is_shutdown_requested(&self) -> bool {
+        self.state.is_requested()
+    }
+}
+
+// shared by the client, its shutdown handles and the task forwarding requests to the actor
+pub(crate) struct ShutdownState {
+    requested: watch::Sender<bool>,
+    requested_receiver: watch::Receiver<bool>,
+    stopped: watch::Sender<bool>,
+    stopped_receiver: watch::Receiver<bool>,
+    running: AtomicBool,
+}
+
+impl ShutdownState {
+    pub(crate) fn new() -> Self {
+        let (requested, requested_receiver) = watch::channel(false);
+        let (stopped, stopped_receiver) = watch::channel(false);
+
+        Self {
+            requested,
+            requested_receiver,
+            stopped,
+            stopped_receiver,
+            running: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn request(&self) {
+        // never fails, the state keeps a receiver
+        let _ = self.requested.send(true);
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        *self.requested_receiver.borrow()
+    }
+
+    // resolves once a shutdown is requested
+    pub(crate) async fn requested(&self) {
+        wait_for(self.requested_receiver.clone()).await
+    }
+
+    // resolves once the drain timeout has passed since a shutdown was requested
+    pub(crate) async fn drain_elapsed(&self, drain_timeout: Duration) {
+        self.requested().await;
+        tokio::time::sleep(drain_timeout).await
+    }
+
+    pub(crate) fn started(&self) {
+        self.running.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn stopped(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        let _ = self.stopped.send(true);
+    }
+}
+
+// requests a shutdown on SIGTERM, or SIGINT on every platform
+pub(crate) fn request_on_signals(state: &Arc<ShutdownState>) {
+    let on_interrupt = state.clone();
+    tokio::spawn(async move {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => {
+                info!("Received SIGINT, shutting down");
+                on_interrupt.request();
+            }
+            Err(error) => warn!("Unable to listen for SIGINT: {}", error),
+        }
+    });
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                let on_terminate = state.clone();
+                tokio::spawn(async move {
+                    if terminate.recv().await.is_some() {
+                        info!("Received SIGTERM, shutting down");
+                        on_terminate.request();
+                    }
+                });
+            }
+            Err(error) => warn!("Unable to listen for SIGTERM: {}", error),
+        }
+    }
+}
+
+#[cfg(feature = "tokio-graceful-shutdown")]
+#[async_trait::async_trait]
+impl<F: crate::Handler> tokio_graceful_shutdown::IntoSubsystem<crate::Error>
+    for crate::SQSListenerClient<F>
+{
+    async fn run(
+        self,
+        subsys: tokio_graceful_shutdown::SubsystemHandle,
+    ) -> Result<(), crate::Error> {
+        let state = self.shutdown.clone();
+        let listener = self.start();
+        tokio::pin!(listener);
+
+        tokio::select! {
+            result = &mut listener => result,
+            () = subsys.on_shutdown_requested() => {
+                // the listener keeps running until the messages in flight are drained
+                state.request();
+                listener.await
+            }
+        }
+    }
+}
+
+async fn wait_for(mut receiver: watch::Receiver<bool>) {
+    while !*receiver.borrow() {
+        if receiver.changed().await.is_err() {
+            return;
+        }
+    }
+}
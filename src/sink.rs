@@ -0,0 +1,83 @@
+//! Result sinks receive the [Outcome] of every message handled by the listener, giving a record
+//! of what happened to each message beyond the logs
+//!
+//! Add a sink to the client using [`result_sink()`](crate::SQSListenerClientBuilder::result_sink)
+use rusoto_sqs::Message;
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::telemetry::{error, info};
+use super::Error;
+
+/// What happened to a message
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Outcome {
+    /// The message was handled, and acknowledged if `auto_ack` is enabled
+    Success,
+
+    /// Handling or acknowledging the message failed
+    Failure(Arc<Error>),
+
+    /// The message failed on the last receive the redrive policy of the queue allows, SQS moves
+    /// it to the dead-letter queue instead of delivering it again. Recorded after its `Failure`,
+    /// once `tune_from_queue` read the redrive policy
+    DeadLettered,
+
+    /// The message was dropped without being handled
+    Dropped,
+}
+
+impl From<Result<(), Error>> for Outcome {
+    fn from(result: Result<(), Error>) -> Self {
+        match result {
+            Ok(()) => Outcome::Success,
+            Err(error) => Outcome::Failure(Arc::new(error)),
+        }
+    }
+}
+
+/// Receives the [Outcome] of every message
+pub trait ResultSink: Send + Sync {
+    fn record(&self, message: &Message, outcome: &Outcome);
+}
+
+/// Logs every outcome, failures are logged as errors
+#[derive(Debug, Default, Clone)]
+pub struct LogSink;
+
+impl ResultSink for LogSink {
+    fn record(&self, message: &Message, outcome: &Outcome) {
+        let message_id = message.message_id.as_deref().unwrap_or_default();
+
+        match outcome {
+            Outcome::Failure(error) => error!("Message {} failed: {}", message_id, error),
+            outcome => info!("Message {}: {:?}", message_id, outcome),
+        }
+    }
+}
+
+/// Forwards every message and its outcome to a channel
+#[derive(Debug, Clone)]
+pub struct ChannelSink {
+    sender: UnboundedSender<(Message, Outcome)>,
+}
+
+impl ChannelSink {
+    pub fn new(sender: UnboundedSender<(Message, Outcome)>) -> Self {
+        Self { sender }
+    }
+
+    /// Create a sink along with the receiving half of its channel
+    pub fn channel() -> (Self, UnboundedReceiver<(Message, Outcome)>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Self::new(sender), receiver)
+    }
+}
+
+impl ResultSink for ChannelSink {
+    fn record(&self, message: &Message, outcome: &Outcome) {
+        // the receiver being dropped means nobody is interested in the outcomes anymore
+        let _ = self.sender.send((message.clone(), outcome.clone()));
+    }
+}
@@ -0,0 +1,125 @@
+// Logging, and spans around poll cycles and handlers with the `tracing` feature
+//
+// With the feature, the log macros of the listener are the ones of `tracing`, so events carry the
+// fields of the span they happen in. `tracing` still emits log records when no subscriber is set
+use rusoto_sqs::Message;
+use std::future::Future;
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{debug, error, info, warn, Span};
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) use log::{debug, error, info, warn};
+
+/// Stand-in for `tracing::Span` without the `tracing` feature
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Span;
+
+/// Message attribute with the number of times the message was received
+pub(crate) const RECEIVE_COUNT_ATTRIBUTE: &str = "ApproximateReceiveCount";
+
+// only set when the attribute was requested
+pub(crate) fn receive_count(message: &Message) -> Option<u64> {
+    message
+        .attributes
+        .as_ref()
+        .and_then(|attributes| attributes.get(RECEIVE_COUNT_ATTRIBUTE))
+        .and_then(|receive_count| receive_count.parse().ok())
+}
+
+// decided by the message id, so a redelivered message is traced the same way
+#[cfg(feature = "tracing")]
+fn is_traced(message: &Message, sample_rate: f64) -> bool {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    if sample_rate >= 1.0 {
+        return true;
+    }
+
+    let mut hasher = DefaultHasher::new();
+    message.message_id.hash(&mut hasher);
+
+    (hasher.finish() as f64 / u64::MAX as f64) < sample_rate
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn poll_span(queue_url: &str) -> Span {
+    tracing::info_span!("poll", queue_url)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn poll_span(_queue_url: &str) -> Span {
+    Span
+}
+
+// unsampled messages get no span, their errors are still logged within the poll span
+#[cfg(feature = "tracing")]
+pub(crate) fn message_span(queue_url: &str, message: &Message, sample_rate: f64) -> Span {
+    if !is_traced(message, sample_rate) {
+        return Span::none();
+    }
+
+    let span = tracing::info_span!(
+        "handle_message",
+        queue_url,
+        message_id = message.message_id.as_deref().unwrap_or_default(),
+        approximate_receive_count = tracing::field::Empty,
+    );
+
+    if let Some(receive_count) = receive_count(message) {
+        span.record("approximate_receive_count", &receive_count);
+    }
+
+    span
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn message_span(_queue_url: &str, _message: &Message, _sample_rate: f64) -> Span {
+    Span
+}
+
+// a batch is sampled by its first message
+#[cfg(feature = "tracing")]
+pub(crate) fn batch_span(queue_url: &str, messages: &[Message], sample_rate: f64) -> Span {
+    if !messages
+        .first()
+        .map_or(false, |message| is_traced(message, sample_rate))
+    {
+        return Span::none();
+    }
+
+    let message_ids: Vec<&str> = messages
+        .iter()
+        .map(|message| message.message_id.as_deref().unwrap_or_default())
+        .collect();
+
+    tracing::info_span!(
+        "handle_batch",
+        queue_url,
+        message_ids = ?message_ids,
+        approximate_receive_count = ?messages.iter().map(receive_count).collect::<Vec<_>>(),
+    )
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn batch_span(_queue_url: &str, _messages: &[Message], _sample_rate: f64) -> Span {
+    Span
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn instrument<Fut: Future>(
+    future: Fut,
+    span: Span,
+) -> impl Future<Output = Fut::Output> {
+    tracing::Instrument::instrument(future, span)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn instrument<Fut: Future>(
+    future: Fut,
+    _span: Span,
+) -> impl Future<Output = Fut::Output> {
+    future
+}
@@ -0,0 +1,646 @@
+//! Helpers to test listeners without a network connection
+//!
+//! Enable with the `testing` feature, usually as a dev-dependency:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! sqs_listener = {version = "0.2", features = ["testing"]}
+//! ```
+use rusoto_core::credential::StaticProvider;
+use rusoto_core::request::HttpClient;
+use rusoto_core::Region;
+use rusoto_sqs::Message;
+use std::sync::Arc;
+
+use super::emulator::{QueueOptions, SqsEmulator};
+use super::{client, unique_id, Handler, SQSListenerClientBuilder, SQSListenerClientBuilderError};
+
+/// Drives a listener against an in memory queue, one poll cycle at a time
+///
+/// Runs the same handling and acknowledgment code as [SQSListenerClient](crate::SQSListenerClient)
+/// so ack strategies, config options and result sinks can be unit tested quickly
+///
+/// ```rust,ignore
+/// let mut driver = TestDriver::new(SQSListenerClientBuilder::default().listener(listener))?;
+///
+/// driver.push_message(Message {
+///     body: Some("hello".to_string()),
+///     ..Default::default()
+/// });
+///
+/// driver.run_one_cycle().await;
+/// assert_eq!(driver.acked_messages().len(), 1);
+/// ```
+pub struct TestDriver<F: Handler> {
+    client: client::SQSListenerClient<F>,
+    emulator: SqsEmulator,
+}
+
+impl<F: Handler> TestDriver<F> {
+    /// Create a driver from a builder, the builder's SQS client is replaced by an
+    /// [emulated](crate::emulator::SqsEmulator) queue
+    pub fn new(
+        builder: SQSListenerClientBuilder<F>,
+    ) -> Result<Self, SQSListenerClientBuilderError> {
+        let emulator = SqsEmulator::new();
+        let client = builder.transport(Arc::new(emulator.clone())).priv_build()?;
+
+        emulator.create_queue(&client.listener.queue_url, QueueOptions::default());
+
+        Ok(Self { client, emulator })
+    }
+
+    /// Add a message to the queue, a message id is generated if missing
+    pub fn push_message(&self, message: Message) {
+        self.emulator.push_message(self.queue_url(), message);
+    }
+
+    /// Run a single poll cycle, receiving and handling messages from the queue
+    pub async fn run_one_cycle(&mut self) {
+        self.client.poll().await;
+    }
+
+    /// Messages that have been acknowledged and deleted from the queue
+    pub fn acked_messages(&self) -> Vec<Message> {
+        self.emulator.deleted_messages(self.queue_url())
+    }
+
+    /// Messages that have been received but not acknowledged
+    pub fn unacked_messages(&self) -> Vec<Message> {
+        self.emulator.in_flight_messages(self.queue_url())
+    }
+
+    /// Messages that have not been received yet
+    pub fn queued_messages(&self) -> Vec<Message> {
+        self.emulator.queued_messages(self.queue_url())
+    }
+
+    /// The emulator backing the queue, to inspect or add other queues
+    pub fn emulator(&self) -> &SqsEmulator {
+        &self.emulator
+    }
+
+    fn queue_url(&self) -> &str {
+        &self.client.listener.queue_url
+    }
+}
+
+// builds the client of a unit test against the emulator, without starting it
+#[cfg(test)]
+pub(crate) fn emulated_client<F: Handler>(
+    emulator: &SqsEmulator,
+    builder: SQSListenerClientBuilder<F>,
+) -> client::SQSListenerClient<F> {
+    builder
+        .transport(Arc::new(emulator.clone()))
+        .priv_build()
+        .expect("all required fields are set")
+}
+
+/// Create a builder that talks to a mock HTTP server (wiremock, httpmock, ...) at `endpoint`
+/// instead of AWS, using static test credentials
+///
+/// Serve the responses from [receive_message_response], [delete_message_response],
+/// [send_message_response] and [error_response] from the mock server
+pub fn mock_server_builder<F: Handler>(endpoint: &str) -> SQSListenerClientBuilder<F> {
+    let region = Region::Custom {
+        name: "us-east-1".to_string(),
+        endpoint: endpoint.to_string(),
+    };
+
+    SQSListenerClientBuilder::new_with(
+        HttpClient::new().expect("failed to create request dispatcher"),
+        StaticProvider::new_minimal("test".to_string(), "test".to_string()),
+        region,
+    )
+}
+
+/// Body of a successful `ReceiveMessage` response containing `messages`
+pub fn receive_message_response(messages: &[Message]) -> String {
+    let messages: String = messages.iter().map(message_xml).collect();
+
+    response(
+        "ReceiveMessage",
+        &format!("<ReceiveMessageResult>{}</ReceiveMessageResult>", messages),
+    )
+}
+
+/// Body of a successful `DeleteMessage` response
+pub fn delete_message_response() -> String {
+    response("DeleteMessage", "")
+}
+
+/// Body of a successful `SendMessage` response
+pub fn send_message_response(message_id: &str) -> String {
+    response(
+        "SendMessage",
+        &format!(
+            "<SendMessageResult>{}</SendMessageResult>",
+            element("MessageId", message_id)
+        ),
+    )
+}
+
+/// Body of an error response, for example with code `AWS.SimpleQueueService.NonExistentQueue`.
+/// Serve it with a 400 status code
+pub fn error_response(code: &str, message: &str) -> String {
+    format!(
+        "<ErrorResponse><Error><Type>Sender</Type>{}{}<Detail/></Error>{}</ErrorResponse>",
+        element("Code", code),
+        element("Message", message),
+        element("RequestId", &unique_id())
+    )
+}
+
+fn response(action: &str, result: &str) -> String {
+    format!(
+        r#"<?xml version="1.0"?><{action}Response xmlns="http://queue.amazonaws.com/doc/2012-11-05/">{result}<ResponseMetadata>{request_id}</ResponseMetadata></{action}Response>"#,
+        action = action,
+        result = result,
+        request_id = element("RequestId", &unique_id())
+    )
+}
+
+fn message_xml(message: &Message) -> String {
+    let mut xml = String::from("<Message>");
+
+    let fields = [
+        ("MessageId", &message.message_id),
+        ("ReceiptHandle", &message.receipt_handle),
+        ("MD5OfBody", &message.md5_of_body),
+        ("MD5OfMessageAttributes", &message.md5_of_message_attributes),
+        ("Body", &message.body),
+    ];
+
+    for (name, value) in fields.iter() {
+        if let Some(value) = value {
+            xml.push_str(&element(name, value));
+        }
+    }
+
+    for (name, value) in message.attributes.iter().flatten() {
+        xml.push_str(&format!(
+            "<Attribute>{}{}</Attribute>",
+            element("Name", name),
+            element("Value", value)
+        ));
+    }
+
+    for (name, value) in message.message_attributes.iter().flatten() {
+        let string_value = value
+            .string_value
+            .as_ref()
+            .map(|string_value| element("StringValue", string_value))
+            .unwrap_or_default();
+
+        xml.push_str(&format!(
+            "<MessageAttribute>{}<Value>{}{}</Value></MessageAttribute>",
+            element("Name", name),
+            string_value,
+            element("DataType", &value.data_type)
+        ));
+    }
+
+    xml.push_str("</Message>");
+    xml
+}
+
+fn element(name: &str, value: &str) -> String {
+    let escaped = value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;");
+
+    format!("<{name}>{value}</{name}>", name = name, value = escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::{InvalidBody, TypedHandler};
+    use crate::router::JsonPath;
+    use crate::{ConfigBuilder, HandlerError, SQSListener};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn message(body: &str) -> Message {
+        Message {
+            body: Some(body.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn handles_and_acks_messages() {
+        let listener = SQSListener::new("queue".to_string(), |message| {
+            assert_eq!(message.body.as_deref(), Some("hello"))
+        });
+
+        let mut driver = TestDriver::new(SQSListenerClientBuilder::default().listener(listener))
+            .expect("listener is set");
+
+        driver.push_message(message("hello"));
+        driver.run_one_cycle().await;
+
+        assert_eq!(driver.acked_messages().len(), 1);
+        assert!(driver.unacked_messages().is_empty());
+        assert!(driver.queued_messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn awaits_async_handlers_before_acking() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let handled_by_listener = handled.clone();
+
+        let listener = SQSListener::new_async("queue".to_string(), move |message| {
+            let handled = handled_by_listener.clone();
+
+            async move {
+                tokio::task::yield_now().await;
+                assert_eq!(message.body.as_deref(), Some("hello"));
+                handled.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        let mut driver = TestDriver::new(SQSListenerClientBuilder::default().listener(listener))
+            .expect("listener is set");
+
+        driver.push_message(message("hello"));
+        driver.run_one_cycle().await;
+
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+        assert_eq!(driver.acked_messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn passes_owned_messages() {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let listener = SQSListener::new_owned("queue".to_string(), move |message| {
+            sender.send(message).expect("receiver is alive");
+        });
+
+        let mut driver = TestDriver::new(SQSListenerClientBuilder::default().listener(listener))
+            .expect("listener is set");
+
+        driver.push_message(message("hello"));
+        driver.run_one_cycle().await;
+
+        let message = receiver.recv().await.expect("message was handled");
+        assert_eq!(message.body.as_deref(), Some("hello"));
+        assert_eq!(driver.acked_messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn acks_only_messages_whose_handler_succeeded() {
+        let listener = SQSListener::new_fallible("queue".to_string(), |message| async move {
+            match message.body.as_deref() {
+                Some("fail") => Err("handler failed"),
+                _ => Ok(()),
+            }
+        });
+
+        let config = ConfigBuilder::default().max_messages(2).build();
+
+        let mut driver = TestDriver::new(
+            SQSListenerClientBuilder::default()
+                .listener(listener)
+                .config(config),
+        )
+        .expect("listener is set");
+
+        driver.push_message(message("hello"));
+        driver.push_message(message("fail"));
+        driver.run_one_cycle().await;
+
+        let acked = driver.acked_messages();
+        assert_eq!(acked.len(), 1);
+        assert_eq!(acked[0].body.as_deref(), Some("hello"));
+
+        let unacked = driver.unacked_messages();
+        assert_eq!(unacked.len(), 1);
+        assert_eq!(unacked[0].body.as_deref(), Some("fail"));
+    }
+
+    #[tokio::test]
+    async fn commits_prepared_messages_once_acked() {
+        let committed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let committed_tokens = committed.clone();
+
+        let listener = SQSListener::new_two_phase(
+            "queue".to_string(),
+            |message| async move {
+                match message.body {
+                    Some(body) if body != "fail" => Ok(body),
+                    _ => Err("prepare failed"),
+                }
+            },
+            move |token: String| {
+                committed_tokens.lock().unwrap().push(token);
+                async {}
+            },
+        );
+
+        let config = ConfigBuilder::default().max_messages(2).build();
+
+        let mut driver = TestDriver::new(
+            SQSListenerClientBuilder::default()
+                .listener(listener)
+                .config(config),
+        )
+        .expect("listener is set");
+
+        driver.push_message(message("hello"));
+        driver.push_message(message("fail"));
+        driver.run_one_cycle().await;
+
+        assert_eq!(*committed.lock().unwrap(), vec!["hello".to_string()]);
+        assert_eq!(driver.acked_messages().len(), 1);
+        assert_eq!(driver.unacked_messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_prepared_tokens_per_delivery() {
+        let committed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let committed_tokens = committed.clone();
+
+        let listener = SQSListener::new_two_phase(
+            "queue".to_string(),
+            |message| async move { Ok::<_, HandlerError>(message.body.unwrap_or_default()) },
+            move |token: String| {
+                committed_tokens.lock().unwrap().push(token);
+                async {}
+            },
+        );
+
+        let config = ConfigBuilder::default().max_messages(2).build();
+
+        let mut driver = TestDriver::new(
+            SQSListenerClientBuilder::default()
+                .listener(listener)
+                .config(config),
+        )
+        .expect("listener is set");
+
+        // the same message delivered twice, as after a producer retry
+        for body in ["first", "second"].iter() {
+            driver.push_message(Message {
+                message_id: Some("same".to_string()),
+                ..message(body)
+            });
+        }
+
+        driver.run_one_cycle().await;
+
+        let mut committed = committed.lock().unwrap().clone();
+        committed.sort();
+
+        assert_eq!(committed, vec!["first".to_string(), "second".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn handles_batches_together() {
+        for fail in [false, true].iter().copied() {
+            let batch_sizes = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let recorded_sizes = batch_sizes.clone();
+
+            let listener = SQSListener::new_batch("queue".to_string(), move |messages| {
+                recorded_sizes.lock().unwrap().push(messages.len());
+                async move {
+                    if fail {
+                        Err("insert failed")
+                    } else {
+                        Ok(())
+                    }
+                }
+            });
+
+            let config = ConfigBuilder::default().max_messages(10).build();
+
+            let mut driver = TestDriver::new(
+                SQSListenerClientBuilder::default()
+                    .listener(listener)
+                    .config(config),
+            )
+            .expect("listener is set");
+
+            for body in ["1", "2", "3"].iter() {
+                driver.push_message(message(body));
+            }
+
+            driver.run_one_cycle().await;
+
+            assert_eq!(*batch_sizes.lock().unwrap(), vec![3]);
+
+            if fail {
+                assert!(driver.acked_messages().is_empty());
+                assert_eq!(driver.unacked_messages().len(), 3);
+            } else {
+                assert_eq!(driver.acked_messages().len(), 3);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn leaves_messages_unacked_without_auto_ack() {
+        let listener = SQSListener::new("queue".to_string(), |_message| {});
+        let config = ConfigBuilder::default().auto_ack(false).build();
+
+        let mut driver = TestDriver::new(
+            SQSListenerClientBuilder::default()
+                .listener(listener)
+                .config(config),
+        )
+        .expect("listener is set");
+
+        driver.push_message(message("hello"));
+        driver.run_one_cycle().await;
+
+        assert!(driver.acked_messages().is_empty());
+        assert_eq!(driver.unacked_messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn skips_handler_for_unsampled_messages() {
+        for ack_unsampled in [true, false].iter().copied() {
+            let handled = Arc::new(AtomicUsize::new(0));
+            let handled_by_listener = handled.clone();
+
+            let listener = SQSListener::new("queue".to_string(), move |_message| {
+                handled_by_listener.fetch_add(1, Ordering::SeqCst);
+            });
+
+            let config = ConfigBuilder::default()
+                .sample_rate(0.0)
+                .ack_unsampled(ack_unsampled)
+                .build();
+
+            let mut driver = TestDriver::new(
+                SQSListenerClientBuilder::default()
+                    .listener(listener)
+                    .config(config),
+            )
+            .expect("listener is set");
+
+            driver.push_message(message("hello"));
+            driver.run_one_cycle().await;
+
+            assert_eq!(handled.load(Ordering::SeqCst), 0);
+            assert_eq!(driver.acked_messages().len(), ack_unsampled as usize);
+            assert_eq!(driver.unacked_messages().len(), !ack_unsampled as usize);
+        }
+    }
+
+    #[test]
+    fn builds_escaped_receive_message_response() {
+        let message = Message {
+            message_id: Some("id".to_string()),
+            receipt_handle: Some("handle".to_string()),
+            body: Some("<b>fish & chips</b>".to_string()),
+            ..Default::default()
+        };
+
+        let response = receive_message_response(&[message]);
+
+        assert!(response.contains(
+            "<Message><MessageId>id</MessageId><ReceiptHandle>handle</ReceiptHandle>\
+             <Body>&lt;b&gt;fish &amp; chips&lt;/b&gt;</Body></Message>"
+        ));
+    }
+
+    #[tokio::test]
+    async fn deserializes_typed_bodies() {
+        #[derive(serde::Deserialize)]
+        struct Order {
+            id: u64,
+        }
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        let handled_by_listener = handled.clone();
+
+        let handler = TypedHandler::new(move |order: Order, _message: &Message| {
+            handled_by_listener.fetch_add(order.id as usize, Ordering::SeqCst);
+        })
+        .on_invalid_body(InvalidBody::Leave);
+
+        let config = ConfigBuilder::default().max_messages(2).build();
+
+        let mut driver = TestDriver::new(
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::with_handler("queue".to_string(), handler))
+                .config(config),
+        )
+        .expect("listener is set");
+
+        driver.push_message(message(r#"{"id":7}"#));
+        driver.push_message(message("not json"));
+        driver.run_one_cycle().await;
+
+        assert_eq!(handled.load(Ordering::SeqCst), 7);
+        assert_eq!(driver.acked_messages().len(), 1);
+
+        let unacked = driver.unacked_messages();
+        assert_eq!(unacked.len(), 1);
+        assert_eq!(unacked[0].body.as_deref(), Some("not json"));
+    }
+
+    #[tokio::test]
+    async fn upgrades_bodies_of_older_versions() {
+        #[derive(serde::Deserialize)]
+        struct OrderV1 {
+            id: u64,
+        }
+
+        #[derive(serde::Deserialize)]
+        struct Order {
+            id: u64,
+            quantity: u64,
+        }
+
+        let handled = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handled_by_listener = handled.clone();
+
+        let handler = TypedHandler::new(move |order: Order, _message: &Message| {
+            handled_by_listener
+                .lock()
+                .unwrap()
+                .push((order.id, order.quantity));
+        })
+        .upgrade_from("1", |order: OrderV1| Order {
+            id: order.id,
+            quantity: 1,
+        })
+        .on_invalid_body(InvalidBody::Leave);
+
+        let config = ConfigBuilder::default()
+            .max_messages(2)
+            .message_attribute_names(vec!["version".to_string()])
+            .build();
+
+        let mut driver = TestDriver::new(
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::with_handler("queue".to_string(), handler))
+                .config(config),
+        )
+        .expect("listener is set");
+
+        let mut message_attributes = std::collections::HashMap::new();
+        message_attributes.insert(
+            "version".to_string(),
+            rusoto_sqs::MessageAttributeValue {
+                data_type: "String".to_string(),
+                string_value: Some("1".to_string()),
+                ..Default::default()
+            },
+        );
+
+        driver.push_message(Message {
+            message_attributes: Some(message_attributes),
+            ..message(r#"{"id":3}"#)
+        });
+        driver.push_message(message(r#"{"id":4,"quantity":2}"#));
+        driver.run_one_cycle().await;
+
+        let mut handled = handled.lock().unwrap().clone();
+        handled.sort_unstable();
+
+        assert_eq!(handled, vec![(3, 1), (4, 2)]);
+        assert_eq!(driver.acked_messages().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn extracts_the_body_at_a_json_path() {
+        #[derive(serde::Deserialize)]
+        struct Order {
+            id: u64,
+        }
+
+        let handled = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let handled_by_listener = handled.clone();
+
+        let handler = TypedHandler::new(move |order: Order, _message: &Message| {
+            handled_by_listener.lock().unwrap().push(order.id);
+        })
+        .extract(JsonPath::parse("$.detail").unwrap())
+        .on_invalid_body(InvalidBody::Leave);
+
+        let mut driver = TestDriver::new(
+            SQSListenerClientBuilder::default()
+                .listener(SQSListener::with_handler("queue".to_string(), handler))
+                .config(ConfigBuilder::default().max_messages(2).build()),
+        )
+        .expect("listener is set");
+
+        driver.push_message(message(
+            r#"{"detail-type":"Order Created","detail":{"id":5},"resources":[]}"#,
+        ));
+        driver.push_message(message(r#"{"id":6}"#));
+        driver.run_one_cycle().await;
+
+        // the body without a detail is left on the queue
+        assert_eq!(*handled.lock().unwrap(), vec![5]);
+        assert_eq!(driver.acked_messages().len(), 1);
+        assert_eq!(driver.unacked_messages().len(), 1);
+    }
+}
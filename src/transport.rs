@@ -0,0 +1,113 @@
+//! The SQS operations used by the listener, implemented for every [Sqs] client. Lets the listener
+//! run against something other than the network, for example the in memory queue of the
+//! [TestDriver](crate::testing::TestDriver)
+use async_trait::async_trait;
+use rusoto_core::RusotoError;
+use rusoto_sqs::{
+    ChangeMessageVisibilityError, ChangeMessageVisibilityRequest, DeleteMessageBatchError,
+    DeleteMessageBatchRequest, DeleteMessageBatchResult, DeleteMessageError, DeleteMessageRequest,
+    GetQueueAttributesError, GetQueueAttributesRequest, GetQueueAttributesResult,
+    ReceiveMessageError, ReceiveMessageRequest, ReceiveMessageResult, SendMessageBatchError,
+    SendMessageBatchRequest, SendMessageBatchResult, SendMessageError, SendMessageRequest,
+    SendMessageResult, Sqs, SqsClient,
+};
+use std::any::Any;
+
+#[async_trait]
+pub(crate) trait Transport: Send + Sync {
+    /// The underlying client, if the transport is an [SqsClient]
+    fn sqs_client(&self) -> Option<SqsClient> {
+        None
+    }
+
+    async fn receive_message(
+        &self,
+        input: ReceiveMessageRequest,
+    ) -> Result<ReceiveMessageResult, RusotoError<ReceiveMessageError>>;
+
+    async fn delete_message(
+        &self,
+        input: DeleteMessageRequest,
+    ) -> Result<(), RusotoError<DeleteMessageError>>;
+
+    async fn delete_message_batch(
+        &self,
+        input: DeleteMessageBatchRequest,
+    ) -> Result<DeleteMessageBatchResult, RusotoError<DeleteMessageBatchError>>;
+
+    async fn change_message_visibility(
+        &self,
+        input: ChangeMessageVisibilityRequest,
+    ) -> Result<(), RusotoError<ChangeMessageVisibilityError>>;
+
+    async fn send_message(
+        &self,
+        input: SendMessageRequest,
+    ) -> Result<SendMessageResult, RusotoError<SendMessageError>>;
+
+    async fn send_message_batch(
+        &self,
+        input: SendMessageBatchRequest,
+    ) -> Result<SendMessageBatchResult, RusotoError<SendMessageBatchError>>;
+
+    async fn get_queue_attributes(
+        &self,
+        input: GetQueueAttributesRequest,
+    ) -> Result<GetQueueAttributesResult, RusotoError<GetQueueAttributesError>>;
+}
+
+#[async_trait]
+impl<S: Sqs> Transport for S {
+    fn sqs_client(&self) -> Option<SqsClient> {
+        (self as &dyn Any).downcast_ref::<SqsClient>().cloned()
+    }
+
+    async fn receive_message(
+        &self,
+        input: ReceiveMessageRequest,
+    ) -> Result<ReceiveMessageResult, RusotoError<ReceiveMessageError>> {
+        Sqs::receive_message(self, input).await
+    }
+
+    async fn delete_message(
+        &self,
+        input: DeleteMessageRequest,
+    ) -> Result<(), RusotoError<DeleteMessageError>> {
+        Sqs::delete_message(self, input).await
+    }
+
+    async fn delete_message_batch(
+        &self,
+        input: DeleteMessageBatchRequest,
+    ) -> Result<DeleteMessageBatchResult, RusotoError<DeleteMessageBatchError>> {
+        Sqs::delete_message_batch(self, input).await
+    }
+
+    async fn change_message_visibility(
+        &self,
+        input: ChangeMessageVisibilityRequest,
+    ) -> Result<(), RusotoError<ChangeMessageVisibilityError>> {
+        Sqs::change_message_visibility(self, input).await
+    }
+
+    async fn send_message(
+        &self,
+        input: SendMessageRequest,
+    ) -> Result<SendMessageResult, RusotoError<SendMessageError>> {
+        Sqs::send_message(self, input).await
+    }
+
+    async fn send_message_batch(
+        &self,
+        input: SendMessageBatchRequest,
+    ) -> Result<SendMessageBatchResult, RusotoError<SendMessageBatchError>> {
+        Sqs::send_message_batch(self, input).await
+    }
+
+    async fn get_queue_attributes(
+        &self,
+        input: GetQueueAttributesRequest,
+    ) -> Result<GetQueueAttributesResult, RusotoError<GetQueueAttributesError>> {
+        Sqs::get_queue_attributes(self, input).await
+    }
+}
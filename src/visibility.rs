@@ -0,0 +1,171 @@
+//! Extending the visibility timeout of messages while their handler is still running
+//!
+//! A [VisibilityPolicy] decides when and by how much the visibility of a message is extended, so
+//! a handler that occasionally takes longer than the visibility timeout of the queue doesn't get
+//! its message delivered a second time. Extending stops as soon as the handler returns
+//!
+//! ```rust,ignore
+//! let client = SQSListenerClientBuilder::new(Region::UsEast1)
+//!     .listener(listener)
+//!     .visibility_policy(FixedExtension::new(Duration::from_secs(20), Duration::from_secs(60)))
+//!     .build()?;
+//! ```
+//!
+//! A handler that knows a message will take long can call [expect_duration] instead, the
+//! visibility is extended once right away, with or without a policy
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// How long the message has been handled, passed to the [VisibilityPolicy]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Heartbeat {
+    /// Time since the handler was called
+    pub elapsed: Duration,
+
+    /// Number of times the visibility was extended so far
+    pub extensions: u32,
+}
+
+/// Decides how the visibility of messages is extended while their handler runs
+pub trait VisibilityPolicy: Send + Sync {
+    /// How long to wait before the next extension
+    fn interval(&self, heartbeat: &Heartbeat) -> Duration;
+
+    /// The new visibility timeout of the message, counted from now. `None` stops extending
+    fn extend(&self, heartbeat: &Heartbeat) -> Option<Duration>;
+}
+
+/// Extends the visibility to `timeout` every `interval`, for at most `max_total` if set
+#[derive(Debug, Clone)]
+pub struct FixedExtension {
+    interval: Duration,
+    timeout: Duration,
+    max_total: Option<Duration>,
+}
+
+impl FixedExtension {
+    /// Keep `interval` well below `timeout`, so the message never becomes visible in between
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        Self {
+            interval,
+            timeout,
+            max_total: None,
+        }
+    }
+
+    /// Stop extending once the handler has run this long, and let the message be redelivered
+    pub fn max_total(mut self, max_total: Duration) -> Self {
+        self.max_total = Some(max_total);
+        self
+    }
+}
+
+impl VisibilityPolicy for FixedExtension {
+    fn interval(&self, _heartbeat: &Heartbeat) -> Duration {
+        self.interval
+    }
+
+    fn extend(&self, heartbeat: &Heartbeat) -> Option<Duration> {
+        match self.max_total {
+            Some(max_total) if heartbeat.elapsed >= max_total => None,
+            _ => Some(self.timeout),
+        }
+    }
+}
+
+/// Doubles the visibility timeout with every extension, from `initial` up to `max`, checking
+/// again when half of the current timeout has passed. Suits handlers whose duration varies a lot,
+/// short ones cost few calls and long ones are extended further and further
+#[derive(Debug, Clone)]
+pub struct ProgressiveExtension {
+    initial: Duration,
+    max: Duration,
+}
+
+impl ProgressiveExtension {
+    /// `initial` is the visibility timeout messages are received with
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max }
+    }
+
+    fn timeout(&self, extensions: u32) -> Duration {
+        let factor = 2_u32.saturating_pow(extensions.min(16));
+        self.initial
+            .checked_mul(factor)
+            .unwrap_or(self.max)
+            .min(self.max)
+    }
+}
+
+impl VisibilityPolicy for ProgressiveExtension {
+    fn interval(&self, heartbeat: &Heartbeat) -> Duration {
+        self.timeout(heartbeat.extensions) / 2
+    }
+
+    fn extend(&self, heartbeat: &Heartbeat) -> Option<Duration> {
+        Some(self.timeout(heartbeat.extensions + 1))
+    }
+}
+
+// the duration a handler expects to take, set by `expect_duration`
+#[derive(Default)]
+pub(crate) struct Hint {
+    duration: Mutex<Option<Duration>>,
+    notify: Notify,
+}
+
+impl Hint {
+    // resolves with the next duration a handler expects
+    pub(crate) async fn expected(&self) -> Duration {
+        loop {
+            if let Some(duration) = self.duration.lock().expect("hint poisoned").take() {
+                return duration;
+            }
+
+            self.notify.notified().await;
+        }
+    }
+
+    fn set(&self, duration: Duration) {
+        *self.duration.lock().expect("hint poisoned") = Some(duration);
+        self.notify.notify_one();
+    }
+}
+
+tokio::task_local! {
+    pub(crate) static HINT: std::sync::Arc<Hint>;
+}
+
+/// Call from a handler that expects to take `duration`, the message stays invisible for that
+/// long from now instead of being redelivered once the visibility timeout expires. Applies to
+/// every message of a batch handler. Does nothing outside of a handler
+pub fn expect_duration(duration: Duration) {
+    let _ = HINT.try_with(|hint| hint.set(duration));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeat(extensions: u32) -> Heartbeat {
+        Heartbeat {
+            elapsed: Duration::from_secs(0),
+            extensions,
+        }
+    }
+
+    #[test]
+    fn progressive_extension_doubles_up_to_max() {
+        let policy = ProgressiveExtension::new(Duration::from_secs(30), Duration::from_secs(100));
+
+        assert_eq!(policy.interval(&heartbeat(0)), Duration::from_secs(15));
+        assert_eq!(policy.extend(&heartbeat(0)), Some(Duration::from_secs(60)));
+        assert_eq!(policy.interval(&heartbeat(1)), Duration::from_secs(30));
+        assert_eq!(policy.extend(&heartbeat(1)), Some(Duration::from_secs(100)));
+        assert_eq!(
+            policy.extend(&heartbeat(40)),
+            Some(Duration::from_secs(100))
+        );
+    }
+}
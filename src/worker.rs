@@ -0,0 +1,874 @@
+// Workers handle the messages received by the poller, so polling and acknowledging carry on while
+// handlers are busy
+use rusoto_sqs::{ChangeMessageVisibilityRequest, DeleteMessageRequest, Message};
+use std::any::Any;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use act_zero::*;
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use futures::FutureExt;
+
+use super::acker::{self, AckBuffer};
+use super::backoff::Backoff;
+use super::chaos::Injector;
+use super::checksum;
+use super::client::{DispatchGauge, MAX_VISIBILITY_TIMEOUT};
+use super::extended::{self, PayloadStore, S3Pointer};
+use super::metrics::Metrics;
+use super::sink::{Outcome, ResultSink};
+use super::telemetry::{self, error, warn};
+use super::transport::Transport;
+use super::visibility::{self, Heartbeat, Hint, VisibilityPolicy};
+use super::{canary, Config, Error, Handler, HandlerError, SlowMessage};
+
+// what becomes of a received message before its handler is called
+enum Acceptance {
+    // to be passed to the handler, with the pointer to its extended payload
+    Accepted(Message, Option<S3Pointer>),
+
+    // canaries and unsampled messages, the handler never sees them
+    Skipped,
+
+    // corrupted messages and payloads that could not be downloaded, left on the queue like a
+    // failed message so the rest of a FIFO group waits for their redelivery
+    Rejected,
+}
+
+/// Everything needed to handle and acknowledge messages, shared by the poller and its workers
+pub(crate) struct Handling<F: Handler> {
+    pub(crate) handler: Arc<F>,
+    pub(crate) queue_url: String,
+    pub(crate) config: Config,
+
+    // replaced when the poller rebuilds its client
+    pub(crate) client: Mutex<Arc<dyn Transport>>,
+
+    // set when an ack failed because the credentials expired, the poller rebuilds the client
+    pub(crate) credentials_expired: Arc<AtomicBool>,
+
+    pub(crate) result_sink: Option<Arc<dyn ResultSink>>,
+    pub(crate) on_slow_message: Option<Arc<dyn Fn(&SlowMessage) + Send + Sync>>,
+    pub(crate) on_receipt_handle_expired: Option<Arc<dyn Fn(&Message) + Send + Sync>>,
+    pub(crate) on_message_acked: Option<Arc<dyn Fn(&Message) + Send + Sync>>,
+    pub(crate) on_error: Option<Arc<dyn Fn(&Error) + Send + Sync>>,
+    pub(crate) fault_injector: Option<Arc<Injector>>,
+    pub(crate) extended_payloads: Option<Arc<dyn PayloadStore>>,
+
+    pub(crate) handler_retry: Option<Arc<dyn Fn() -> Box<dyn Backoff> + Send + Sync>>,
+    pub(crate) visibility_policy: Option<Arc<dyn VisibilityPolicy>>,
+
+    // from the redrive policy of the queue, a message failing on this receive is dead-lettered
+    pub(crate) max_receive_count: Option<u64>,
+
+    pub(crate) metrics: Arc<Metrics>,
+
+    // acks waiting to be sent with `batch_acks`, kept when the config is updated
+    pub(crate) ack_buffer: Arc<AckBuffer>,
+
+    // ids of the periodic canaries this listener sent and has not deleted yet, kept when the
+    // config is updated
+    pub(crate) own_canaries: Arc<Mutex<HashSet<String>>>,
+}
+
+impl<F: Handler> Handling<F> {
+    pub(crate) fn client(&self) -> Arc<dyn Transport> {
+        self.client.lock().expect("client poisoned").clone()
+    }
+
+    pub(crate) fn set_client(&self, client: Arc<dyn Transport>) {
+        *self.client.lock().expect("client poisoned") = client;
+    }
+
+    // handles the messages of a receive, passing them to a batch handler at once or to the
+    // handler at most `concurrency` at a time
+    pub(crate) async fn handle_messages(&self, messages: Vec<Message>) {
+        if self.handler.handles_batches() {
+            self.handle_batch(messages).await;
+        } else if self.is_fifo() {
+            // groups are handled concurrently, the messages of a group one after the other
+            stream::iter(by_group(messages))
+                .for_each_concurrent(self.config.concurrency.max(1), |group| {
+                    self.handle_group(group)
+                })
+                .await;
+        } else {
+            // messages start in priority order, at most `concurrency` of them at a time
+            stream::iter(messages)
+                .for_each_concurrent(self.config.concurrency.max(1), |message| {
+                    self.handle_message(message)
+                })
+                .await;
+        }
+    }
+
+    pub(crate) fn is_fifo(&self) -> bool {
+        self.config.fifo || self.queue_url.ends_with(".fifo")
+    }
+
+    // stops at the first failure, the rest of the group is left on the queue and redelivered after
+    // the failed message, so the order is kept
+    async fn handle_group(&self, messages: Vec<Message>) {
+        let mut messages = messages.into_iter();
+
+        while let Some(message) = messages.next() {
+            let group_id = message_group_id(&message).map(str::to_string);
+
+            if !self.handle_message(message).await {
+                let skipped = messages.len();
+
+                if skipped > 0 {
+                    warn!(
+                        "Skipped {} messages of group {} after a failure, they will be redelivered in order",
+                        skipped,
+                        group_id.as_deref().unwrap_or_default()
+                    );
+                }
+
+                return;
+            }
+        }
+    }
+
+    pub(crate) fn record(&self, message: &Message, outcome: Outcome) {
+        if let Outcome::Failure(error) = &outcome {
+            error!(
+                "Error when handling message {}: {:?}",
+                message.message_id.as_deref().unwrap_or_default(),
+                error
+            )
+        }
+
+        if let Some(result_sink) = &self.result_sink {
+            result_sink.record(message, &outcome);
+
+            if let Outcome::Failure(_) = outcome {
+                if self.is_last_receive(message) {
+                    result_sink.record(message, &Outcome::DeadLettered)
+                }
+            }
+        }
+    }
+
+    // the redrive policy moves the message to the dead-letter queue instead of receiving it again
+    fn is_last_receive(&self, message: &Message) -> bool {
+        match (self.max_receive_count, telemetry::receive_count(message)) {
+            (Some(max_receive_count), Some(receive_count)) => receive_count >= max_receive_count,
+            _ => false,
+        }
+    }
+
+    pub(crate) async fn delete_message(&self, receipt_handle: Option<String>) -> Result<(), Error> {
+        let receipt_handle = receipt_handle.ok_or(Error::NoMessageHandle)?;
+
+        let result = self
+            .client()
+            .delete_message(DeleteMessageRequest {
+                queue_url: self.queue_url.clone(),
+                receipt_handle,
+            })
+            .await
+            .map_err(Error::from_ack);
+
+        if let Err(error) = &result {
+            if error.is_expired_credentials() {
+                self.credentials_expired.store(true, Ordering::Relaxed);
+            }
+        }
+
+        result
+    }
+
+    // deletes the message, reporting it if it can't be deleted because the receipt handle expired
+    pub(crate) async fn ack(&self, message: &mut Message) -> Result<(), Error> {
+        // the receipt handle is only needed to ack, move it instead of cloning
+        let result = self.delete_message(message.receipt_handle.take()).await;
+
+        match &result {
+            Ok(()) => {
+                self.metrics.acked(1);
+                self.report_acked(message);
+            }
+            Err(error) => {
+                self.metrics.ack_failed(1);
+                self.report_error(error);
+
+                if let Error::ReceiptHandleExpired(_) = error {
+                    self.report_expired_receipt_handle(message);
+                }
+            }
+        }
+
+        result
+    }
+
+    // deletes the messages with `DeleteMessageBatch`, returns the result of every message in order
+    pub(crate) async fn ack_batch(&self, messages: &mut [Message]) -> Vec<Result<(), Arc<Error>>> {
+        let mut results = vec![Err(Arc::new(Error::NoMessageHandle)); messages.len()];
+        let mut acked = Vec::with_capacity(messages.len());
+        let mut receipt_handles = Vec::with_capacity(messages.len());
+
+        for (index, message) in messages.iter_mut().enumerate() {
+            if let Some(receipt_handle) = message.receipt_handle.take() {
+                acked.push(index);
+                receipt_handles.push(receipt_handle);
+            }
+        }
+
+        let batch_results =
+            acker::delete_all(&*self.client(), &self.queue_url, receipt_handles).await;
+
+        let acked_count = batch_results.iter().filter(|result| result.is_ok()).count();
+        self.metrics.acked(acked_count);
+        self.metrics.ack_failed(batch_results.len() - acked_count);
+
+        for (index, result) in acked.into_iter().zip(batch_results) {
+            if result.is_ok() {
+                self.report_acked(&messages[index]);
+            }
+
+            if let Err(error) = &result {
+                self.report_error(error);
+
+                if error.is_expired_credentials() {
+                    self.credentials_expired.store(true, Ordering::Relaxed);
+                }
+
+                if let Error::ReceiptHandleExpired(_) = &**error {
+                    self.report_expired_receipt_handle(&messages[index]);
+                }
+            }
+
+            results[index] = result;
+        }
+
+        results
+    }
+
+    // sends the buffered acks, recording the outcome of their messages
+    pub(crate) async fn flush_acks(&self) {
+        let (mut messages, pointers): (Vec<_>, Vec<_>) = self.ack_buffer.take().into_iter().unzip();
+
+        if messages.is_empty() {
+            return;
+        }
+
+        let receipt_handles: Vec<_> = messages
+            .iter()
+            .map(|message| self.keep_receipt_handle(message))
+            .collect();
+
+        let results = self.ack_batch(&mut messages).await;
+
+        for (((message, pointer), result), receipt_handle) in messages
+            .iter_mut()
+            .zip(pointers)
+            .zip(results)
+            .zip(receipt_handles)
+        {
+            let acked = result.is_ok();
+
+            match result {
+                Ok(()) => {
+                    self.record(message, Outcome::Success);
+                    self.delete_payload(pointer).await;
+                }
+                Err(error) => self.record(message, Outcome::Failure(error)),
+            }
+
+            message.receipt_handle = receipt_handle;
+            self.handler.after_ack(message, acked).await;
+        }
+    }
+
+    // the receipt handle is moved out to ack, it's only cloned beforehand for handlers that need
+    // it in `after_ack`
+    fn keep_receipt_handle(&self, message: &Message) -> Option<String> {
+        if self.handler.keeps_receipt_handles() {
+            message.receipt_handle.clone()
+        } else {
+            None
+        }
+    }
+
+    fn report_error(&self, error: &Error) {
+        if let Some(on_error) = &self.on_error {
+            on_error(error)
+        }
+    }
+
+    fn report_acked(&self, message: &Message) {
+        if let Some(on_message_acked) = &self.on_message_acked {
+            on_message_acked(message)
+        }
+    }
+
+    fn report_expired_receipt_handle(&self, message: &Message) {
+        warn!(
+            "Receipt handle of message {} expired before it was acknowledged, it will be redelivered",
+            message.message_id.as_deref().unwrap_or_default()
+        );
+
+        if let Some(on_receipt_handle_expired) = &self.on_receipt_handle_expired {
+            on_receipt_handle_expired(message)
+        }
+    }
+
+    // makes the message visible again after the delay
+    pub(crate) async fn nack(&self, message: Message, delay: Duration) -> Result<(), Error> {
+        self.change_visibility(&message, delay).await?;
+        self.metrics.nacked();
+
+        Ok(())
+    }
+
+    async fn change_visibility(&self, message: &Message, timeout: Duration) -> Result<(), Error> {
+        let receipt_handle = message
+            .receipt_handle
+            .clone()
+            .ok_or(Error::NoMessageHandle)?;
+
+        let result = self
+            .client()
+            .change_message_visibility(ChangeMessageVisibilityRequest {
+                queue_url: self.queue_url.clone(),
+                receipt_handle,
+                visibility_timeout: (timeout.as_secs() as i64).min(MAX_VISIBILITY_TIMEOUT),
+            })
+            .await
+            .map_err(Error::from);
+
+        if let Err(error) = &result {
+            if error.is_expired_credentials() {
+                self.credentials_expired.store(true, Ordering::Relaxed);
+            }
+        }
+
+        result
+    }
+
+    // passes the message to the handler and acks it if `auto_ack` is set, returns false if
+    // handling or acking it failed, or if it was rejected before reaching the handler
+    pub(crate) async fn handle_message(&self, message: Message) -> bool {
+        let (message, pointer) = match self.accept_message(message).await {
+            Acceptance::Accepted(message, pointer) => (message, pointer),
+            Acceptance::Skipped => return true,
+            Acceptance::Rejected => return false,
+        };
+
+        self.inject_handler_delay().await;
+
+        let span =
+            telemetry::message_span(&self.queue_url, &message, self.config.trace_sample_rate);
+        let started_at = Instant::now();
+        let result = telemetry::instrument(
+            self.with_heartbeat(
+                std::slice::from_ref(&message),
+                self.with_retries(&message, || self.handler.handle(&message)),
+            ),
+            span,
+        )
+        .await;
+        self.metrics.handler_duration(started_at.elapsed());
+        self.check_duration(std::iter::once(&message), started_at);
+
+        match result {
+            Ok(()) => {
+                self.metrics.handled(1);
+                self.complete(message, pointer).await
+            }
+            // not acked, so the message is redelivered after its visibility timeout
+            Err(error) => {
+                self.metrics.handler_failed(1);
+                self.record(&message, Outcome::Failure(Arc::new(Error::Handler(error))));
+                false
+            }
+        }
+    }
+
+    // passes the messages of a receive to the handler at once, acking all of them if it succeeds
+    async fn handle_batch(&self, messages: Vec<Message>) {
+        let mut accepted = Vec::with_capacity(messages.len());
+        let mut pointers = Vec::with_capacity(messages.len());
+
+        for message in messages {
+            if let Acceptance::Accepted(message, pointer) = self.accept_message(message).await {
+                accepted.push(message);
+                pointers.push(pointer);
+            }
+        }
+
+        if accepted.is_empty() {
+            return;
+        }
+
+        self.inject_handler_delay().await;
+
+        let span = telemetry::batch_span(&self.queue_url, &accepted, self.config.trace_sample_rate);
+        let started_at = Instant::now();
+        let result = telemetry::instrument(
+            self.with_heartbeat(
+                &accepted,
+                self.with_retries(&accepted[0], || self.handler.handle_batch(&accepted)),
+            ),
+            span,
+        )
+        .await;
+        self.metrics.handler_duration(started_at.elapsed());
+        self.check_duration(&accepted, started_at);
+
+        match &result {
+            Ok(()) => self.metrics.handled(accepted.len()),
+            Err(_) => self.metrics.handler_failed(accepted.len()),
+        }
+
+        // every message of a failed batch shares the error
+        let error = result.err().map(|error| Arc::new(Error::Handler(error)));
+
+        for (message, pointer) in accepted.into_iter().zip(pointers) {
+            match &error {
+                Some(error) => self.record(&message, Outcome::Failure(error.clone())),
+                None => {
+                    self.complete(message, pointer).await;
+                }
+            }
+        }
+    }
+
+    // settles canaries, drops unsampled messages and rejects corrupted ones, before the message
+    // is passed to the handler
+    async fn accept_message(&self, mut message: Message) -> Acceptance {
+        // canaries are only used to check the listener and never passed to the handler
+        if canary::id(&message).is_some() {
+            self.settle_canary(message).await;
+            return Acceptance::Skipped;
+        }
+
+        if !self.is_sampled(&message) {
+            let outcome = if self.config.ack_unsampled {
+                match self.delete_message(message.receipt_handle.take()).await {
+                    Ok(()) => Outcome::Dropped,
+                    Err(error) => Outcome::Failure(Arc::new(error)),
+                }
+            } else {
+                Outcome::Dropped
+            };
+
+            self.record(&message, outcome);
+            return Acceptance::Skipped;
+        }
+
+        if self.config.verify_checksums {
+            // left on the queue, a corrupted delivery may be followed by an intact one
+            if let Err(error) = checksum::verify(&message) {
+                self.record(&message, Outcome::Failure(Arc::new(error)));
+                return Acceptance::Rejected;
+            }
+        }
+
+        match self.resolve_payload(&mut message).await {
+            Ok(pointer) => Acceptance::Accepted(message, pointer),
+            Err(error) => {
+                self.record(&message, Outcome::Failure(Arc::new(error)));
+                Acceptance::Rejected
+            }
+        }
+    }
+
+    // replaces a pointer to an extended payload with the payload
+    async fn resolve_payload(&self, message: &mut Message) -> Result<Option<S3Pointer>, Error> {
+        let store = match &self.extended_payloads {
+            Some(store) => store,
+            None => return Ok(None),
+        };
+
+        let pointer = match extended::pointer(message) {
+            Some(pointer) => pointer,
+            None => return Ok(None),
+        };
+
+        message.body = Some(store.get(&pointer).await?);
+
+        Ok(Some(pointer))
+    }
+
+    async fn delete_payload(&self, pointer: Option<S3Pointer>) {
+        if let (Some(store), Some(pointer)) = (&self.extended_payloads, pointer) {
+            if store.delete_after_ack() {
+                if let Err(error) = store.delete(&pointer).await {
+                    error!("Error when deleting extended payload: {:?}", error)
+                }
+            }
+        }
+    }
+
+    // calls the handler again after a failure, as long as the `handler_retry` backoff allows. A
+    // panicking handler fails like one returning an error, so it doesn't take the worker down
+    async fn with_retries<Fut>(
+        &self,
+        message: &Message,
+        handle: impl Fn() -> Fut,
+    ) -> Result<(), HandlerError>
+    where
+        Fut: Future<Output = Result<(), HandlerError>>,
+    {
+        let mut backoff = self.handler_retry.as_ref().map(|new_backoff| new_backoff());
+
+        loop {
+            let error = match AssertUnwindSafe(handle()).catch_unwind().await {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(error)) => error,
+                Err(panic) => panic_error(message, panic),
+            };
+
+            match backoff.as_mut().and_then(|backoff| backoff.next_backoff()) {
+                Some(delay) => {
+                    warn!(
+                        "Handler failed for message {}, retrying in {:?}: {}",
+                        message.message_id.as_deref().unwrap_or_default(),
+                        delay,
+                        error
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                None => return Err(error),
+            }
+        }
+    }
+
+    // runs the handler, extending the visibility of its messages until it returns
+    async fn with_heartbeat<T>(&self, messages: &[Message], handler: impl Future<Output = T>) -> T {
+        let hint = Arc::new(Hint::default());
+
+        let handler = visibility::HINT.scope(hint.clone(), handler);
+        let heartbeat = self.heartbeat(messages, &hint);
+        tokio::pin!(handler, heartbeat);
+
+        tokio::select! {
+            result = &mut handler => result,
+            // only returns once none of the messages can be extended anymore
+            () = &mut heartbeat => handler.await,
+        }
+    }
+
+    // extends the visibility of the messages when the policy says so or a handler expects to take
+    // long, returns once every receipt handle is invalid or the messages reached the 12 hours SQS
+    // allows them to stay hidden
+    async fn heartbeat(&self, messages: &[Message], hint: &Hint) {
+        let started_at = Instant::now();
+        let max_hidden = Duration::from_secs(MAX_VISIBILITY_TIMEOUT as u64);
+        let mut messages: Vec<&Message> = messages.iter().collect();
+        let mut extensions = 0;
+        let mut policy = self.visibility_policy.as_ref();
+
+        loop {
+            if messages.is_empty() || started_at.elapsed() >= max_hidden {
+                return;
+            }
+
+            let interval = policy.map(|policy| {
+                policy.interval(&Heartbeat {
+                    elapsed: started_at.elapsed(),
+                    extensions,
+                })
+            });
+
+            let timeout = tokio::select! {
+                duration = hint.expected() => duration,
+                () = tokio::time::sleep(interval.unwrap_or_default()), if interval.is_some() => {
+                    let heartbeat = Heartbeat {
+                        elapsed: started_at.elapsed(),
+                        extensions,
+                    };
+
+                    match policy.and_then(|policy| policy.extend(&heartbeat)) {
+                        Some(timeout) => timeout,
+                        None => {
+                            policy = None;
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            extensions += 1;
+
+            let mut extendable = Vec::with_capacity(messages.len());
+
+            for message in messages {
+                match self.change_visibility(message, timeout).await {
+                    Ok(()) => extendable.push(message),
+                    // the message is visible again or was deleted, it can't be extended anymore
+                    Err(error) if error.is_invalid_receipt_handle() => {
+                        warn!("Stopped extending message visibility: {:?}", error)
+                    }
+                    Err(error) => {
+                        error!("Error when extending message visibility: {:?}", error);
+                        extendable.push(message)
+                    }
+                }
+            }
+
+            messages = extendable;
+        }
+    }
+
+    async fn inject_handler_delay(&self) {
+        if let Some(injector) = &self.fault_injector {
+            if let Some(delay) = injector.handler_delay() {
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+
+    // reports the messages as slow if the handler took longer than the `slow_message_threshold`
+    fn check_duration<'a>(
+        &self,
+        messages: impl IntoIterator<Item = &'a Message>,
+        started_at: Instant,
+    ) {
+        if let Some(threshold) = self.config.slow_message_threshold {
+            let elapsed = started_at.elapsed();
+
+            if elapsed > threshold {
+                for message in messages {
+                    self.report_slow_message(message, elapsed);
+                }
+            }
+        }
+    }
+
+    // acks a handled message if `auto_ack` is set and records the outcome, returns false if the
+    // ack failed. With `batch_acks` the ack is buffered and the outcome recorded once it is sent
+    async fn complete(&self, mut message: Message, pointer: Option<S3Pointer>) -> bool {
+        if !self.config.auto_ack {
+            self.record(&message, Outcome::Success);
+            self.handler.after_ack(&message, false).await;
+            return true;
+        }
+
+        if self.config.batch_acks {
+            if self.ack_buffer.push(message, pointer) {
+                self.flush_acks().await;
+            }
+
+            return true;
+        }
+
+        let receipt_handle = self.keep_receipt_handle(&message);
+        let outcome: Outcome = self.ack(&mut message).await.into();
+        let succeeded = matches!(outcome, Outcome::Success);
+        self.record(&message, outcome);
+
+        if succeeded {
+            self.delete_payload(pointer).await;
+        }
+
+        message.receipt_handle = receipt_handle;
+        self.handler.after_ack(&message, succeeded).await;
+
+        succeeded
+    }
+
+    // acks the canaries this listener sent and expired ones, the canaries of other consumers of
+    // the queue are made visible again right away, for the consumer waiting for them
+    pub(crate) async fn settle_canary(&self, message: Message) {
+        let own = canary::id(&message).map_or(false, |canary_id| {
+            self.own_canaries
+                .lock()
+                .expect("own canaries poisoned")
+                .remove(canary_id)
+        });
+
+        let result = if own || canary::is_expired(&message) {
+            self.delete_message(message.receipt_handle).await
+        } else {
+            self.change_visibility(&message, Duration::from_secs(0))
+                .await
+        };
+
+        if let Err(error) = result {
+            error!("Error when settling canary: {:?}", error)
+        }
+    }
+
+    // decided by the message id, so a redelivered message is sampled the same way
+    fn is_sampled(&self, message: &Message) -> bool {
+        if self.config.sample_rate >= 1.0 {
+            return true;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        message.message_id.hash(&mut hasher);
+
+        (hasher.finish() as f64 / u64::MAX as f64) < self.config.sample_rate
+    }
+
+    fn report_slow_message(&self, message: &Message, duration: Duration) {
+        let slow_message = SlowMessage::new(message, duration);
+
+        warn!(
+            "Slow message {}, handler took {:?}, receive count: {:?}, body hash: {:016x}",
+            slow_message.message_id.as_deref().unwrap_or_default(),
+            slow_message.duration,
+            slow_message.receive_count,
+            slow_message.body_hash
+        );
+
+        if let Some(on_slow_message) = &self.on_slow_message {
+            on_slow_message(&slow_message)
+        }
+    }
+}
+
+// the panic message, as a handler error
+fn panic_error(message: &Message, panic: Box<dyn Any + Send>) -> HandlerError {
+    let reason = match panic.downcast::<String>() {
+        Ok(reason) => *reason,
+        Err(panic) => panic
+            .downcast_ref::<&str>()
+            .map_or_else(|| "unknown reason".to_string(), |reason| reason.to_string()),
+    };
+
+    error!(
+        "Handler panicked for message {}: {}",
+        message.message_id.as_deref().unwrap_or_default(),
+        reason
+    );
+
+    HandlerError::from(format!("handler panicked: {}", reason))
+}
+
+// only set on messages of FIFO queues, when the `MessageGroupId` attribute was requested
+pub(crate) fn message_group_id(message: &Message) -> Option<&str> {
+    message
+        .attributes
+        .as_ref()
+        .and_then(|attributes| attributes.get("MessageGroupId"))
+        .map(String::as_str)
+}
+
+// splits the messages by group, keeping the order they were received in. Messages without a group
+// id are groups of their own
+fn by_group(messages: Vec<Message>) -> Vec<Vec<Message>> {
+    let mut groups: Vec<(Option<String>, Vec<Message>)> = Vec::new();
+
+    for message in messages {
+        let group_id = message_group_id(&message).map(str::to_string);
+
+        match groups
+            .iter_mut()
+            .find(|(id, _)| id.is_some() && *id == group_id)
+        {
+            Some((_, group)) => group.push(message),
+            None => groups.push((group_id, vec![message])),
+        }
+    }
+
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+/// Handles the messages the poller passes on, one receive after the other
+pub(crate) struct Worker<F: Handler> {
+    handling: Arc<Handling<F>>,
+    dispatch_gauge: Arc<DispatchGauge>,
+}
+
+impl<F: Handler> Worker<F> {
+    pub(crate) fn new(handling: Arc<Handling<F>>, dispatch_gauge: Arc<DispatchGauge>) -> Self {
+        Self {
+            handling,
+            dispatch_gauge,
+        }
+    }
+
+    pub(crate) async fn handle(&mut self, messages: Vec<Message>) -> ActorResult<()> {
+        let count = messages.len();
+
+        self.handling.handle_messages(messages).await;
+
+        self.dispatch_gauge
+            .in_flight
+            .fetch_sub(count, Ordering::SeqCst);
+        self.dispatch_gauge.worker_done.notify_one();
+
+        Produces::ok(())
+    }
+
+    // replaces the handling after the config was updated, the messages passed on before are
+    // handled with the previous one
+    pub(crate) async fn set_handling(&mut self, handling: Arc<Handling<F>>) -> ActorResult<()> {
+        self.handling = handling;
+        Produces::ok(())
+    }
+
+    // queued behind the messages passed on before, so it resolves once they are handled
+    pub(crate) async fn drain(&self) -> ActorResult<()> {
+        Produces::ok(())
+    }
+}
+
+#[async_trait]
+impl<F: Handler> Actor for Worker<F> {
+    async fn error(&mut self, error: ActorError) -> bool {
+        error!("Worker Error: {:?}", error);
+
+        // do not stop on actor error
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn message(id: &str, group_id: Option<&str>) -> Message {
+        let attributes = group_id.map(|group_id| {
+            let mut attributes = HashMap::new();
+            attributes.insert("MessageGroupId".to_string(), group_id.to_string());
+            attributes
+        });
+
+        Message {
+            message_id: Some(id.to_string()),
+            attributes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn groups_messages_in_order() {
+        let messages = vec![
+            message("1", Some("a")),
+            message("2", Some("b")),
+            message("3", None),
+            message("4", Some("a")),
+            message("5", None),
+            message("6", Some("b")),
+        ];
+
+        let groups: Vec<Vec<&str>> = by_group(messages)
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .map(|message| message.message_id.as_deref().unwrap())
+                    .collect()
+            })
+            .collect();
+
+        assert_eq!(
+            groups,
+            vec![vec!["1", "4"], vec!["2", "6"], vec!["3"], vec!["5"]]
+        );
+    }
+}